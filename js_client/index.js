@@ -49,11 +49,19 @@ console.log(privkey);
 let binary_string = "eyJyZWdpc3RlciI6eyJuYW1lIjoidGVzdF9mcm9tX3RydXN0Ym9vc3Rfc2VwdCJ9fQ==";
 binary_string = "eyJyZWdpc3Rlcl90YiI6eyJuYW1lIjoidGVzdF9mcm9tX3RydXN0Ym9vc3Rfc2VwdCJ9fQ==";
 
-const len = binary_string.length;
-let bytes = new Uint8Array(len);
-for (var i = 0; i < len; i++) {
-   bytes[i] = binary_string.charCodeAt(i);
-}
+// The signature covers the binary together with the nonce of the signer (query get_nonce) and the domain
+// of the deployment, as Json without whitespace and in this field order
+const nonce = 0;
+const domain = {
+  deployment_id: "",
+  targets: [
+    { chain_id: "ibc-0", contract: "wasm14hj2tavq8fpesdwxxcu44rty3hh90vhujrvcmstl4zr3txmfvw9s0phg4d" },
+    { chain_id: "ibc-1", contract: "wasm14hj2tavq8fpesdwxxcu44rty3hh90vhujrvcmstl4zr3txmfvw9s0phg4d" },
+  ],
+};
+const sign_doc = JSON.stringify({ binary: binary_string, nonce: nonce, domain: domain });
+
+let bytes = new TextEncoder().encode(sign_doc);
 let messageHash = sha256(bytes);
 
 console.log("Message Hash");
@@ -4,7 +4,7 @@ use std::fs::create_dir_all;
 use cosmwasm_schema::{export_schema, remove_schemas, schema_for};
 
 use simple_option::ibc_msg::PacketMsg;
-use simple_option::msg::{ValueResponse, ExecuteMsg, InstantiateMsg, QueryMsg};
+use simple_option::msg::{ValueResponse, ExecuteMsg, InstantiateMsg, MigrateMsg, QueryMsg};
 
 fn main() {
     let mut out_dir = current_dir().unwrap();
@@ -14,6 +14,7 @@ fn main() {
 
     export_schema(&schema_for!(InstantiateMsg), &out_dir);
     export_schema(&schema_for!(ExecuteMsg), &out_dir);
+    export_schema(&schema_for!(MigrateMsg), &out_dir);
     export_schema(&schema_for!(QueryMsg), &out_dir);
     export_schema(&schema_for!(ValueResponse), &out_dir);
     export_schema(&schema_for!(PacketMsg), &out_dir);
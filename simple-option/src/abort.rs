@@ -1,16 +1,8 @@
-use cosmwasm_std::{
-    StdResult, Order, StdError, Storage, IbcTimeout, Env, Api
-};
-
-use crate::utils::{get_id_channel_pair_from_storage};
-use crate::state::{
-    STATE, HIGHEST_ABORT, DEBUG
-};
+use cosmwasm_std::StdError;
 
-
-use crate::utils::{
-    reset_view_specific_maps
-};
+use crate::context::Context;
+use crate::log::log;
+use crate::state::{LogCategory, LogLevel, PeerId};
 
 use crate::view_change::{
     append_queue_view_change
@@ -18,97 +10,53 @@ use crate::view_change::{
 
 use crate::ibc_msg::Msg;
 
-pub fn handle_abort(storage: &mut dyn Storage, 
-                    queue: &mut Vec<Vec<Msg>>, view: u32, 
-                    sender_chain_id: u32, timeout: IbcTimeout,
-                    env: &Env,
-                    api: &dyn Api,
+pub fn handle_abort(ctx: &mut Context,
+                    view: u32, 
+                    sender_chain_id: PeerId,
                     ) -> Result<(), StdError> {
-    let mut state = STATE.load(storage)?;
-    
-    let mut loaded_val: i32 = 0;
-    let option = HIGHEST_ABORT.load(storage, sender_chain_id);
-    match option {
-        Ok(val) => loaded_val = val,
-        Err(_) => return Err(StdError::GenericErr { msg: "handle_abort cannot find loadedVal".to_string()} ), 
-    }
+    let loaded_val = match ctx.highest_abort.get(&sender_chain_id) {
+        Some(val) => *val,
+        None => return Err(StdError::GenericErr { msg: "handle_abort cannot find loadedVal".to_string()} ), 
+    };
 
     if ((loaded_val + 1) as u32)< (view+1) {
-        HIGHEST_ABORT.update(storage, sender_chain_id, |option| -> StdResult<i32> {
-            match option {
-                Some(_val) => Ok(view as i32),
-                None => Ok(view as i32),
-            }
-        })?;
-
-        let highest_abort_vector_pair: StdResult<Vec<_>> = HIGHEST_ABORT
-            .range(storage, None, None, Order::Ascending)
-            .collect();
-        let mut vector_values = match highest_abort_vector_pair {
-            Ok(vec) => { 
-                let temp = vec.iter().map(|(_key, value)| value.clone()).collect::<Vec<i32>>();
-                temp
-            }
-            Err(_) => return Err(StdError::GenericErr { msg: "Error nth".to_string()}),
-        };
+        ctx.highest_abort.insert(sender_chain_id.clone(), view as i32);
+
+        let mut vector_values: Vec<i32> = ctx.highest_abort.values().copied().collect();
         vector_values.sort();
 
         // Sort will sort the array ascendingly... [-1,0,-1,-1] --> [-1,-1,-1,0]..
         // F+1 highest meaning n-F+1
-        let u = vector_values[ (vector_values.len()-(state.F+1) as usize)];  // [-1,0,0] f = 1 length = 3 (3-2) --> 1
-        let mut loaded_val: i32 = 0;
-        match HIGHEST_ABORT.load(storage, sender_chain_id) {
-            Ok(val) => loaded_val = val,
-            Err(_) => return Err(StdError::GenericErr { msg: "handle_abort cannot find loaded_val part 2".to_string()} ), 
-        }
+        let u = vector_values[ (vector_values.len()-(ctx.state.F+1) as usize)];  // [-1,0,0] f = 1 length = 3 (3-2) --> 1
+        let loaded_val = ctx.highest_abort[&sender_chain_id];
 
-        DEBUG.save(storage, 1203, &format!("u IS {} loaded_val IS {}", u, loaded_val).to_string())?;
+        log(ctx, LogLevel::Trace, LogCategory::Abort, format!("f + 1 highest abort is {}, {} sent {}", u, sender_chain_id, loaded_val))?;
         if u > loaded_val {
-            DEBUG.save(storage, 1201, &"CLONE_ABORT_PACKET OUTSIDE".to_string())?;
             if u > -1 {
-                let abort_packet = Msg::Abort { view: u as u32, chain_id: state.chain_id};
-                let channel_ids = get_id_channel_pair_from_storage(storage)?;
-                DEBUG.save(storage, 1200, &"CLONE_ABORT_PACKET".to_string())?;
-                for (chain_id, _channel_id) in &channel_ids {
-                    queue[*chain_id as usize].push(abort_packet.clone());
-                }
-                HIGHEST_ABORT.update(storage, sender_chain_id, |option| -> StdResult<i32> {
-                    match option {
-                        Some(_val) => Ok(u),
-                        None => Ok(u),
-                    }
-                })?;
+                let abort_packet = Msg::Abort { view: u as u32, chain_id: ctx.state.chain_id.clone()};
+                log(ctx, LogLevel::Debug, LogCategory::Abort, format!("joining the abort of view {}", u))?;
+                ctx.metrics.aborts += 1;
+                ctx.outbox.broadcast(ctx.channels.keys(), &abort_packet);
+                ctx.highest_abort.insert(sender_chain_id, u);
             }
-        } else {
-            DEBUG.save(storage, 1202, &format!("u IS {} loaded_val IS {}", u, loaded_val).to_string())?;
         }
 
-        let highest_abort_vector_pair: StdResult<Vec<_>> = HIGHEST_ABORT
-            .range(storage, None, None, Order::Ascending)
-            .collect();
-        let mut vector_values = match highest_abort_vector_pair {
-            Ok(vecs) => { 
-                let temp = vecs.iter().map(|(_key, value)| value.clone()).collect::<Vec<i32>>();
-                temp
-            }
-            Err(msg) => return Err(StdError::GenericErr { msg: msg.to_string()} ),
-        };
+        let mut vector_values: Vec<i32> = ctx.highest_abort.values().copied().collect();
         vector_values.sort();        
 
         // Sort will sort the array ascendingly... [-1,0,-1,-1] --> [-1,-1,-1,0]
-        let idx = vector_values.len()-(state.n-state.F) as usize;
+        let idx = vector_values.len()-(ctx.state.n-ctx.state.F) as usize;
         // println!("state.n is {} F is {} vector_values size is {} idx is {}", state.n, F, vector_values.len(), idx);
         let w = vector_values[idx as usize];
 
         // Start new view here!.... 
-        if (w+1) as u32 >= state.view {
-            let previous_view = state.view;
-            state.start_new_view((w+1) as u32, env.block.time);
+        if (w+1) as u32 >= ctx.state.view {
+            let previous_view = ctx.state.view;
+            ctx.state.start_new_view((w+1) as u32, ctx.env.block.time);
         
-            STATE.save(storage, &state)?;
-            if previous_view != state.view {
-                DEBUG.save(storage, 1300, &"TRIGGER_VIEW_CHANGE_NEW".to_string())?;
-                match reset_view_specific_maps(storage) {
+            if previous_view != ctx.state.view {
+                log(ctx, LogLevel::Info, LogCategory::Abort, format!("view {} aborted, moving to view {}", previous_view, ctx.state.view))?;
+                match ctx.reset_view_specific_maps() {
                     Ok(_) => {
                         
                     }
@@ -119,7 +67,7 @@ pub fn handle_abort(storage: &mut dyn Storage,
                 }         
 
                 // Send request AND suggest to everyone here by appending the messages to the queue!.....
-                let result = append_queue_view_change(storage, queue, timeout, env, api);
+                let result = append_queue_view_change(ctx);
                 match result {
                     Ok(_) => {
 
@@ -0,0 +1,44 @@
+// Ownership of the deployment. The admin set at instantiation may change the configuration and
+// hands the role over in two steps: it proposes a successor, which then has to accept.
+
+use cosmwasm_std::{Addr, Response, StdResult, Storage};
+
+use crate::msg::AdminResponse;
+use crate::state::{ADMIN, PENDING_ADMIN};
+use crate::ContractError;
+
+// assert_admin fails with Unauthorized unless sender is the admin
+pub fn assert_admin(store: &dyn Storage, sender: &Addr) -> Result<(), ContractError> {
+    match ADMIN.may_load(store)? {
+        Some(admin) if admin == *sender => Ok(()),
+        _ => Err(ContractError::Unauthorized {}),
+    }
+}
+
+// transfer_admin offers the role to new_admin, replacing any earlier offer
+pub fn transfer_admin(store: &mut dyn Storage, sender: &Addr, new_admin: Addr) -> Result<Response, ContractError> {
+    assert_admin(store, sender)?;
+    PENDING_ADMIN.save(store, &new_admin)?;
+    Ok(Response::new()
+        .add_attribute("action", "transfer_admin")
+        .add_attribute("pending_admin", new_admin))
+}
+
+// accept_admin completes the transfer, only the account the role was offered to may call it
+pub fn accept_admin(store: &mut dyn Storage, sender: &Addr) -> Result<Response, ContractError> {
+    if PENDING_ADMIN.may_load(store)?.as_ref() != Some(sender) {
+        return Err(ContractError::Unauthorized {});
+    }
+    ADMIN.save(store, sender)?;
+    PENDING_ADMIN.remove(store);
+    Ok(Response::new()
+        .add_attribute("action", "accept_admin")
+        .add_attribute("admin", sender.as_str()))
+}
+
+pub fn query_admin(store: &dyn Storage) -> StdResult<AdminResponse> {
+    Ok(AdminResponse {
+        admin: ADMIN.load(store)?,
+        pending_admin: PENDING_ADMIN.may_load(store)?,
+    })
+}
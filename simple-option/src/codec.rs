@@ -0,0 +1,758 @@
+// Packet encodings, negotiated per channel through the channel version.
+//
+// Json is what we always used and stays the default, it is also accepted on every channel so packets
+// can be crafted by hand when debugging. Compact is a fixed binary layout for PacketMsg::MsgQueue:
+//
+//   version: u8 | count: varint | msg*
+//   msg      = tag: u8 | fields in declaration order
+//   u32      = LEB128 varint, i32 = zigzag varint
+//   bytes    = len: varint | raw bytes (strings, peer ids included, are their utf-8 bytes)
+//   value    = 0 | binary: bytes | public_key: bytes | signature: bytes | scheme: u8 | nonce: varint | domain | multisig   (full)
+//            | 1 | 32 raw bytes                                          (hex sha256 digest)
+//            | 2 | bytes                                                 (any other digest)
+//            | 3 | bytes                                                 (digest of a chunked value)
+//   domain   = deployment_id: bytes | count: varint | (chain_id: bytes | contract: bytes)*
+//   multisig = 0 | 1 | threshold: varint | count: varint | (scheme: u8 | public_key: bytes)* | count: varint | signature: bytes*
+//
+// Compact payloads never start with '{', which is how they are told apart from Json.
+//
+// Earlier layouts lack the later fields of a full value: version 4 has neither scheme nor multisig (the scheme
+// is secp256k1), version 5 has no multisig. A channel keeps the layout it was opened with, packets are read
+// in whichever supported layout their first byte names, and a queue the layout of the channel cannot carry
+// is sent as Json instead. Layouts before 4 predate the nonce and domain of inputs and are not supported.
+
+use cosmwasm_std::{from_slice, to_binary, Binary, StdError, StdResult};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::ibc_msg::{Msg, PacketMsg, ValueRef, WireMsg};
+use crate::state::{InputDomain, InputTarget, Multisig, MultisigKey, SignatureScheme, TBInput};
+use crate::utils::{from_hex, to_hex, IBC_APP_VERSION_COMPACT, IBC_APP_VERSION_COMPACT_PREFIX};
+
+/// Current version of the compact layout
+pub const COMPACT_CODEC_VERSION: u8 = 6;
+/// Oldest version of the compact layout still read and written
+pub const MIN_COMPACT_CODEC_VERSION: u8 = 4;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PacketEncoding {
+    Json,
+    Compact,
+}
+
+impl PacketEncoding {
+    // from_version picks the encoding matching a channel version, any version we don't know of means Json
+    pub fn from_version(version: &str) -> StdResult<Self> {
+        Ok(match compact_version(version)? {
+            Some(_) => PacketEncoding::Compact,
+            None => PacketEncoding::Json,
+        })
+    }
+}
+
+// compact_version is the layout selected by a channel version, None when it selects Json
+pub fn compact_version(version: &str) -> StdResult<Option<u8>> {
+    let layout = match version.strip_prefix(IBC_APP_VERSION_COMPACT_PREFIX) {
+        Some(layout) => layout,
+        None => return Ok(None),
+    };
+    match layout.parse::<u8>() {
+        Ok(layout) if (MIN_COMPACT_CODEC_VERSION..=COMPACT_CODEC_VERSION).contains(&layout) => Ok(Some(layout)),
+        _ => Err(StdError::generic_err(format!(
+            "Unsupported compact encoding `{}`, expected `{}{}` up to `{}`",
+            version, IBC_APP_VERSION_COMPACT_PREFIX, MIN_COMPACT_CODEC_VERSION, IBC_APP_VERSION_COMPACT
+        ))),
+    }
+}
+
+// negotiate_version checks the version chosen for a channel by the end answering the handshake against the one
+// proposed to it and returns the layout agreed on, None for Json. Compact layouts are negotiated down: an older
+// layout than the proposed one may be chosen, as long as we support it.
+pub fn negotiate_version(proposed: &str, chosen: &str) -> StdResult<Option<u8>> {
+    let agreed = compact_version(chosen)?;
+    let mismatch = match (proposed.strip_prefix(IBC_APP_VERSION_COMPACT_PREFIX), agreed) {
+        (Some(layout), Some(agreed)) => layout.parse::<u8>().map_or(true, |layout| layout < agreed),
+        (None, None) => false,
+        _ => true,
+    };
+    if mismatch {
+        return Err(StdError::generic_err(format!(
+            "Channel version `{}` does not match the proposed `{}`",
+            chosen, proposed
+        )));
+    }
+    Ok(agreed)
+}
+
+// encode_packet encodes a packet for a channel, compact queues in the given layout
+pub fn encode_packet(packet: &PacketMsg, encoding: PacketEncoding, layout: u8) -> StdResult<Binary> {
+    match (encoding, packet) {
+        (PacketEncoding::Compact, PacketMsg::MsgQueue(msgs)) => match encode_queue(msgs, layout) {
+            Ok(data) => Ok(Binary(data)),
+            // Json is read on every channel
+            Err(_) => to_binary(packet),
+        },
+        _ => to_binary(packet),
+    }
+}
+
+pub fn decode_packet(data: &[u8]) -> StdResult<PacketMsg> {
+    match data.first() {
+        Some(b'{') | None => from_slice(data),
+        Some(_) => Ok(PacketMsg::MsgQueue(decode_queue(data)?)),
+    }
+}
+
+// encode_queue lays msgs out in the given version, it fails when a value has fields the version lacks
+pub fn encode_queue(msgs: &[WireMsg], version: u8) -> StdResult<Vec<u8>> {
+    let mut writer = Writer { out: vec![version], version };
+    put_varint(&mut writer.out, msgs.len() as u64);
+    for msg in msgs {
+        writer.msg(msg)?;
+    }
+    Ok(writer.out)
+}
+
+pub fn decode_queue(data: &[u8]) -> StdResult<Vec<WireMsg>> {
+    let mut reader = Reader { data, pos: 0, version: 0 };
+    let version = reader.u8()?;
+    if !(MIN_COMPACT_CODEC_VERSION..=COMPACT_CODEC_VERSION).contains(&version) {
+        return Err(StdError::generic_err(format!(
+            "Unsupported compact codec version {}, expected {} to {}",
+            version, MIN_COMPACT_CODEC_VERSION, COMPACT_CODEC_VERSION
+        )));
+    }
+    reader.version = version;
+    let count = reader.varint()?;
+    let mut msgs = Vec::new();
+    for _ in 0..count {
+        msgs.push(reader.msg()?);
+    }
+    if reader.pos != data.len() {
+        return Err(StdError::generic_err("Trailing bytes after compact queue"));
+    }
+    Ok(msgs)
+}
+
+fn put_varint(out: &mut Vec<u8>, mut n: u64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn put_i32(out: &mut Vec<u8>, n: i32) {
+    put_varint(out, ((n << 1) ^ (n >> 31)) as u32 as u64);
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    put_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+struct Writer {
+    out: Vec<u8>,
+    version: u8,
+}
+
+impl Writer {
+    fn input(&mut self, val: &TBInput) -> StdResult<()> {
+        let scheme = self.version >= 5;
+        let multisig = self.version >= 6;
+        if !scheme && val.scheme != SignatureScheme::default() || !multisig && val.multisig.is_some() {
+            return Err(StdError::generic_err(format!(
+                "Value not representable in compact codec version {}",
+                self.version
+            )));
+        }
+        let out = &mut self.out;
+        put_bytes(out, val.binary.as_bytes());
+        put_bytes(out, &val.public_key);
+        put_bytes(out, &val.signature);
+        if scheme {
+            out.push(val.scheme.tag());
+        }
+        put_varint(out, val.nonce);
+        put_bytes(out, val.domain.deployment_id.as_bytes());
+        put_varint(out, val.domain.targets.len() as u64);
+        for target in &val.domain.targets {
+            put_bytes(out, target.chain_id.as_bytes());
+            put_bytes(out, target.contract.as_bytes());
+        }
+        if !multisig {
+            return Ok(());
+        }
+        match &val.multisig {
+            None => out.push(0),
+            Some(multisig) => {
+                out.push(1);
+                put_varint(out, multisig.threshold as u64);
+                put_varint(out, multisig.keys.len() as u64);
+                for key in &multisig.keys {
+                    out.push(key.scheme.tag());
+                    put_bytes(out, &key.public_key);
+                }
+                put_varint(out, multisig.signatures.len() as u64);
+                for signature in &multisig.signatures {
+                    put_bytes(out, signature);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn value(&mut self, val: &ValueRef) -> StdResult<()> {
+        let out = &mut self.out;
+        match val {
+            ValueRef::Full(val) => {
+                out.push(0);
+                return self.input(val);
+            }
+            ValueRef::Digest(digest) => match from_hex(digest) {
+                Some(raw) if raw.len() == 32 => {
+                    out.push(1);
+                    out.extend_from_slice(&raw);
+                }
+                _ => {
+                    out.push(2);
+                    put_bytes(out, digest.as_bytes());
+                }
+            },
+            ValueRef::Chunked(digest) => {
+                out.push(3);
+                put_bytes(out, digest.as_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    fn u32(&mut self, n: u32) {
+        put_varint(&mut self.out, n as u64);
+    }
+
+    fn string(&mut self, s: &str) {
+        put_bytes(&mut self.out, s.as_bytes());
+    }
+
+    fn msg(&mut self, msg: &WireMsg) -> StdResult<()> {
+        match msg {
+            Msg::Request { view, chain_id } => {
+                self.out.push(0);
+                self.u32(*view);
+                self.string(chain_id);
+            }
+            Msg::Suggest { chain_id, view, key2, key2_val, prev_key2, key3, key3_val } => {
+                self.out.push(1);
+                self.string(chain_id);
+                self.u32(*view);
+                self.u32(*key2);
+                self.value(key2_val)?;
+                put_i32(&mut self.out, *prev_key2);
+                self.u32(*key3);
+                self.value(key3_val)?;
+            }
+            Msg::Proof { key1, key1_val, prev_key1, view } => {
+                self.out.push(2);
+                self.u32(*key1);
+                self.value(key1_val)?;
+                put_i32(&mut self.out, *prev_key1);
+                self.u32(*view);
+            }
+            Msg::Abort { view, chain_id } => {
+                self.out.push(3);
+                self.u32(*view);
+                self.string(chain_id);
+            }
+            Msg::Propose { chain_id, k, v, view } => {
+                self.out.push(4);
+                self.string(chain_id);
+                self.u32(*k);
+                self.value(v)?;
+                self.u32(*view);
+            }
+            Msg::Echo { val, view } => {
+                self.out.push(5);
+                self.value(val)?;
+                self.u32(*view);
+            }
+            Msg::Key1 { val, view } => {
+                self.out.push(6);
+                self.value(val)?;
+                self.u32(*view);
+            }
+            Msg::Key2 { val, view } => {
+                self.out.push(7);
+                self.value(val)?;
+                self.u32(*view);
+            }
+            Msg::Key3 { val, view } => {
+                self.out.push(8);
+                self.value(val)?;
+                self.u32(*view);
+            }
+            Msg::Lock { val, view } => {
+                self.out.push(9);
+                self.value(val)?;
+                self.u32(*view);
+            }
+            Msg::Done { val } => {
+                self.out.push(10);
+                self.value(val)?;
+            }
+            Msg::FetchValue { digest } => {
+                self.out.push(11);
+                self.value(&ValueRef::Digest(digest.clone()))?;
+            }
+            Msg::Value { val } => {
+                self.out.push(12);
+                self.input(val)?;
+            }
+            Msg::ValueChunk { digest, index, total, data } => {
+                self.out.push(13);
+                self.value(&ValueRef::Digest(digest.clone()))?;
+                self.u32(*index);
+                self.u32(*total);
+                put_bytes(&mut self.out, data);
+            }
+        }
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    // layout of the packet, read from its first byte
+    version: u8,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> StdResult<&'a [u8]> {
+        if self.data.len() - self.pos < len {
+            return Err(StdError::generic_err("Truncated compact packet"));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> StdResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn varint(&mut self) -> StdResult<u64> {
+        let mut n: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            // the 10th byte only has room for the top bit of a u64 and ends the varint
+            if shift == 63 && byte > 1 {
+                return Err(StdError::generic_err("Varint overflows u64 in compact packet"));
+            }
+            n |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(StdError::generic_err("Varint too long in compact packet"))
+    }
+
+    fn u32(&mut self) -> StdResult<u32> {
+        let n = self.varint()?;
+        if n > u32::MAX as u64 {
+            return Err(StdError::generic_err("Integer out of range in compact packet"));
+        }
+        Ok(n as u32)
+    }
+
+    fn i32(&mut self) -> StdResult<i32> {
+        let n = self.u32()?;
+        Ok(((n >> 1) as i32) ^ -((n & 1) as i32))
+    }
+
+    fn bytes(&mut self) -> StdResult<Vec<u8>> {
+        let len = self.varint()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn string(&mut self) -> StdResult<String> {
+        String::from_utf8(self.bytes()?)
+            .map_err(|_| StdError::generic_err("Invalid utf-8 in compact packet"))
+    }
+
+    fn input(&mut self) -> StdResult<TBInput> {
+        Ok(TBInput {
+            binary: self.string()?,
+            public_key: self.bytes()?,
+            signature: self.bytes()?,
+            scheme: if self.version >= 5 { self.scheme()? } else { SignatureScheme::default() },
+            nonce: self.varint()?,
+            domain: self.domain()?,
+            multisig: if self.version >= 6 { self.multisig()? } else { None },
+        })
+    }
+
+    fn scheme(&mut self) -> StdResult<SignatureScheme> {
+        let tag = self.u8()?;
+        SignatureScheme::from_tag(tag)
+            .ok_or_else(|| StdError::generic_err(format!("Unknown signature scheme {} in compact packet", tag)))
+    }
+
+    fn domain(&mut self) -> StdResult<InputDomain> {
+        let deployment_id = self.string()?;
+        let count = self.varint()?;
+        let mut targets = Vec::new();
+        for _ in 0..count {
+            targets.push(InputTarget {
+                chain_id: self.string()?,
+                contract: self.string()?,
+            });
+        }
+        Ok(InputDomain { deployment_id, targets })
+    }
+
+    fn multisig(&mut self) -> StdResult<Option<Multisig>> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => {
+                let threshold = self.u32()?;
+                let count = self.varint()?;
+                let mut keys = Vec::new();
+                for _ in 0..count {
+                    keys.push(MultisigKey {
+                        scheme: self.scheme()?,
+                        public_key: self.bytes()?,
+                    });
+                }
+                let count = self.varint()?;
+                let mut signatures = Vec::new();
+                for _ in 0..count {
+                    signatures.push(self.bytes()?);
+                }
+                Ok(Some(Multisig { threshold, keys, signatures }))
+            }
+            tag => Err(StdError::generic_err(format!("Unknown multisig tag {} in compact packet", tag))),
+        }
+    }
+
+    fn value(&mut self) -> StdResult<ValueRef> {
+        match self.u8()? {
+            0 => Ok(ValueRef::Full(self.input()?)),
+            1 => Ok(ValueRef::Digest(to_hex(self.take(32)?))),
+            2 => Ok(ValueRef::Digest(self.string()?)),
+            3 => Ok(ValueRef::Chunked(self.string()?)),
+            tag => Err(StdError::generic_err(format!("Unknown value tag {} in compact packet", tag))),
+        }
+    }
+
+    fn digest(&mut self) -> StdResult<String> {
+        match self.value()? {
+            ValueRef::Digest(digest) => Ok(digest),
+            _ => Err(StdError::generic_err("Expected a digest in compact packet")),
+        }
+    }
+
+    fn msg(&mut self) -> StdResult<WireMsg> {
+        Ok(match self.u8()? {
+            0 => Msg::Request { view: self.u32()?, chain_id: self.string()? },
+            1 => Msg::Suggest {
+                chain_id: self.string()?,
+                view: self.u32()?,
+                key2: self.u32()?,
+                key2_val: self.value()?,
+                prev_key2: self.i32()?,
+                key3: self.u32()?,
+                key3_val: self.value()?,
+            },
+            2 => Msg::Proof {
+                key1: self.u32()?,
+                key1_val: self.value()?,
+                prev_key1: self.i32()?,
+                view: self.u32()?,
+            },
+            3 => Msg::Abort { view: self.u32()?, chain_id: self.string()? },
+            4 => Msg::Propose {
+                chain_id: self.string()?,
+                k: self.u32()?,
+                v: self.value()?,
+                view: self.u32()?,
+            },
+            5 => Msg::Echo { val: self.value()?, view: self.u32()? },
+            6 => Msg::Key1 { val: self.value()?, view: self.u32()? },
+            7 => Msg::Key2 { val: self.value()?, view: self.u32()? },
+            8 => Msg::Key3 { val: self.value()?, view: self.u32()? },
+            9 => Msg::Lock { val: self.value()?, view: self.u32()? },
+            10 => Msg::Done { val: self.value()? },
+            11 => Msg::FetchValue { digest: self.digest()? },
+            12 => Msg::Value { val: self.input()? },
+            13 => Msg::ValueChunk {
+                digest: self.digest()?,
+                index: self.u32()?,
+                total: self.u32()?,
+                data: Binary(self.bytes()?),
+            },
+            tag => return Err(StdError::generic_err(format!("Unknown message tag {} in compact packet", tag))),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(binary: &str) -> TBInput {
+        TBInput {
+            binary: binary.to_string(),
+            public_key: vec![3, 117, 218, 217, 204, 108, 10, 167, 180, 109, 53, 118, 212, 125, 89, 153, 137],
+            signature: vec![244; 64],
+            scheme: SignatureScheme::EthPersonalSign,
+            nonce: 300,
+            domain: InputDomain {
+                deployment_id: "test".to_string(),
+                targets: vec![
+                    InputTarget { chain_id: "ibc-1".to_string(), contract: "wasm1ns".to_string() },
+                    InputTarget { chain_id: "ibc-2".to_string(), contract: "wasm1ns".to_string() },
+                ],
+            },
+            multisig: None,
+        }
+    }
+
+    fn multisig_input(binary: &str) -> TBInput {
+        TBInput {
+            public_key: vec![],
+            signature: vec![],
+            multisig: Some(Multisig {
+                threshold: 2,
+                keys: vec![
+                    MultisigKey { scheme: SignatureScheme::Secp256k1, public_key: vec![2; 33] },
+                    MultisigKey { scheme: SignatureScheme::Ed25519, public_key: vec![7; 32] },
+                    MultisigKey { scheme: SignatureScheme::EthPersonalSign, public_key: vec![4; 65] },
+                ],
+                signatures: vec![vec![1; 64], vec![], vec![3; 64]],
+            }),
+            ..input(binary)
+        }
+    }
+
+    fn all_msgs() -> Vec<WireMsg> {
+        let full = ValueRef::Full(input("eyJyZWdpc3RlciI6eyJuYW1lIjoidGVzdCJ9fQ=="));
+        let digest = ValueRef::Digest(input("f").digest());
+        vec![
+            Msg::Request { view: 0, chain_id: "3".to_string() },
+            Msg::Suggest {
+                chain_id: "ibc-1".to_string(),
+                view: 2,
+                key2: 1,
+                key2_val: digest.clone(),
+                prev_key2: -1,
+                key3: 0,
+                key3_val: ValueRef::Digest("not-a-sha256".to_string()),
+            },
+            Msg::Proof { key1: 1, key1_val: digest.clone(), prev_key1: i32::MIN, view: u32::MAX },
+            Msg::Abort { view: 7, chain_id: "2".to_string() },
+            Msg::Propose { chain_id: "ibc-1".to_string(), k: 0, v: full, view: 0 },
+            Msg::Echo { val: digest.clone(), view: 0 },
+            Msg::Key1 { val: digest.clone(), view: 0 },
+            Msg::Key2 { val: digest.clone(), view: 0 },
+            Msg::Key3 { val: digest.clone(), view: 0 },
+            Msg::Lock { val: digest.clone(), view: 300 },
+            Msg::Done { val: digest },
+            Msg::FetchValue { digest: input("f").digest() },
+            Msg::Value { val: input("") },
+            Msg::Value { val: multisig_input("eyJ0cmFuc2ZlciI6e319") },
+            Msg::Propose { chain_id: "ibc-2".to_string(), k: 1, v: ValueRef::Chunked(input("f").digest()), view: 1 },
+            Msg::ValueChunk { digest: input("f").digest(), index: 1, total: 3, data: Binary(vec![b'{'; 40]) },
+        ]
+    }
+
+    #[test]
+    fn compact_round_trip() {
+        let packet = PacketMsg::MsgQueue(all_msgs());
+        let data = encode_packet(&packet, PacketEncoding::Compact, COMPACT_CODEC_VERSION).unwrap();
+        assert_eq!(data.0[0], COMPACT_CODEC_VERSION);
+        assert_eq!(decode_packet(&data).unwrap(), packet);
+
+        let empty = PacketMsg::MsgQueue(vec![]);
+        let data = encode_packet(&empty, PacketEncoding::Compact, COMPACT_CODEC_VERSION).unwrap();
+        assert_eq!(decode_packet(&data).unwrap(), empty);
+    }
+
+    #[test]
+    fn compact_is_smaller_than_json() {
+        let packet = PacketMsg::MsgQueue(all_msgs());
+        let compact = encode_packet(&packet, PacketEncoding::Compact, COMPACT_CODEC_VERSION).unwrap();
+        let json = encode_packet(&packet, PacketEncoding::Json, COMPACT_CODEC_VERSION).unwrap();
+        assert!(compact.len() * 2 < json.len(), "{} vs {}", compact.len(), json.len());
+    }
+
+    #[test]
+    fn json_is_accepted_on_any_channel() {
+        // what a chain running the json-only code sends
+        let legacy = br#"{"msg_queue":[{"request":{"view":0,"chain_id":1}},{"echo":{"val":{"digest":"ab"},"view":0}}]}"#;
+        assert_eq!(
+            decode_packet(legacy).unwrap(),
+            PacketMsg::MsgQueue(vec![
+                Msg::Request { view: 0, chain_id: "1".to_string() },
+                Msg::Echo { val: ValueRef::Digest("ab".to_string()), view: 0 },
+            ])
+        );
+
+        let packet = PacketMsg::MsgQueue(all_msgs());
+        let data = encode_packet(&packet, PacketEncoding::Json, COMPACT_CODEC_VERSION).unwrap();
+        assert_eq!(decode_packet(&data).unwrap(), packet);
+    }
+
+    #[test]
+    fn handshake_stays_json() {
+        let packet = PacketMsg::WhoAmI {
+            chain_id: "ibc-1".to_string(),
+            protocol_version: 1,
+            fingerprint: "ab".to_string(),
+            contract_addr: "wasm1".to_string(),
+            network_id: "ibc-1".to_string(),
+        };
+        let data = encode_packet(&packet, PacketEncoding::Compact, COMPACT_CODEC_VERSION).unwrap();
+        assert_eq!(data, to_binary(&packet).unwrap());
+        assert_eq!(decode_packet(&data).unwrap(), packet);
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        for version in [MIN_COMPACT_CODEC_VERSION - 1, COMPACT_CODEC_VERSION + 1] {
+            let mut data = encode_queue(&all_msgs(), COMPACT_CODEC_VERSION).unwrap();
+            data[0] = version;
+            let err = decode_packet(&data).unwrap_err();
+            assert!(err.to_string().contains("Unsupported compact codec version"), "{}", err);
+        }
+
+        assert_eq!(PacketEncoding::from_version(IBC_APP_VERSION_COMPACT).unwrap(), PacketEncoding::Compact);
+        assert_eq!(PacketEncoding::from_version("trustboost-test").unwrap(), PacketEncoding::Json);
+        for version in [MIN_COMPACT_CODEC_VERSION - 1, COMPACT_CODEC_VERSION + 1] {
+            let version = format!("{}{}", IBC_APP_VERSION_COMPACT_PREFIX, version);
+            assert!(PacketEncoding::from_version(&version).is_err());
+        }
+        assert!(PacketEncoding::from_version("trustboost-compact-x").is_err());
+    }
+
+    fn layout(version: u8) -> String {
+        format!("{}{}", IBC_APP_VERSION_COMPACT_PREFIX, version)
+    }
+
+    #[test]
+    fn versions_are_negotiated_down() {
+        assert_eq!(negotiate_version(IBC_APP_VERSION_COMPACT, &layout(5)).unwrap(), Some(5));
+        assert_eq!(negotiate_version(IBC_APP_VERSION_COMPACT, IBC_APP_VERSION_COMPACT).unwrap(), Some(6));
+        // a newer chain proposing a layout we don't know of gets ours
+        assert_eq!(negotiate_version(&layout(9), IBC_APP_VERSION_COMPACT).unwrap(), Some(6));
+        assert_eq!(negotiate_version("trustboost-test", "trustboost-test").unwrap(), None);
+
+        // no answering with a newer layout than proposed, one we don't support, or another encoding
+        assert!(negotiate_version(&layout(5), IBC_APP_VERSION_COMPACT).is_err());
+        assert!(negotiate_version(IBC_APP_VERSION_COMPACT, &layout(3)).is_err());
+        assert!(negotiate_version(IBC_APP_VERSION_COMPACT, "trustboost-test").is_err());
+        assert!(negotiate_version("trustboost-test", IBC_APP_VERSION_COMPACT).is_err());
+    }
+
+    // the messages of all_msgs an older layout can carry
+    fn msgs_of(version: u8) -> Vec<WireMsg> {
+        let mut msgs = all_msgs();
+        msgs.retain(|msg| match msg {
+            Msg::Value { val } => val.multisig.is_none() || version >= 6,
+            _ => true,
+        });
+        if version < 5 {
+            for msg in msgs.iter_mut() {
+                match msg {
+                    Msg::Value { val } | Msg::Propose { v: ValueRef::Full(val), .. } => val.scheme = SignatureScheme::Secp256k1,
+                    _ => (),
+                }
+            }
+        }
+        msgs
+    }
+
+    #[test]
+    fn older_layouts_round_trip() {
+        for version in MIN_COMPACT_CODEC_VERSION..=COMPACT_CODEC_VERSION {
+            let msgs = msgs_of(version);
+            let data = encode_queue(&msgs, version).unwrap();
+            assert_eq!(data[0], version);
+            assert_eq!(decode_queue(&data).unwrap(), msgs, "version {}", version);
+        }
+        // every layout drops fields of the next one
+        let sizes: Vec<usize> = (MIN_COMPACT_CODEC_VERSION..=COMPACT_CODEC_VERSION)
+            .map(|version| encode_queue(&msgs_of(4), version).unwrap().len())
+            .collect();
+        assert!(sizes.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", sizes);
+    }
+
+    #[test]
+    fn older_layouts_are_read() {
+        // a Value in the layout of version 4: binary, public key and signature, nonce and domain
+        let data = [4, 1, 12, 2, b'e', b'0', 1, 2, 1, 3, 7, 1, b't', 1, 1, b'1', 1, b'w'];
+        let val = TBInput {
+            binary: "e0".to_string(),
+            public_key: vec![2],
+            signature: vec![3],
+            scheme: SignatureScheme::Secp256k1,
+            nonce: 7,
+            domain: InputDomain {
+                deployment_id: "t".to_string(),
+                targets: vec![InputTarget { chain_id: "1".to_string(), contract: "w".to_string() }],
+            },
+            multisig: None,
+        };
+        assert_eq!(decode_queue(&data).unwrap(), vec![Msg::Value { val: val.clone() }]);
+
+        // version 5 adds the scheme after the signature
+        let mut data = data.to_vec();
+        data[0] = 5;
+        data.insert(10, SignatureScheme::Ed25519.tag());
+        let val = TBInput { scheme: SignatureScheme::Ed25519, ..val };
+        assert_eq!(decode_queue(&data).unwrap(), vec![Msg::Value { val }]);
+    }
+
+    #[test]
+    fn queues_an_older_layout_cannot_carry_go_as_json() {
+        let packet = PacketMsg::MsgQueue(all_msgs());
+        for version in MIN_COMPACT_CODEC_VERSION..COMPACT_CODEC_VERSION {
+            let data = encode_packet(&packet, PacketEncoding::Compact, version).unwrap();
+            assert_eq!(data, to_binary(&packet).unwrap());
+            assert_eq!(decode_packet(&data).unwrap(), packet);
+        }
+        let packet = PacketMsg::MsgQueue(msgs_of(4));
+        let data = encode_packet(&packet, PacketEncoding::Compact, 4).unwrap();
+        assert_eq!(data.0[0], 4);
+        assert_eq!(decode_packet(&data).unwrap(), packet);
+    }
+
+    #[test]
+    fn rejects_malformed_data() {
+        let data = encode_queue(&all_msgs(), COMPACT_CODEC_VERSION).unwrap();
+        for len in 1..data.len() {
+            assert!(decode_queue(&data[..len]).is_err(), "accepted truncation at {}", len);
+        }
+        let mut trailing = data.clone();
+        trailing.push(0);
+        assert!(decode_queue(&trailing).is_err());
+
+        let unknown_tag = vec![COMPACT_CODEC_VERSION, 1, 99];
+        assert!(decode_queue(&unknown_tag).is_err());
+
+        let mut reader = Reader { data: &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01], pos: 0, version: 0 };
+        assert_eq!(reader.varint().unwrap(), u64::MAX);
+        // bits beyond 64, and a continuation after the 10th byte
+        for last in [0x02, 0x7f, 0x81] {
+            let mut data = vec![0xff; 9];
+            data.extend([last, 0x00]);
+            let mut reader = Reader { data: &data, pos: 0, version: 0 };
+            assert!(reader.varint().is_err(), "accepted {:#x}", last);
+        }
+    }
+}
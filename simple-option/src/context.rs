@@ -0,0 +1,436 @@
+// Execution context of a single entry point call.
+//
+// The protocol tables touched by packet processing (State, channels, highest request/abort,
+// the received sets, the vote maps, the pending send_all_upon_join messages and the backlog) are loaded once,
+// mutated in memory by the handlers and written back by commit(), which only writes the entries
+// that actually changed. Everything else (values, nonces, log, timeline, ...) is written through `store`,
+// which stages the writes until commit() as well. A context dropped without commit leaves storage as it was,
+// even when the entry point goes on to succeed, as ibc_packet_receive does with an error acknowledgement.
+// rollback() does the same without dropping the context, back to its last commit.
+
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::iter::Peekable;
+use std::ops::Bound;
+
+use cosmwasm_std::{Api, Env, Event, IbcTimeout, Order, Record, StdError, StdResult, Storage};
+use cw_storage_plus::{KeyDeserialize, Map, PrimaryKey};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::ibc_msg::Msg;
+use crate::metrics::record_metrics;
+use crate::outbox::Outbox;
+use crate::state::{
+    Metrics, PeerId, State, BACKLOG, CHANNELS, HIGHEST_ABORT, HIGHEST_REQ, PROCESSING_BUDGET, RECEIVED, RECEIVED_DONE, RECEIVED_ECHO, RECEIVED_KEY1,
+    RECEIVED_KEY2, RECEIVED_KEY3, RECEIVED_LOCK, SEND_ALL_UPON, STATE,
+};
+use crate::utils::{clear_vote_values, get_timeout, DEFAULT_PROCESSING_BUDGET};
+use crate::values::retire_pending_values;
+#[cfg(feature = "testing")]
+use crate::utils::clear_test_queue;
+
+/// Message types counted per value, each backed by its RECEIVED_* map
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Vote {
+    Echo,
+    Key1,
+    Key2,
+    Key3,
+    Lock,
+    Done,
+}
+
+impl Vote {
+    pub const ALL: [Vote; 6] = [Vote::Echo, Vote::Key1, Vote::Key2, Vote::Key3, Vote::Lock, Vote::Done];
+
+    pub fn map(self) -> Map<'static, u64, HashSet<PeerId>> {
+        match self {
+            Vote::Echo => RECEIVED_ECHO,
+            Vote::Key1 => RECEIVED_KEY1,
+            Vote::Key2 => RECEIVED_KEY2,
+            Vote::Key3 => RECEIVED_KEY3,
+            Vote::Lock => RECEIVED_LOCK,
+            Vote::Done => RECEIVED_DONE,
+        }
+    }
+}
+
+pub type VoteMaps = BTreeMap<Vote, BTreeMap<u64, HashSet<PeerId>>>;
+
+// what was read from storage, commit() writes the difference
+#[derive(Clone)]
+struct Snapshot {
+    state: State,
+    channels: BTreeMap<PeerId, String>,
+    highest_req: BTreeMap<PeerId, u32>,
+    highest_abort: BTreeMap<PeerId, i32>,
+    received: BTreeMap<String, HashSet<PeerId>>,
+    votes: VoteMaps,
+    outbox: Outbox,
+    events: usize,
+    inbox: VecDeque<(Option<String>, Msg)>,
+    budget: u32,
+}
+
+pub struct Context<'a> {
+    pub store: StagedStore<'a>,
+    pub api: &'a dyn Api,
+    pub env: &'a Env,
+    pub timeout: IbcTimeout,
+
+    pub state: State,
+    pub channels: BTreeMap<PeerId, String>,
+    pub highest_req: BTreeMap<PeerId, u32>,
+    pub highest_abort: BTreeMap<PeerId, i32>,
+    pub received: BTreeMap<String, HashSet<PeerId>>,
+    pub votes: VoteMaps,
+    pub outbox: Outbox,
+    // counters of this transaction, added to the stored ones by commit()
+    pub metrics: Metrics,
+    // protocol events for the response of the entry point, see events.rs
+    pub events: Vec<Event>,
+
+    // messages waiting to be handled, with the channel they arrived on or None when we sent them to ourselves.
+    // It starts with the backlog of previous transactions, what is left of it is committed as the new backlog
+    pub inbox: VecDeque<(Option<String>, Msg)>,
+    // number of messages that may be handled in this transaction
+    pub budget: u32,
+
+    loaded: Box<Snapshot>,
+}
+
+impl<'a> Context<'a> {
+    pub fn load(store: &'a mut dyn Storage, api: &'a dyn Api, env: &'a Env) -> StdResult<Self> {
+        let mut votes = VoteMaps::new();
+        for vote in Vote::ALL {
+            votes.insert(vote, load_map(store, vote.map())?);
+        }
+        let loaded = Snapshot {
+            state: STATE.load(store)?,
+            channels: load_map(store, CHANNELS)?,
+            highest_req: load_map(store, HIGHEST_REQ)?,
+            highest_abort: load_map(store, HIGHEST_ABORT)?,
+            received: load_map(store, RECEIVED)?,
+            votes,
+            outbox: Outbox::new(load_map(store, SEND_ALL_UPON)?),
+            events: 0,
+            inbox: BACKLOG.may_load(store)?.unwrap_or_default(),
+            budget: PROCESSING_BUDGET.may_load(store)?.unwrap_or(DEFAULT_PROCESSING_BUDGET),
+        };
+        Ok(Context {
+            store: StagedStore::new(store),
+            api,
+            env,
+            timeout: get_timeout(env),
+            state: loaded.state.clone(),
+            channels: loaded.channels.clone(),
+            highest_req: loaded.highest_req.clone(),
+            highest_abort: loaded.highest_abort.clone(),
+            received: loaded.received.clone(),
+            votes: loaded.votes.clone(),
+            outbox: loaded.outbox.clone(),
+            metrics: Metrics::default(),
+            events: Vec::new(),
+            inbox: loaded.inbox.clone(),
+            budget: loaded.budget,
+            loaded: Box::new(loaded),
+        })
+    }
+
+    // writes back whatever changed since the context was loaded or last committed
+    pub fn commit(&mut self) -> StdResult<()> {
+        let store = &mut self.store;
+        if self.state != self.loaded.state {
+            STATE.save(store, &self.state)?;
+        }
+        commit_map(store, CHANNELS, &self.loaded.channels, &self.channels)?;
+        commit_map(store, HIGHEST_REQ, &self.loaded.highest_req, &self.highest_req)?;
+        commit_map(store, HIGHEST_ABORT, &self.loaded.highest_abort, &self.highest_abort)?;
+        commit_map(store, RECEIVED, &self.loaded.received, &self.received)?;
+        let empty = BTreeMap::new();
+        for vote in Vote::ALL {
+            let before = self.loaded.votes.get(&vote).unwrap_or(&empty);
+            let after = self.votes.get(&vote).unwrap_or(&empty);
+            commit_map(store, vote.map(), before, after)?;
+        }
+        commit_map(store, SEND_ALL_UPON, self.loaded.outbox.held(), self.outbox.held())?;
+        if self.inbox != self.loaded.inbox {
+            if self.inbox.is_empty() {
+                BACKLOG.remove(store);
+            } else {
+                BACKLOG.save(store, &self.inbox)?;
+            }
+        }
+        if !self.metrics.is_empty() {
+            record_metrics(store, &std::mem::take(&mut self.metrics))?;
+        }
+        store.flush();
+
+        *self.loaded = Snapshot {
+            state: self.state.clone(),
+            channels: self.channels.clone(),
+            highest_req: self.highest_req.clone(),
+            highest_abort: self.highest_abort.clone(),
+            received: self.received.clone(),
+            votes: self.votes.clone(),
+            outbox: self.outbox.clone(),
+            events: self.events.len(),
+            inbox: self.inbox.clone(),
+            budget: self.budget,
+        };
+        Ok(())
+    }
+
+    // goes back to the context as it was loaded or last committed, dropping the writes made since.
+    // Messages queued and events emitted before the commit are kept, they still belong to the transaction
+    pub fn rollback(&mut self) {
+        self.store.discard();
+        let loaded = &self.loaded;
+        self.state = loaded.state.clone();
+        self.channels = loaded.channels.clone();
+        self.highest_req = loaded.highest_req.clone();
+        self.highest_abort = loaded.highest_abort.clone();
+        self.received = loaded.received.clone();
+        self.votes = loaded.votes.clone();
+        self.outbox = loaded.outbox.clone();
+        self.metrics = Metrics::default();
+        self.events.truncate(loaded.events);
+        self.inbox = loaded.inbox.clone();
+        self.budget = loaded.budget;
+    }
+
+    pub fn chain_id_of(&self, channel_id: &str) -> StdResult<PeerId> {
+        self.channels
+            .iter()
+            .find(|(_, id)| id.as_str() == channel_id)
+            .map(|(chain_id, _)| chain_id.clone())
+            .ok_or_else(|| StdError::generic_err(format!("channel {} is not bound to a chain", channel_id)))
+    }
+
+    // chain_id of the sender of a message, None meaning we sent it to ourselves
+    pub fn sender(&self, local_channel_id: &Option<String>) -> StdResult<PeerId> {
+        match local_channel_id {
+            Some(id) => self.chain_id_of(id),
+            None => Ok(self.state.chain_id.clone()),
+        }
+    }
+
+    pub fn send_to_self(&mut self, msg: Msg) {
+        self.metrics.self_deliveries += 1;
+        self.inbox.push_back((None, msg));
+    }
+
+    // a chain we have no request from yet is at view 0, the value the entries are initialized with
+    pub fn highest_req(&self, chain_id: &str) -> u32 {
+        self.highest_req.get(chain_id).copied().unwrap_or_default()
+    }
+
+    pub fn votes(&mut self, vote: Vote) -> &mut BTreeMap<u64, HashSet<PeerId>> {
+        self.votes.entry(vote).or_default()
+    }
+
+    // in memory counterpart of utils::reset_view_specific_maps
+    pub fn reset_view_specific_maps(&mut self) -> StdResult<()> {
+        for set in self.received.values_mut() {
+            set.clear();
+        }
+        for maps in self.votes.values_mut() {
+            maps.clear();
+        }
+        clear_vote_values(&mut self.store)?;
+        retire_pending_values(&mut self.store, self.state.view)?;
+        #[cfg(feature = "testing")]
+        clear_test_queue(&mut self.store)?;
+        Ok(())
+    }
+}
+
+/// Storage that keeps writes in memory until flush(), reads see them.
+/// Dropping it discards the writes, the underlying storage is only read until then
+pub struct StagedStore<'a> {
+    base: &'a mut dyn Storage,
+    // None marks a removed key
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<'a> StagedStore<'a> {
+    pub fn new(base: &'a mut dyn Storage) -> Self {
+        StagedStore { base, writes: BTreeMap::new() }
+    }
+
+    // applies the staged writes to the underlying storage
+    fn flush(&mut self) {
+        for (key, value) in std::mem::take(&mut self.writes) {
+            match value {
+                Some(value) => self.base.set(&key, &value),
+                None => self.base.remove(&key),
+            }
+        }
+    }
+
+    // forgets the staged writes
+    fn discard(&mut self) {
+        self.writes.clear();
+    }
+}
+
+impl Storage for StagedStore<'_> {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        match self.writes.get(key) {
+            Some(value) => value.clone(),
+            None => self.base.get(key),
+        }
+    }
+
+    fn range<'b>(&'b self, start: Option<&[u8]>, end: Option<&[u8]>, order: Order) -> Box<dyn Iterator<Item = Record> + 'b> {
+        let bounds = (start.map_or(Bound::Unbounded, Bound::Included), end.map_or(Bound::Unbounded, Bound::Excluded));
+        let writes = self.writes.range::<[u8], _>(bounds);
+        let writes: StagedWrites<'b> = match order {
+            Order::Ascending => Box::new(writes),
+            Order::Descending => Box::new(writes.rev()),
+        };
+        Box::new(StagedRange {
+            base: self.base.range(start, end, order).peekable(),
+            writes: writes.peekable(),
+            order,
+        })
+    }
+
+    fn set(&mut self, key: &[u8], value: &[u8]) {
+        self.writes.insert(key.to_vec(), Some(value.to_vec()));
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        self.writes.insert(key.to_vec(), None);
+    }
+}
+
+type StagedWrites<'b> = Box<dyn Iterator<Item = (&'b Vec<u8>, &'b Option<Vec<u8>>)> + 'b>;
+
+// merges the records of the underlying storage with the staged writes, both in the order of the range
+struct StagedRange<'b> {
+    base: Peekable<Box<dyn Iterator<Item = Record> + 'b>>,
+    writes: Peekable<StagedWrites<'b>>,
+    order: Order,
+}
+
+impl Iterator for StagedRange<'_> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        loop {
+            let staged_first = match (self.base.peek(), self.writes.peek()) {
+                (None, None) => return None,
+                (Some(_), None) => false,
+                (None, Some(_)) => true,
+                (Some((base_key, _)), Some((staged_key, _))) => {
+                    let ordering = match self.order {
+                        Order::Ascending => base_key.cmp(staged_key),
+                        Order::Descending => (*staged_key).cmp(base_key),
+                    };
+                    match ordering {
+                        std::cmp::Ordering::Less => false,
+                        // the staged write shadows the stored record
+                        std::cmp::Ordering::Equal => {
+                            self.base.next();
+                            true
+                        }
+                        std::cmp::Ordering::Greater => true,
+                    }
+                }
+            };
+            if !staged_first {
+                return self.base.next();
+            }
+            if let Some((key, Some(value))) = self.writes.next() {
+                return Some((key.clone(), value.clone()));
+            }
+        }
+    }
+}
+
+fn load_map<K, V>(store: &dyn Storage, map: Map<'static, K, V>) -> StdResult<BTreeMap<K, V>>
+where
+    K: PrimaryKey<'static> + KeyDeserialize<Output = K> + Ord + 'static,
+    V: Serialize + DeserializeOwned,
+{
+    map.range(store, None, None, Order::Ascending).collect()
+}
+
+fn commit_map<K, V>(
+    store: &mut dyn Storage,
+    map: Map<'static, K, V>,
+    before: &BTreeMap<K, V>,
+    after: &BTreeMap<K, V>,
+) -> StdResult<()>
+where
+    K: PrimaryKey<'static> + Ord + Clone,
+    V: Serialize + DeserializeOwned + PartialEq,
+{
+    for (key, value) in after {
+        if before.get(key) != Some(value) {
+            map.save(store, key.clone(), value)?;
+        }
+    }
+    for key in before.keys().filter(|key| !after.contains_key(key)) {
+        map.remove(store, key.clone());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::testing::MockStorage;
+
+    use super::*;
+
+    fn base() -> MockStorage {
+        let mut store = MockStorage::new();
+        for key in [b"a", b"c", b"e"] {
+            store.set(key, key);
+        }
+        store
+    }
+
+    fn keys(store: &dyn Storage, start: Option<&[u8]>, end: Option<&[u8]>, order: Order) -> Vec<Vec<u8>> {
+        store.range(start, end, order).map(|(key, _)| key).collect()
+    }
+
+    #[test]
+    fn staged_writes_are_read_back() {
+        let mut base = base();
+        let mut staged = StagedStore::new(&mut base);
+        staged.set(b"b", b"new");
+        staged.set(b"c", b"changed");
+        staged.remove(b"e");
+        assert_eq!(staged.get(b"a"), Some(b"a".to_vec()));
+        assert_eq!(staged.get(b"c"), Some(b"changed".to_vec()));
+        assert_eq!(staged.get(b"e"), None);
+
+        let all: Vec<Record> = staged.range(None, None, Order::Ascending).collect();
+        assert_eq!(all, vec![
+            (b"a".to_vec(), b"a".to_vec()),
+            (b"b".to_vec(), b"new".to_vec()),
+            (b"c".to_vec(), b"changed".to_vec()),
+        ]);
+        assert_eq!(keys(&staged, None, None, Order::Descending), vec![b"c".to_vec(), b"b".to_vec(), b"a".to_vec()]);
+        assert_eq!(keys(&staged, Some(b"b"), Some(b"e"), Order::Ascending), vec![b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(keys(&staged, Some(b"b"), Some(b"c"), Order::Descending), vec![b"b".to_vec()]);
+    }
+
+    #[test]
+    fn writes_reach_storage_only_when_flushed() {
+        let mut base = base();
+        let mut staged = StagedStore::new(&mut base);
+        staged.set(b"b", b"new");
+        staged.remove(b"a");
+        drop(staged);
+        assert_eq!(keys(&base, None, None, Order::Ascending), vec![b"a".to_vec(), b"c".to_vec(), b"e".to_vec()]);
+
+        let mut staged = StagedStore::new(&mut base);
+        staged.set(b"b", b"new");
+        staged.remove(b"a");
+        staged.flush();
+        assert_eq!(keys(&base, None, None, Order::Ascending), vec![b"b".to_vec(), b"c".to_vec(), b"e".to_vec()]);
+    }
+}
@@ -2,34 +2,52 @@
 use cosmwasm_std::entry_point;
 use cosmwasm_std::{
     to_binary, Binary, Deps, DepsMut, Env, IbcMsg, IbcTimeout, MessageInfo, Order, Reply, Response,
-    StdError, StdResult, SubMsg, wasm_execute, WasmMsg, Storage, Addr, Timestamp,
+    StdError, StdResult, SubMsg, SubMsgResult, wasm_execute, WasmMsg, Storage, Addr, Timestamp, ContractInfoResponse, WasmQuery,
 };
 
-use std::convert::TryInto;
 
 use cw2::set_contract_version;
+use cw_storage_plus::Map;
 use std::cmp::Ordering;
 use std::collections::HashSet;
-use sha2::{Digest, Sha256};
 
+use crate::codec::decode_packet;
+use crate::context::Context;
+use crate::migrate::{self, CURRENT_STORAGE_VERSION, STORAGE_VERSION};
 use crate::error::ContractError;
 use crate::ibc_msg::{Msg, PacketMsg};
-use crate::queue_handler::{receive_queue, send_all_party};
-use crate::utils::{get_timeout, init_receive_map, get_id_channel_pair_from_storage, convert_send_ibc_msg, derive_addr_from_pubkey, get_seconds_diff};
-use crate::view_change::{view_change, convert_queue_to_ibc_msgs, testing_add2queue};
+use crate::queue_handler::{receive_queue, run_backlog, send_all_party};
+use crate::utils::{get_timeout, init_receive_map, get_id_channel_pair_from_storage, convert_send_ibc_msg, get_seconds_diff, local_bech32_prefix, validate_bech32_prefix, DEFAULT_BECH32_PREFIX, DEFAULT_PROCESSING_BUDGET, DEFAULT_VALUE_CHUNK_SIZE};
+use crate::view_change::{view_change, convert_queue_to_ibc_msgs};
 // use crate::ibc_msg::PacketMsg;
+use crate::admin::{accept_admin, assert_admin, query_admin, transfer_admin};
+use crate::diagnose::diagnose;
+use crate::replay::{check_nonce, query_nonce};
+use crate::signature::{query_addresses, signer, verify_signature};
+use crate::submission::{check_submission, query_submission_policy, set_submission_policy};
+use crate::values::check_value_size;
+use crate::health::peer_health;
+use crate::metrics::query_metrics;
+use crate::timeline::{query_timeline, record_milestone_at};
+use crate::events::{protocol_event, EVENT_EXECUTION};
+use crate::log::{log_at, query_logs, set_log_config};
 use crate::msg::{
-    AbortResponse, ChannelsResponse, DoneQueryResponse, EchoQueryResponse, ExecuteMsg,
-    HighestAbortResponse, HighestReqResponse, InstantiateMsg, Key1QueryResponse, Key2QueryResponse,
+    AbortResponse, BacklogResponse, PeerHealthResponse, ChannelsResponse, DoneQueryResponse, EchoQueryResponse, ExecuteMsg, HandshakesResponse,
+    HighestAbortResponse, HighestReqResponse, InstantiateMsg, MigrateMsg, Key1QueryResponse, Key2QueryResponse,
     Key3QueryResponse, LockQueryResponse, QueryMsg, ReceivedSuggestResponse, SendAllUponResponse,
-    StateResponse, TestQueueResponse,
+    StateResponse, VoteEntry, VoteValue,
 };
+#[cfg(feature = "testing")]
+use crate::msg::TestQueueResponse;
 use crate::state::{
-    State, CHANNELS, DEBUG, HIGHEST_ABORT, HIGHEST_REQ, RECEIVED, RECEIVED_ECHO, DEBUG_CTR,
-    RECEIVED_KEY1, RECEIVED_KEY2, RECEIVED_KEY3, RECEIVED_LOCK, STATE, TEST, RECEIVED_DONE, IBC_MSG_SEND_DEBUG, InputType,
-    DEBUG_RECEIVE_MSG
+    State, CHANNELS, HIGHEST_ABORT, HIGHEST_REQ, RECEIVED, RECEIVED_ECHO,
+    RECEIVED_KEY1, RECEIVED_KEY2, RECEIVED_KEY3, RECEIVED_LOCK, STATE, RECEIVED_DONE, InputType,
+    MEMBERS, HANDSHAKES, VOTE_VALUES, PeerId, Milestone, LogCategory, LogConfig, LogLevel
 };
-use crate::state::{SEND_ALL_UPON, TEST_QUEUE};
+use crate::state::{ADMIN, BECH32_PREFIX, DEPLOYMENT_ID, MEMBER_BECH32_PREFIXES, SEND_ALL_UPON, BACKLOG, PROCESSING_BUDGET, VALUE_CHUNK_SIZE};
+#[cfg(feature = "testing")]
+use crate::state::{TEST, TEST_QUEUE};
+#[cfg(feature = "testing")]
 use crate::malicious_trigger::{trigger_done, trigger_done_2, trigger_abort, trigger_key1_diff_val, trigger_multi_propose};
 
 // version info for migration info
@@ -40,7 +58,6 @@ pub const SUGGEST_REPLY_ID: u64 = 101;
 pub const PROOF_REPLY_ID: u64 = 102;
 pub const PROPOSE_REPLY_ID: u64 = 103;
 pub const VIEW_TIMEOUT_SECONDS: u64 = 0;
-pub const ALLOW_DEBUG: bool = true;
 
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn instantiate(
@@ -49,23 +66,45 @@ pub fn instantiate(
     info: MessageInfo,
     msg: InstantiateMsg,
 ) -> Result<Response, ContractError> {
-    let state = State::new(msg.chain_id, msg.input, deps.api.addr_validate(&msg.contract_addr)?, env.block.time);
+    let chain_id = msg.chain_id.unwrap_or_else(|| env.block.chain_id.clone());
+    let mut state = State::new(chain_id, msg.input, deps.api.addr_validate(&msg.contract_addr)?, env.block.time);
     // let exe_msg = WasmMsg::Execute { contract_addr: , msg: , funds: () };
     // let exe_msg: ContractExecuteMsg = serde_json::from_str(&msg.msg).unwrap();
     // let exe_msg = wasm_execute(state.contract_addr.to_string(), &msg.msg, vec![])?;
+    let bech32_prefix = msg.bech32_prefix.unwrap_or_else(|| DEFAULT_BECH32_PREFIX.to_string());
+    validate_bech32_prefix(&bech32_prefix)?;
+    BECH32_PREFIX.save(deps.storage, &bech32_prefix)?;
+    for member in msg.members {
+        MEMBERS.save(deps.storage, member.chain_id.clone(), &member.network_id)?;
+        if let Some(prefix) = member.bech32_prefix {
+            validate_bech32_prefix(&prefix)?;
+            MEMBER_BECH32_PREFIXES.save(deps.storage, member.chain_id.clone(), &prefix)?;
+        }
+        state.members.insert(member.chain_id);
+    }
     STATE.save(deps.storage, &state)?;
+    STORAGE_VERSION.save(deps.storage, &CURRENT_STORAGE_VERSION)?;
+    save_processing_budget(deps.storage, msg.processing_budget.unwrap_or(DEFAULT_PROCESSING_BUDGET))?;
+    save_value_chunk_size(deps.storage, msg.value_chunk_size.unwrap_or(DEFAULT_VALUE_CHUNK_SIZE))?;
+    let admin = match msg.admin {
+        Some(admin) => deps.api.addr_validate(&admin)?,
+        None => info.sender,
+    };
+    ADMIN.save(deps.storage, &admin)?;
+    DEPLOYMENT_ID.save(deps.storage, &msg.deployment_id)?;
+    set_submission_policy(deps.storage, &msg.submission_policy.unwrap_or_default())?;
     for msg_type in vec!["Suggest", "Proof"] {
         RECEIVED.save(deps.storage, msg_type.to_string(), &HashSet::new())?;
     }
     set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
 
-    DEBUG_CTR.save(deps.storage, &0)?;
+    set_log_config(deps.storage, &LogConfig::default())?;
 
     // let action = |_| -> StdResult<u32> { Ok(u32::MAX) };
     Ok(Response::new()
         // .add_message(exe_msg)
         .add_attribute("method", "instantiate")
-        .add_attribute("owner", info.sender))
+        .add_attribute("admin", admin))
 }
 
 // execute entry_point is used for beginning new instance of IT-HS consensus
@@ -80,120 +119,128 @@ pub fn execute(
         ExecuteMsg::Input { value } => handle_execute_input(deps, env, info, value),
         ExecuteMsg::PreInput { value } => handle_execute_preinput(deps, env, info, value),
         ExecuteMsg::ForceAbort {} => {
-            todo!()
+            assert_admin(deps.storage, &info.sender)?;
+            handle_execute_abort(deps, env, true)
         },
-        ExecuteMsg::Abort {} => handle_execute_abort(deps, env),
-        ExecuteMsg::Trigger { behavior } => handle_trigger(deps, env, behavior),
+        ExecuteMsg::Abort {} => handle_execute_abort(deps, env, false),
+        ExecuteMsg::ProcessBacklog {} => handle_execute_process_backlog(deps, env),
+        ExecuteMsg::SetLogConfig { config } => handle_execute_set_log_config(deps, info, config),
+        ExecuteMsg::SetParams { processing_budget, value_chunk_size } => {
+            handle_execute_set_params(deps, info, processing_budget, value_chunk_size)
+        },
+        ExecuteMsg::SetSubmissionPolicy { policy } => {
+            assert_admin(deps.storage, &info.sender)?;
+            set_submission_policy(deps.storage, &policy)?;
+            Ok(Response::new()
+                .add_attribute("action", "execute")
+                .add_attribute("msg_type", "set_submission_policy"))
+        },
+        ExecuteMsg::TransferAdmin { new_admin } => {
+            let new_admin = deps.api.addr_validate(&new_admin)?;
+            transfer_admin(deps.storage, &info.sender, new_admin)
+        },
+        ExecuteMsg::AcceptAdmin {} => accept_admin(deps.storage, &info.sender),
+        #[cfg(feature = "testing")]
+        ExecuteMsg::Trigger { behavior } => {
+            assert_admin(deps.storage, &info.sender)?;
+            handle_trigger(deps, env, behavior)
+        },
+        #[cfg(feature = "testing")]
         ExecuteMsg::Key3 { val, view, local_channel_id } => {
-            if !ALLOW_DEBUG {
-                return Ok(Response::new())
+            assert_admin(deps.storage, &info.sender)?;
+            let mut ctx = Context::load(deps.storage, deps.api, &env)?;
+            for chain_id in ctx.state.members.clone() {
+                ctx.highest_req.insert(chain_id, 0);
             }
-            HIGHEST_REQ.save(deps.storage, 0,&0)?;
-            HIGHEST_REQ.save(deps.storage, 1, &0)?;
-            HIGHEST_REQ.save(deps.storage, 2, &0)?;
-            HIGHEST_REQ.save(deps.storage, 3, &0)?;
-
-            let state = STATE.load(deps.storage)?;
-            let mut queue: Vec<Vec<Msg>> = vec!(Vec::new(); state.n.try_into().unwrap());
             let mut result;
             if local_channel_id != "None" {
                 result =receive_queue(
-                    deps.storage,
-                    get_timeout(&env),
+                    &mut ctx,
                     Some(local_channel_id),
                     vec![Msg::Key3 { val: val, view: view }],
-                    &mut queue,
-                    &env, 
-                    deps.api
+                    false
                 )?;
             } else {
                 result = receive_queue(
-                    deps.storage,
-                    get_timeout(&env),
+                    &mut ctx,
                     None,
                     vec![Msg::Key3 { val: val, view: view }],
-                    &mut queue,
-                    &env,
-                    deps.api
+                    false
                 )?;
             }
 
+            ctx.commit()?;
+
             let messages = result.messages;
-            Ok(Response::new().add_submessages(messages))
+            Ok(Response::new().add_submessages(messages).add_events(result.events))
         },
+        #[cfg(feature = "testing")]
         ExecuteMsg::Lock { val, view, local_channel_id } => {
-            if !ALLOW_DEBUG {
-                return Ok(Response::new())
-            }
-            let state = STATE.load(deps.storage)?;
-            let mut queue: Vec<Vec<Msg>> = vec!(Vec::new(); state.n.try_into().unwrap());
+            assert_admin(deps.storage, &info.sender)?;
+            let mut ctx = Context::load(deps.storage, deps.api, &env)?;
             let mut result;
             if local_channel_id != "None" {
                 result = receive_queue(
-                    deps.storage,
-                    get_timeout(&env),
+                    &mut ctx,
                     Some(local_channel_id),
                     vec![Msg::Lock { val: val, view: view }],
-                    &mut queue,
-                    &env,
-                    deps.api
+                    false
                 )?;
             } else {
                 result = receive_queue(
-                    deps.storage,
-                    get_timeout(&env),
+                    &mut ctx,
                     None,
                     vec![Msg::Lock { val: val, view: view }],
-                    &mut queue,
-                    &env,
-                    deps.api
+                    false
                 )?;
             }
     
+            ctx.commit()?;
+
             let messages = result.messages;
-            Ok(Response::new().add_submessages(messages))
+            Ok(Response::new().add_submessages(messages).add_events(result.events))
         },
+        #[cfg(feature = "testing")]
         ExecuteMsg::Done { val, view, local_channel_id } => {
-            if !ALLOW_DEBUG {
-                return Ok(Response::new())
-            }
-            let state = STATE.load(deps.storage)?;
-            let mut queue: Vec<Vec<Msg>> = vec!(Vec::new(); state.n.try_into().unwrap());
+            assert_admin(deps.storage, &info.sender)?;
+            let mut ctx = Context::load(deps.storage, deps.api, &env)?;
             let mut result;
             if local_channel_id != "None" {
                 result = receive_queue(
-                    deps.storage,
-                    get_timeout(&env),
+                    &mut ctx,
                     Some(local_channel_id),
                     vec![Msg::Done { val: val }],
-                    &mut queue,
-                    &env,
-                    deps.api
+                    false
                 )?;
             } else {
                 result = receive_queue(
-                    deps.storage,
-                    get_timeout(&env),
+                    &mut ctx,
                     None,
                     vec![Msg::Done { val: val }],
-                    &mut queue,
-                    &env,
-                    deps.api
+                    false
                 )?;
             }
             
+            ctx.commit()?;
+
             let messages = result.messages;
-            Ok(Response::new().add_submessages(messages))
+            Ok(Response::new().add_submessages(messages).add_events(result.events))
         },         
         ExecuteMsg::SetContractAddr { addr } => {
+            assert_admin(deps.storage, &info.sender)?;
+            let contract_addr = deps.api.addr_validate(&addr)?;
             let mut state = STATE.load(deps.storage)?;
-            state.contract_addr = cosmwasm_std::Addr::unchecked(addr);
+            state.contract_addr = contract_addr.clone();
             STATE.save(deps.storage, &state)?;
-            Ok(Response::new())
+            Ok(Response::new()
+                .add_attribute("action", "execute")
+                .add_attribute("msg_type", "set_contract_addr")
+                .add_attribute("contract_addr", contract_addr))
         },
     }
 }
 
+#[cfg(feature = "testing")]
 pub fn handle_trigger(
     deps: DepsMut,
     env: Env,
@@ -218,13 +265,16 @@ pub fn handle_execute_input(
     _info: MessageInfo,
     input: InputType,
 ) -> Result<Response, ContractError> {
-    // set timeout for broadcasting
-    let timeout: IbcTimeout = get_timeout(&env);
     /* a better way?
     CHANNELS
         .keys(deps.storage, None, None, Order::Ascending)
         .map(|id| HIGHEST_REQ.save(deps.storage, id?, &0)? );
     */
+    // the signature and domain are checked once the value is decided, reused nonces are turned down early
+    let signer = signer(deps.api, &local_bech32_prefix(deps.storage)?, &input)?;
+    check_nonce(deps.storage, &signer.address, input.nonce)?;
+    check_value_size(deps.storage, &input)?;
+    check_submission(deps.storage, &env, &signer, &input)?;
 
     // Initialization
     init_receive_map(deps.storage)?;
@@ -236,16 +286,21 @@ pub fn handle_execute_input(
     STATE.save(deps.storage, &state)?;
 
     // By calling view_change(), Request messages will be delivered to all chains that we established a channel with
-    view_change(deps.storage, timeout.clone(), &env, deps.api)
+    let mut ctx = Context::load(deps.storage, deps.api, &env)?;
+    let response = view_change(&mut ctx)?;
+    ctx.commit()?;
+    Ok(response)
 
 }
 
 pub fn handle_execute_preinput(
     deps: DepsMut,
     env: Env,
-    _info: MessageInfo,
+    info: MessageInfo,
     input: InputType,
 ) -> Result<Response, ContractError> {
+    assert_admin(deps.storage, &info.sender)?;
+    check_value_size(deps.storage, &input)?;
     // Initialization
     init_receive_map(deps.storage)?;
 
@@ -260,7 +315,8 @@ pub fn handle_execute_preinput(
         .add_attribute("msg_type", "pre_input"))
 }
 
-pub fn handle_execute_abort(deps: DepsMut, env: Env) -> Result<Response, ContractError> {
+// handle_execute_abort aborts the current view once it timed out, or right away when forced
+pub fn handle_execute_abort(deps: DepsMut, env: Env, force: bool) -> Result<Response, ContractError> {
     let state = STATE.load(deps.storage)?;
 
 
@@ -272,26 +328,24 @@ pub fn handle_execute_abort(deps: DepsMut, env: Env) -> Result<Response, Contrac
     };
 
     let end_time = state.start_time.plus_seconds(VIEW_TIMEOUT_SECONDS);
-    match env.block.time.cmp(&end_time) {
-        Ordering::Greater => {
+    match (force, env.block.time.cmp(&end_time)) {
+        (true, _) | (false, Ordering::Greater) => {
 
             // Execute abort via queue...
             let abort_packet = Msg::Abort {
                 view: state.view,
-                chain_id: state.chain_id,
+                chain_id: state.chain_id.clone(),
             };
-            let mut queue: Vec<Vec<Msg>> =
-                vec![vec![abort_packet.clone()]; state.n.try_into().unwrap()];
-
+            let mut ctx = Context::load(deps.storage, deps.api, &env)?;
+            ctx.metrics.aborts += 1;
+            ctx.outbox.broadcast(ctx.channels.keys(), &abort_packet);
             let response = receive_queue(
-                deps.storage,
-                get_timeout(&env),
+                &mut ctx,
                 Some("ABORT_UNUSED_CHANNEL".to_string()),
                 vec![abort_packet.clone()],
-                &mut queue,
-                &env,
-                deps.api
+                false
             )?;
+            ctx.commit()?;
             
             // get response from receive_queue and forward it to the call stack
             let sub_msgs = response.messages;
@@ -299,6 +353,7 @@ pub fn handle_execute_abort(deps: DepsMut, env: Env) -> Result<Response, Contrac
             Ok(Response::new()
                 .add_attribute("action", "execute")
                 .add_submessages(sub_msgs)
+                .add_events(response.events)
                 .add_attribute("msg_type", "abort"))
         }
         _ => {
@@ -311,16 +366,94 @@ pub fn handle_execute_abort(deps: DepsMut, env: Env) -> Result<Response, Contrac
     }
 }
 
+pub fn handle_execute_set_log_config(
+    deps: DepsMut,
+    info: MessageInfo,
+    config: LogConfig,
+) -> Result<Response, ContractError> {
+    assert_admin(deps.storage, &info.sender)?;
+    set_log_config(deps.storage, &config)?;
+    Ok(Response::new()
+        .add_attribute("action", "execute")
+        .add_attribute("msg_type", "set_log_config"))
+}
+
+// handle_execute_set_params changes the parameters of the instantiate message, unset ones are kept
+pub fn handle_execute_set_params(
+    deps: DepsMut,
+    info: MessageInfo,
+    processing_budget: Option<u32>,
+    value_chunk_size: Option<u32>,
+) -> Result<Response, ContractError> {
+    assert_admin(deps.storage, &info.sender)?;
+    if let Some(budget) = processing_budget {
+        save_processing_budget(deps.storage, budget)?;
+    }
+    if let Some(chunk_size) = value_chunk_size {
+        save_value_chunk_size(deps.storage, chunk_size)?;
+    }
+    Ok(Response::new()
+        .add_attribute("action", "execute")
+        .add_attribute("msg_type", "set_params"))
+}
+
+// a budget of 0 would leave every message in the backlog
+fn save_processing_budget(store: &mut dyn Storage, processing_budget: u32) -> Result<(), ContractError> {
+    if processing_budget == 0 {
+        return Err(ContractError::CustomError { val: "processing_budget must be positive".to_string() });
+    }
+    PROCESSING_BUDGET.save(store, &processing_budget)?;
+    Ok(())
+}
+
+fn save_value_chunk_size(store: &mut dyn Storage, value_chunk_size: u32) -> Result<(), ContractError> {
+    if value_chunk_size == 0 {
+        return Err(ContractError::CustomError { val: "value_chunk_size must be positive".to_string() });
+    }
+    VALUE_CHUNK_SIZE.save(store, &value_chunk_size)?;
+    Ok(())
+}
+
+pub fn handle_execute_process_backlog(deps: DepsMut, env: Env) -> Result<Response, ContractError> {
+    let mut ctx = Context::load(deps.storage, deps.api, &env)?;
+    let (wasm_exec_messages, dropped) = run_backlog(&mut ctx)?;
+    let msgs = convert_queue_to_ibc_msgs(&mut ctx)?;
+    ctx.commit()?;
+    let mut response = Response::new()
+        .add_messages(msgs)
+        .add_submessages(wasm_exec_messages)
+        .add_events(std::mem::take(&mut ctx.events))
+        .add_attribute("action", "execute")
+        .add_attribute("msg_type", "process_backlog");
+    for failure in dropped {
+        response = response
+            .add_attribute("error", failure.error.to_string())
+            .add_attribute("dropped", failure.msg_type());
+    }
+    Ok(response.add_attribute("backlog", ctx.inbox.len().to_string()))
+}
+
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
     match msg {
         QueryMsg::GetState {} => to_binary(&query_state(deps)?),
         QueryMsg::GetStateProgress {} => to_binary(&query_state_progress(deps)?),
         QueryMsg::GetChannels {} => to_binary(&query_channels(deps)?),
+        QueryMsg::GetHandshakes {} => to_binary(&query_handshakes(deps)?),
+        #[cfg(feature = "testing")]
         QueryMsg::GetTest {} => to_binary(&query_test(deps)?),
         QueryMsg::GetHighestReq {} => to_binary(&query_highest_request(deps)?),
         QueryMsg::GetReceivedSuggest {} => to_binary(&query_received_suggest(deps)?),
         QueryMsg::GetSendAllUpon {} => to_binary(&query_send_all_upon(deps)?),
+        QueryMsg::GetBacklog {} => to_binary(&query_backlog(deps)?),
+        QueryMsg::GetPeerHealth {} => to_binary(&query_peer_health(deps)?),
+        QueryMsg::Admin {} => to_binary(&query_admin(deps.storage)?),
+        QueryMsg::GetSubmissionPolicy {} => to_binary(&query_submission_policy(deps.storage)?),
+        QueryMsg::GetNonce { address } => to_binary(&query_nonce(deps.storage, address)?),
+        QueryMsg::Diagnose {} => to_binary(&diagnose(deps.storage, &env)?),
+        QueryMsg::GetMetrics {} => to_binary(&query_metrics(deps.storage)?),
+        QueryMsg::GetTimeline { instance } => to_binary(&query_timeline(deps.storage, instance)?),
+        #[cfg(feature = "testing")]
         QueryMsg::GetTestQueue {} => to_binary(&query_test_queue(deps)?),
         QueryMsg::GetEcho {} => to_binary(&query_echo(deps)?),
         QueryMsg::GetKey1 {} => to_binary(&query_key1(deps)?),
@@ -329,50 +462,49 @@ pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
         QueryMsg::GetLock {} => to_binary(&query_lock(deps)?),
         QueryMsg::GetDone {} => to_binary(&query_done(deps)?),
         QueryMsg::GetAbortInfo {} => to_binary(&query_abort_info(deps, env)?),
-        QueryMsg::GetDebug {} => to_binary(&query_debug(deps)?),
         QueryMsg::GetHighestAbort {} => to_binary(&query_highest_abort(deps)?),
-        QueryMsg::GetIbcDebug {} => to_binary(&query_ibc_debug(deps)?),
-        QueryMsg::GetDebugReceive{} => to_binary(&query_debug_receive(deps)?),
+        QueryMsg::GetLogs { level, since, limit } => to_binary(&query_logs(deps.storage, level, since, limit)?),
         QueryMsg::CheckSignature { val } => to_binary(&check_signature(deps, val)?),
         QueryMsg::GetAddress { val }  => to_binary(&get_address(deps, val)?),
+        QueryMsg::DeriveAddresses { public_key, scheme } => to_binary(&query_addresses(deps.storage, scheme, &public_key)?),
+        QueryMsg::DecodePacket { data } => to_binary(&decode_packet(&data)?),
      }
 }
 
 fn query_echo(deps: Deps) -> StdResult<EchoQueryResponse> {
-    let query: StdResult<Vec<_>> = RECEIVED_ECHO
-        .range(deps.storage, None, None, Order::Ascending)
-        .collect();
-    Ok(EchoQueryResponse { echo: query? })
+    Ok(EchoQueryResponse { echo: query_votes(deps, RECEIVED_ECHO)? })
 }
 fn query_key1(deps: Deps) -> StdResult<Key1QueryResponse> {
-    let query: StdResult<Vec<_>> = RECEIVED_KEY1
-        .range(deps.storage, None, None, Order::Ascending)
-        .collect();
-    Ok(Key1QueryResponse { key1: query? })
+    Ok(Key1QueryResponse { key1: query_votes(deps, RECEIVED_KEY1)? })
 }
 fn query_key2(deps: Deps) -> StdResult<Key2QueryResponse> {
-    let query: StdResult<Vec<_>> = RECEIVED_KEY2
-        .range(deps.storage, None, None, Order::Ascending)
-        .collect();
-    Ok(Key2QueryResponse { key2: query? })
+    Ok(Key2QueryResponse { key2: query_votes(deps, RECEIVED_KEY2)? })
 }
 fn query_key3(deps: Deps) -> StdResult<Key3QueryResponse> {
-    let query: StdResult<Vec<_>> = RECEIVED_KEY3
-        .range(deps.storage, None, None, Order::Ascending)
-        .collect();
-    Ok(Key3QueryResponse { key3: query? })
+    Ok(Key3QueryResponse { key3: query_votes(deps, RECEIVED_KEY3)? })
 }
 fn query_lock(deps: Deps) -> StdResult<LockQueryResponse> {
-    let query: StdResult<Vec<_>> = RECEIVED_LOCK
-        .range(deps.storage, None, None, Order::Ascending)
-        .collect();
-    Ok(LockQueryResponse { lock: query? })
+    Ok(LockQueryResponse { lock: query_votes(deps, RECEIVED_LOCK)? })
 }
 fn query_done(deps: Deps) -> StdResult<DoneQueryResponse> {
-    let query: StdResult<Vec<_>> = RECEIVED_DONE
+    Ok(DoneQueryResponse { done: query_votes(deps, RECEIVED_DONE)? })
+}
+
+// query_votes lists the voters of each value, along with the value from the registry
+fn query_votes(deps: Deps, votes: Map<u64, HashSet<PeerId>>) -> StdResult<Vec<VoteEntry>> {
+    let prefix = local_bech32_prefix(deps.storage)?;
+    votes
         .range(deps.storage, None, None, Order::Ascending)
-        .collect();
-    Ok(DoneQueryResponse { done: query? })
+        .map(|entry| {
+            let (hash, voters) = entry?;
+            let value = VOTE_VALUES.may_load(deps.storage, hash)?.map(|val| VoteValue {
+                digest: val.digest(),
+                signer: signer(deps.api, &prefix, &val).ok().map(|signer| signer.address),
+                binary: val.binary,
+            });
+            Ok(VoteEntry { hash, value, voters })
+        })
+        .collect()
 }
 
 fn query_state(deps: Deps) -> StdResult<StateResponse> {
@@ -416,6 +548,7 @@ fn query_state_progress(deps: Deps) -> StdResult<StateResponse> {
     return Ok(StateResponse::InProgress { state });
 }
 
+#[cfg(feature = "testing")]
 fn query_test_queue(deps: Deps) -> StdResult<TestQueueResponse> {
     let req: StdResult<Vec<_>> = TEST_QUEUE
         .range(deps.storage, None, None, Order::Ascending)
@@ -423,6 +556,20 @@ fn query_test_queue(deps: Deps) -> StdResult<TestQueueResponse> {
     Ok(TestQueueResponse { test_queue: req? })
 }
 
+fn query_backlog(deps: Deps) -> StdResult<BacklogResponse> {
+    let backlog = BACKLOG.may_load(deps.storage)?.unwrap_or_default();
+    Ok(BacklogResponse {
+        depth: backlog.len() as u32,
+        budget: PROCESSING_BUDGET.may_load(deps.storage)?.unwrap_or(DEFAULT_PROCESSING_BUDGET),
+    })
+}
+
+fn query_peer_health(deps: Deps) -> StdResult<PeerHealthResponse> {
+    Ok(PeerHealthResponse {
+        peers: peer_health(deps.storage)?,
+    })
+}
+
 fn query_send_all_upon(deps: Deps) -> StdResult<SendAllUponResponse> {
     let req: StdResult<Vec<_>> = SEND_ALL_UPON
         .range(deps.storage, None, None, Order::Ascending)
@@ -460,6 +607,7 @@ fn query_highest_abort(deps: Deps) -> StdResult<HighestAbortResponse> {
     })
 }
 
+#[cfg(feature = "testing")]
 fn query_test(deps: Deps) -> StdResult<Vec<(u32, Vec<IbcMsg>)>> {
     let test: StdResult<Vec<_>> = TEST
         .range(deps.storage, None, None, Order::Ascending)
@@ -478,6 +626,15 @@ fn query_channels(deps: Deps) -> StdResult<ChannelsResponse> {
     })
 }
 
+fn query_handshakes(deps: Deps) -> StdResult<HandshakesResponse> {
+    let handshakes: StdResult<Vec<_>> = HANDSHAKES
+        .range(deps.storage, None, None, Order::Ascending)
+        .collect();
+    Ok(HandshakesResponse {
+        handshakes: handshakes?,
+    })
+}
+
 fn query_abort_info(deps: Deps, env: Env) -> StdResult<AbortResponse> {
     let state = STATE.load(deps.storage)?;
     // let channels = channels?;
@@ -503,39 +660,14 @@ fn query_abort_info(deps: Deps, env: Env) -> StdResult<AbortResponse> {
     })
 }
 
-fn query_debug(deps: Deps) -> StdResult<Vec<(u32, String)>> {
-    let test: StdResult<Vec<_>> = DEBUG
-        .range(deps.storage, None, None, Order::Ascending)
-        .collect();
-    Ok(test?)
-}
-
-fn query_ibc_debug(deps: Deps) -> StdResult<Vec<(u32, String)>> {
-    let test: StdResult<Vec<_>> = IBC_MSG_SEND_DEBUG
-        .range(deps.storage, None, None, Order::Ascending)
-        .collect();
-    Ok(test?)
-}
-
-fn query_debug_receive(deps: Deps) -> StdResult<Vec<(String, Vec<String>)>> {
-    let test: StdResult<Vec<_>> = DEBUG_RECEIVE_MSG
-        .range(deps.storage, None, None, Order::Ascending)
-        .collect();
-    Ok(test?)
-}
 
 
 // https://github.com/CosmWasm/cosmwasm/blob/main/contracts/crypto-verify/src/contract.rs#L90-L107
 fn check_signature(deps: Deps, val: InputType) -> StdResult<Vec<bool>> {
     let mut result: Vec<bool> = Vec::new();
 
-    // Hashing
-    let hash = Sha256::digest(val.binary);
-
-    // Verification
-    let verify_result = deps
-        .api
-        .secp256k1_verify(hash.as_ref(), &val.signature, &val.public_key)?;
+    // Verification, as the scheme of val says
+    let verify_result = verify_signature(deps.api, &local_bech32_prefix(deps.storage)?, &val).is_ok();
 
     result.push(verify_result);
     Ok(result)
@@ -543,10 +675,23 @@ fn check_signature(deps: Deps, val: InputType) -> StdResult<Vec<bool>> {
 
 // https://github.com/CosmWasm/cosmwasm/blob/main/contracts/crypto-verify/src/contract.rs#L90-L107
 fn get_address(deps: Deps, val: InputType) -> StdResult<Addr> {
-   let result = derive_addr_from_pubkey(&val.public_key);
-    Ok(result.unwrap())
+    let result = signer(deps.api, &local_bech32_prefix(deps.storage)?, &val).map_err(|err| StdError::generic_err(err.to_string()))?;
+    Ok(Addr::unchecked(result.address))
 }
 
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn migrate(deps: DepsMut, env: Env, _msg: MigrateMsg) -> Result<Response, ContractError> {
+    // the account migrating the contract becomes its admin when upgrading from before version 4
+    let contract_info: ContractInfoResponse = deps.querier.query(&WasmQuery::ContractInfo {
+        contract_addr: env.contract.address.to_string(),
+    }.into())?;
+    let from_version = migrate::migrate(deps.storage, contract_info.admin.map(Addr::unchecked))?;
+    set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
+    Ok(Response::new()
+        .add_attribute("method", "migrate")
+        .add_attribute("from_storage_version", from_version.to_string())
+        .add_attribute("storage_version", CURRENT_STORAGE_VERSION.to_string()))
+}
 
 // entry_point for sub-messages
 #[cfg_attr(not(feature = "library"), entry_point)]
@@ -555,36 +700,358 @@ pub fn reply(deps: DepsMut, env: Env, msg: Reply) -> StdResult<Response> {
         // REQUEST_REPLY_ID => handle_request_reply(deps, get_timeout(env), msg),
         REQUEST_REPLY_ID => Ok(Response::new()),
         SUGGEST_REPLY_ID => Ok(Response::new()),
-        1234 => handle_wasm_exec(deps, msg),
+        1234 => handle_wasm_exec(deps, env, msg),
         id => Err(StdError::generic_err(format!("Unknown reply id: {}", id))),
     }
 }
 
-fn handle_wasm_exec(deps: DepsMut, msg: Reply) -> StdResult<Response> {
+fn handle_wasm_exec(deps: DepsMut, env: Env, msg: Reply) -> StdResult<Response> {
     // Upon sucessfully delivered the Suggest Message
     // Load the state
     // let _state = STATE.load(deps.storage)?;
     
     
-    let res: Response = Response::new();
-
-    let subMsgResult = msg.result;
-    if subMsgResult.is_err() {
-        let str = subMsgResult.unwrap_err();
-        DEBUG.save(deps.storage, 12341234, &str.clone())?;
-        Err(StdError::generic_err(&str.clone()))
-    } else {
-        DEBUG.save(deps.storage, 12341111, &"OK".to_string())?;
-        Ok(res)
+    let state = STATE.load(deps.storage)?;
+    // a failed execution is reported rather than returned, an error would revert the log entry and the event
+    let mut event = match msg.result {
+        SubMsgResult::Err(err) => {
+            log_at(deps.storage, &env, LogLevel::Error, LogCategory::Execution, format!("execution failed: {}", err))?;
+            protocol_event(EVENT_EXECUTION, state.instance, state.view)
+                .add_attribute("success", "false")
+                .add_attribute("error", err)
+        }
+        SubMsgResult::Ok(_) => {
+            log_at(deps.storage, &env, LogLevel::Info, LogCategory::Execution, "execution succeeded")?;
+            record_milestone_at(deps.storage, &env, state.instance, state.view, Milestone::Executed)?;
+            protocol_event(EVENT_EXECUTION, state.instance, state.view).add_attribute("success", "true")
+        }
+    };
+    if let Some(val) = &state.done {
+        event = event.add_attribute("digest", val.digest());
     }
+    Ok(Response::new().add_event(event))
     // Add consecutive submessages
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use cosmwasm_std::testing::{
-        mock_dependencies, mock_env, mock_info, MockApi, MockQuerier, MockStorage,
+    use cosmwasm_std::testing::{mock_dependencies, mock_env, mock_info};
+    use cosmwasm_std::{from_binary, ContractResult, SubMsgResponse};
+    #[cfg(not(feature = "testing"))]
+    use cosmwasm_std::{from_slice, to_vec};
+
+    use crate::ibc_msg::WireMsg;
+    use crate::ibc_msg::ValueRef;
+    use crate::msg::{AddressesResponse, EchoQueryResponse, VoteEntry};
+    use crate::signature::key_address;
+    use crate::state::{Member, SignatureScheme};
+    use crate::test_utils::{
+        ack, deliver, failing_msg, instantiate_msg, instantiate_with, setup, setup_with, signed_input, signing_key, submit, TestDeps, ADMIN,
     };
-    use cosmwasm_std::{coins, from_binary, OwnedDeps};
+    use crate::utils::{MAX_BACKLOG, MAX_BECH32_PREFIX_LEN};
+
+    fn set_contract_addr(addr: &str) -> ExecuteMsg {
+        ExecuteMsg::SetContractAddr { addr: addr.to_string() }
+    }
+
+    #[test]
+    fn set_contract_addr_is_admin_only() {
+        let mut deps = setup(0);
+        let err = execute(deps.as_mut(), mock_env(), mock_info("user", &[]), set_contract_addr("other")).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}), "{:?}", err);
+
+        let res = execute(deps.as_mut(), mock_env(), mock_info(ADMIN, &[]), set_contract_addr("other")).unwrap();
+        assert!(res.attributes.iter().any(|attr| attr.key == "contract_addr" && attr.value == "other"));
+        assert_eq!(STATE.load(&deps.storage).unwrap().contract_addr, Addr::unchecked("other"));
+    }
+
+    #[test]
+    fn set_contract_addr_validates_the_address() {
+        let mut deps = setup(0);
+        // MockApi turns down addresses shorter than 3 characters
+        let err = execute(deps.as_mut(), mock_env(), mock_info(ADMIN, &[]), set_contract_addr("ab")).unwrap_err();
+        assert!(matches!(err, ContractError::Std(_)), "{:?}", err);
+        assert_eq!(STATE.load(&deps.storage).unwrap().contract_addr, Addr::unchecked("nameservice"));
+    }
+
+    #[test]
+    fn admin_transfer_takes_two_steps() {
+        let mut deps = setup(0);
+        let transfer = ExecuteMsg::TransferAdmin { new_admin: "successor".to_string() };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("successor", &[]), transfer.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}), "{:?}", err);
+        execute(deps.as_mut(), mock_env(), mock_info(ADMIN, &[]), transfer).unwrap();
+
+        // the role stays with the admin until the successor accepts, and only the successor may
+        let admin = query_admin(&deps.storage).unwrap();
+        assert_eq!(admin.admin, Addr::unchecked(ADMIN));
+        assert_eq!(admin.pending_admin, Some(Addr::unchecked("successor")));
+        let err = execute(deps.as_mut(), mock_env(), mock_info("user", &[]), ExecuteMsg::AcceptAdmin {}).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}), "{:?}", err);
+
+        execute(deps.as_mut(), mock_env(), mock_info("successor", &[]), ExecuteMsg::AcceptAdmin {}).unwrap();
+        let admin = query_admin(&deps.storage).unwrap();
+        assert_eq!(admin.admin, Addr::unchecked("successor"));
+        assert_eq!(admin.pending_admin, None);
+        let err = execute(deps.as_mut(), mock_env(), mock_info(ADMIN, &[]), set_contract_addr("other")).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}), "{:?}", err);
+    }
+
+    fn request(view: u32) -> WireMsg {
+        Msg::Request { view, chain_id: "1".to_string() }
+    }
+
+    fn highest_req_of_1(deps: &TestDeps) -> u32 {
+        HIGHEST_REQ.load(&deps.storage, "1".to_string()).unwrap()
+    }
+
+    fn backlog(deps: &TestDeps) -> Vec<&'static str> {
+        BACKLOG.may_load(&deps.storage).unwrap().unwrap_or_default().iter().map(|(_, msg)| msg.name()).collect()
+    }
+
+    fn process_backlog(deps: &mut TestDeps) -> Response {
+        execute(deps.as_mut(), mock_env(), mock_info("anyone", &[]), ExecuteMsg::ProcessBacklog {}).unwrap()
+    }
+
+    fn attribute<'r>(res: &'r Response, key: &str) -> Option<&'r str> {
+        res.attributes.iter().find(|attr| attr.key == key).map(|attr| attr.value.as_str())
+    }
+
+    fn budget_of_two() -> TestDeps {
+        setup_with(InstantiateMsg { processing_budget: Some(2), ..instantiate_msg(2) })
+    }
+
+    #[test]
+    fn messages_over_budget_are_kept_as_backlog() {
+        let mut deps = budget_of_two();
+        let res = deliver(&mut deps, 1, (1..5).map(request).collect());
+        assert!(matches!(ack(&res), ContractResult::Ok(_)));
+        assert_eq!(highest_req_of_1(&deps), 2);
+        assert_eq!(backlog(&deps), vec!["Request", "Request"]);
+
+        let res = process_backlog(&mut deps);
+        assert_eq!(attribute(&res, "backlog"), Some("0"));
+        assert_eq!(attribute(&res, "dropped"), None);
+        assert_eq!(highest_req_of_1(&deps), 4);
+        assert!(BACKLOG.may_load(&deps.storage).unwrap().is_none());
+    }
+
+    fn poisoned_backlog() -> TestDeps {
+        let mut deps = budget_of_two();
+        let poison = failing_msg(&mut deps);
+        deliver(&mut deps, 1, vec![request(1), request(2), request(3), poison, request(4)]);
+        assert_eq!(backlog(&deps), vec!["Request", "FetchValue", "Request"]);
+        deps
+    }
+
+    #[test]
+    fn process_backlog_drops_the_failing_message_only() {
+        let mut deps = poisoned_backlog();
+        // the request before it is kept, the one after it is left for later
+        let res = process_backlog(&mut deps);
+        assert_eq!(attribute(&res, "dropped"), Some("FetchValue"));
+        assert_eq!(attribute(&res, "backlog"), Some("1"));
+        assert_eq!(highest_req_of_1(&deps), 3);
+        assert_eq!(backlog(&deps), vec!["Request"]);
+
+        let res = process_backlog(&mut deps);
+        assert_eq!(attribute(&res, "dropped"), None);
+        assert_eq!(highest_req_of_1(&deps), 4);
+    }
+
+    #[test]
+    fn packets_dont_fail_on_the_backlog() {
+        let mut deps = poisoned_backlog();
+        // the backlog takes the budget and loses its failing message, the packet joins what is left of it
+        let res = deliver(&mut deps, 1, vec![request(5)]);
+        assert!(matches!(ack(&res), ContractResult::Ok(_)));
+        let dropped = res.attributes.iter().find(|attr| attr.key == "dropped").map(|attr| attr.value.as_str());
+        assert_eq!(dropped, Some("FetchValue"));
+        assert_eq!(highest_req_of_1(&deps), 3);
+        assert_eq!(backlog(&deps), vec!["Request", "Request"]);
+
+        let res = process_backlog(&mut deps);
+        assert_eq!(attribute(&res, "dropped"), None);
+        assert_eq!(highest_req_of_1(&deps), 5);
+    }
+
+    #[test]
+    fn failing_packets_keep_what_the_backlog_did() {
+        let mut deps = budget_of_two();
+        deliver(&mut deps, 1, (1..4).map(request).collect());
+        assert_eq!(backlog(&deps), vec!["Request"]);
+
+        let failing = failing_msg(&mut deps);
+        let res = deliver(&mut deps, 1, vec![failing]);
+        assert!(matches!(ack(&res), ContractResult::Err(_)));
+        assert_eq!(highest_req_of_1(&deps), 3);
+        assert!(BACKLOG.may_load(&deps.storage).unwrap().is_none());
+    }
+
+    #[test]
+    fn packets_beyond_the_backlog_cap_are_refused() {
+        let mut deps = budget_of_two();
+        let res = deliver(&mut deps, 1, (0..MAX_BACKLOG as u32 + 1).map(request).collect());
+        assert!(matches!(ack(&res), ContractResult::Err(_)));
+        assert!(BACKLOG.may_load(&deps.storage).unwrap().is_none());
+
+        let res = deliver(&mut deps, 1, (0..MAX_BACKLOG as u32).map(request).collect());
+        assert!(matches!(ack(&res), ContractResult::Ok(_)));
+        assert_eq!(backlog(&deps).len(), MAX_BACKLOG - 2);
+    }
+
+    #[test]
+    fn processing_budget_must_be_positive() {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg { processing_budget: Some(0), ..instantiate_msg(2) };
+        let err = instantiate(deps.as_mut(), mock_env(), mock_info(ADMIN, &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::CustomError { .. }), "{:?}", err);
+
+        let mut deps = budget_of_two();
+        let msg = ExecuteMsg::SetParams { processing_budget: Some(0), value_chunk_size: None };
+        let err = execute(deps.as_mut(), mock_env(), mock_info(ADMIN, &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::CustomError { .. }), "{:?}", err);
+        assert_eq!(PROCESSING_BUDGET.load(&deps.storage).unwrap(), 2);
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn testing_messages_are_handled_with_the_feature() {
+        let mut deps = setup(0);
+        let trigger = ExecuteMsg::Trigger { behavior: "none".to_string() };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("user", &[]), trigger.clone()).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}), "{:?}", err);
+        let res = execute(deps.as_mut(), mock_env(), mock_info(ADMIN, &[]), trigger).unwrap();
+        assert_eq!(attribute(&res, "trigger_behavior"), Some("unknown"));
+
+        let val = signed_input(1, 0);
+        let local_channel_id = "None".to_string();
+        for msg in [
+            ExecuteMsg::Key3 { val: val.clone(), view: 0, local_channel_id: local_channel_id.clone() },
+            ExecuteMsg::Lock { val: val.clone(), view: 0, local_channel_id: local_channel_id.clone() },
+            ExecuteMsg::Done { val: val.clone(), view: 0, local_channel_id: local_channel_id.clone() },
+        ] {
+            execute(deps.as_mut(), mock_env(), mock_info(ADMIN, &[]), msg).unwrap();
+        }
+    }
+
+    #[cfg(not(feature = "testing"))]
+    #[test]
+    fn testing_messages_do_not_parse_without_the_feature() {
+        let val = String::from_utf8(to_vec(&signed_input(1, 0)).unwrap()).unwrap();
+        for msg in [
+            r#"{"trigger":{"behavior":"abort"}}"#.to_string(),
+            format!(r#"{{"key3":{{"val":{},"view":0,"local_channel_id":"None"}}}}"#, val),
+            format!(r#"{{"lock":{{"val":{},"view":0,"local_channel_id":"None"}}}}"#, val),
+            format!(r#"{{"done":{{"val":{},"view":0,"local_channel_id":"None"}}}}"#, val),
+        ] {
+            let err = from_slice::<ExecuteMsg>(msg.as_bytes()).unwrap_err();
+            assert!(err.to_string().contains("unknown variant"), "{}", err);
+        }
+        from_slice::<ExecuteMsg>(br#"{"process_backlog":{}}"#).unwrap();
+    }
+
+    fn execution(deps: &mut TestDeps, result: SubMsgResult) -> Response {
+        reply(deps.as_mut(), mock_env(), Reply { id: 1234, result }).unwrap()
+    }
+
+    fn execution_attribute<'r>(res: &'r Response, key: &str) -> Option<&'r str> {
+        let event = res.events.iter().find(|event| event.ty == EVENT_EXECUTION).unwrap();
+        event.attributes.iter().find(|attr| attr.key == key).map(|attr| attr.value.as_str())
+    }
+
+    #[test]
+    fn failed_execution_is_reported_and_logged() {
+        let mut deps = setup(0);
+        let res = execution(&mut deps, SubMsgResult::Err("out of gas".to_string()));
+        assert_eq!(execution_attribute(&res, "success"), Some("false"));
+        assert_eq!(execution_attribute(&res, "error"), Some("out of gas"));
+        let errors = query_logs(&deps.storage, Some(LogLevel::Error), None, None).unwrap().entries;
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].message.contains("out of gas"));
+
+        let res = execution(&mut deps, SubMsgResult::Ok(SubMsgResponse { events: vec![], data: None }));
+        assert_eq!(execution_attribute(&res, "success"), Some("true"));
+        assert_eq!(execution_attribute(&res, "error"), None);
+    }
+
+    fn member(chain_id: u32, bech32_prefix: Option<&str>) -> Member {
+        Member {
+            chain_id: chain_id.to_string(),
+            network_id: format!("network-{}", chain_id),
+            bech32_prefix: bech32_prefix.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn derive_addresses_starts_with_this_chain() {
+        // the prefix of this chain is the local one, even when listed among the members
+        let members = vec![member(2, None), member(1, Some("osmo")), member(0, Some("osmo"))];
+        let deps = instantiate_with(InstantiateMsg { bech32_prefix: Some("juno".to_string()), members, ..instantiate_msg(0) });
+        let public_key = signing_key(1).verifying_key().to_bytes().to_vec();
+        let msg = QueryMsg::DeriveAddresses { public_key: public_key.clone(), scheme: SignatureScheme::Secp256k1 };
+        let res: AddressesResponse = from_binary(&query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap();
+
+        let prefixes: Vec<(&str, &str)> =
+            res.addresses.iter().map(|member| (member.chain_id.as_str(), member.bech32_prefix.as_str())).collect();
+        assert_eq!(prefixes, vec![("0", "juno"), ("1", "osmo"), ("2", "juno")]);
+        for member in res.addresses {
+            assert_eq!(member.address, key_address(SignatureScheme::Secp256k1, &public_key, &member.bech32_prefix).unwrap());
+        }
+    }
+
+    #[test]
+    fn invalid_bech32_prefixes_are_refused() {
+        let too_long = "a".repeat(MAX_BECH32_PREFIX_LEN + 1);
+        for prefix in ["", "Osmo", "os-mo", too_long.as_str()] {
+            let msg = InstantiateMsg { bech32_prefix: Some(prefix.to_string()), ..instantiate_msg(0) };
+            instantiate(mock_dependencies().as_mut(), mock_env(), mock_info(ADMIN, &[]), msg).unwrap_err();
+            let msg = InstantiateMsg { members: vec![member(1, Some(prefix))], ..instantiate_msg(0) };
+            instantiate(mock_dependencies().as_mut(), mock_env(), mock_info(ADMIN, &[]), msg).unwrap_err();
+        }
+        // digits are part of some prefixes
+        let msg = InstantiateMsg { bech32_prefix: Some("osmo1".to_string()), members: vec![member(1, Some("cosmos2"))], ..instantiate_msg(0) };
+        instantiate(mock_dependencies().as_mut(), mock_env(), mock_info(ADMIN, &[]), msg).unwrap();
+    }
+
+    fn echoes(deps: &TestDeps) -> Vec<VoteEntry> {
+        let res: EchoQueryResponse = from_binary(&query(deps.as_ref(), mock_env(), QueryMsg::GetEcho {}).unwrap()).unwrap();
+        res.echo
+    }
+
+    // chain 2 with the proposal of the primary of view 0 and the Echo of chain 0
+    fn echoed(val: &InputType) -> TestDeps {
+        let mut deps = setup(2);
+        submit(&mut deps, val.clone());
+        let v = ValueRef::Full(val.clone());
+        deliver(&mut deps, 1, vec![Msg::Propose { chain_id: "1".to_string(), k: 0, v: v.clone(), view: 0 }]);
+        deliver(&mut deps, 0, vec![Msg::Echo { val: v, view: 0 }]);
+        deps
+    }
+
+    #[test]
+    fn votes_are_listed_with_their_value() {
+        let val = signed_input(7, 0);
+        let deps = echoed(&val);
+        let echoes = echoes(&deps);
+        assert_eq!(echoes.len(), 1);
+        assert_eq!(echoes[0].voters, HashSet::from(["0".to_string(), "2".to_string()]));
+        let value = echoes[0].value.as_ref().unwrap();
+        assert_eq!(value.digest, val.digest());
+        assert_eq!(value.binary, val.binary);
+        assert_eq!(value.signer, Some(signer(&deps.api, "wasm", &val).unwrap().address));
+    }
+
+    #[test]
+    fn votes_and_their_values_go_with_the_view() {
+        let mut deps = echoed(&signed_input(7, 0));
+        execute(deps.as_mut(), mock_env(), mock_info(ADMIN, &[]), ExecuteMsg::ForceAbort {}).unwrap();
+        for from in [0, 1] {
+            deliver(&mut deps, from, vec![Msg::Abort { view: 0, chain_id: from.to_string() }]);
+        }
+        assert_eq!(STATE.load(&deps.storage).unwrap().view, 1);
+        assert!(echoes(&deps).is_empty());
+        assert_eq!(VOTE_VALUES.keys(&deps.storage, None, None, Order::Ascending).count(), 0);
+    }
 }
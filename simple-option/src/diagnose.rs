@@ -0,0 +1,191 @@
+// The Diagnose query, answering why an instance doesn't make progress: which quorum it waits for,
+// who has and hasn't contributed to it, what is held back for peers and whether the view timed out.
+
+use std::collections::HashSet;
+
+use cosmwasm_std::{Env, Order, StdResult, Storage};
+
+use crate::context::Vote;
+use crate::contract::VIEW_TIMEOUT_SECONDS;
+use crate::msg::{DiagnoseResponse, HeldMessages, QuorumStatus};
+use crate::state::{PeerId, State, HIGHEST_REQ, RECEIVED, SEND_ALL_UPON, STATE};
+
+// each vote is followed by the message sent once its quorum is reached
+const VOTE_STEPS: [(Vote, &str); 5] = [
+    (Vote::Echo, "Key1"),
+    (Vote::Key1, "Key2"),
+    (Vote::Key2, "Key3"),
+    (Vote::Key3, "Lock"),
+    (Vote::Lock, "Done"),
+];
+
+pub fn diagnose(store: &dyn Storage, env: &Env) -> StdResult<DiagnoseResponse> {
+    let state = STATE.load(store)?;
+    let (phase, next_quorum) = next_quorum(store, &state)?;
+    let deadline = state.start_time.plus_seconds(VIEW_TIMEOUT_SECONDS);
+    Ok(DiagnoseResponse {
+        view: state.view,
+        primary: state.primary.clone(),
+        phase,
+        next_quorum,
+        held: held_messages(store, &state)?,
+        deadline,
+        deadline_passed: env.block.time > deadline,
+    })
+}
+
+// next_quorum returns the phase we are in and the quorum that ends it, None once decided
+fn next_quorum(store: &dyn Storage, state: &State) -> StdResult<(String, Option<QuorumStatus>)> {
+    if state.done.is_some() {
+        return Ok(("Decided".to_string(), None));
+    }
+    let required = state.n - state.F;
+    if state.primary == state.chain_id && !state.sent.contains("Propose") {
+        let contributed = RECEIVED.may_load(store, "Suggest".to_string())?.unwrap_or_default();
+        // only the suggestions passing accept_key count towards the quorum
+        let current = state.suggestions.len() as u32;
+        return Ok(("Suggest".to_string(), Some(quorum(state, "Suggest", current, required, contributed))));
+    }
+    if !state.received_propose {
+        let contributed = HashSet::new();
+        let status = quorum(state, "Propose", 0, 1, contributed);
+        // only the primary proposes
+        let missing = vec![state.primary.clone()];
+        return Ok(("Propose".to_string(), Some(QuorumStatus { missing, ..status })));
+    }
+    let vote = VOTE_STEPS
+        .iter()
+        .find(|(_, next)| !state.sent.contains(*next))
+        .map_or(Vote::Done, |(vote, _)| *vote);
+    // votes are counted per value, the value closest to a quorum is reported
+    let mut contributed = HashSet::new();
+    for entry in vote.map().range(store, None, None, Order::Ascending) {
+        let (_, voters) = entry?;
+        if voters.len() > contributed.len() {
+            contributed = voters;
+        }
+    }
+    let name = format!("{:?}", vote);
+    let current = contributed.len() as u32;
+    Ok((name.clone(), Some(quorum(state, &name, current, required, contributed))))
+}
+
+fn quorum(state: &State, msg_type: &str, current: u32, required: u32, contributed: HashSet<PeerId>) -> QuorumStatus {
+    let missing = state.members.iter().filter(|chain_id| !contributed.contains(*chain_id)).cloned().collect();
+    let mut contributed: Vec<_> = contributed.into_iter().collect();
+    contributed.sort();
+    QuorumStatus {
+        msg_type: msg_type.to_string(),
+        current,
+        required,
+        contributed,
+        missing,
+    }
+}
+
+// held_messages lists what SEND_ALL_UPON keeps for peers that haven't sent Request for the current view
+fn held_messages(store: &dyn Storage, state: &State) -> StdResult<Vec<HeldMessages>> {
+    let mut held = Vec::new();
+    for entry in SEND_ALL_UPON.range(store, None, None, Order::Ascending) {
+        let (chain_id, msgs) = entry?;
+        if msgs.is_empty() {
+            continue;
+        }
+        let highest_req = HIGHEST_REQ.may_load(store, chain_id.clone())?.unwrap_or_default();
+        // released as soon as the peer joins the view
+        if highest_req == state.view {
+            continue;
+        }
+        held.push(HeldMessages {
+            chain_id,
+            highest_req,
+            msg_types: msgs.iter().map(|msg| msg.name().to_string()).collect(),
+        });
+    }
+    Ok(held)
+}
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::testing::mock_env;
+
+    use crate::ibc_msg::{Msg, ValueRef};
+    use crate::test_utils::{deliver, setup, signed_input, submit, TestDeps};
+
+    use super::*;
+
+    // chain_id after submitting an input, in view 0 whose primary is chain 1
+    fn started(chain_id: u32) -> TestDeps {
+        let mut deps = setup(chain_id);
+        submit(&mut deps, signed_input(7, 0));
+        deps
+    }
+
+    fn quorum_of(deps: &TestDeps) -> QuorumStatus {
+        diagnose(&deps.storage, &mock_env()).unwrap().next_quorum.unwrap()
+    }
+
+    #[test]
+    fn primary_waits_for_suggestions() {
+        let deps = started(1);
+        let res = diagnose(&deps.storage, &mock_env()).unwrap();
+        assert_eq!((res.view, res.primary.as_str(), res.phase.as_str()), (0, "1", "Suggest"));
+        let quorum = res.next_quorum.unwrap();
+        assert_eq!(quorum.required, 3);
+        assert_eq!(quorum.current as usize, STATE.load(&deps.storage).unwrap().suggestions.len());
+    }
+
+    #[test]
+    fn replicas_wait_for_the_primary_then_for_votes() {
+        let mut deps = started(2);
+        let quorum = quorum_of(&deps);
+        assert_eq!((quorum.msg_type.as_str(), quorum.missing), ("Propose", vec!["1".to_string()]));
+
+        let val = ValueRef::Full(signed_input(7, 0));
+        deliver(&mut deps, 1, vec![Msg::Propose { chain_id: "1".to_string(), k: 0, v: val.clone(), view: 0 }]);
+        deliver(&mut deps, 0, vec![Msg::Echo { val, view: 0 }]);
+        let quorum = quorum_of(&deps);
+        assert_eq!((quorum.msg_type.as_str(), quorum.current, quorum.required), ("Echo", 2, 3));
+        assert_eq!(quorum.contributed, vec!["0", "2"]);
+        assert_eq!(quorum.missing, vec!["1", "3"]);
+    }
+
+    #[test]
+    fn decided_instances_wait_for_nothing() {
+        let mut deps = started(2);
+        let mut state = STATE.load(&deps.storage).unwrap();
+        state.done = Some(signed_input(7, 0));
+        STATE.save(&mut deps.storage, &state).unwrap();
+        let res = diagnose(&deps.storage, &mock_env()).unwrap();
+        assert_eq!(res.phase, "Decided");
+        assert_eq!(res.next_quorum, None);
+    }
+
+    #[test]
+    fn messages_held_for_peers_behind_are_listed() {
+        let mut deps = started(2);
+        let mut state = STATE.load(&deps.storage).unwrap();
+        state.view = 1;
+        STATE.save(&mut deps.storage, &state).unwrap();
+        HIGHEST_REQ.save(&mut deps.storage, "0".to_string(), &1).unwrap();
+        for chain_id in ["0", "3"] {
+            SEND_ALL_UPON.save(&mut deps.storage, chain_id.to_string(), &vec![Msg::Abort { view: 1, chain_id: "2".to_string() }]).unwrap();
+        }
+
+        // chain 0 joined view 1, its messages are on their way
+        let held = diagnose(&deps.storage, &mock_env()).unwrap().held;
+        assert_eq!(held, vec![HeldMessages { chain_id: "3".to_string(), highest_req: 0, msg_types: vec!["Abort".to_string()] }]);
+    }
+
+    #[test]
+    fn deadline_passes_with_the_view_timeout() {
+        let deps = started(2);
+        let res = diagnose(&deps.storage, &mock_env()).unwrap();
+        assert_eq!(res.deadline, mock_env().block.time.plus_seconds(VIEW_TIMEOUT_SECONDS));
+        assert!(!res.deadline_passed);
+
+        let mut env = mock_env();
+        env.block.time = res.deadline.plus_seconds(1);
+        assert!(diagnose(&deps.storage, &env).unwrap().deadline_passed);
+    }
+}
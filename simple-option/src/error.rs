@@ -11,6 +11,33 @@ pub enum ContractError {
 
     #[error("Custom Error val: {val:?}")]
     CustomError { val: String },
+
+    #[error("Signer {signer} may not submit inputs")]
+    SignerNotAllowed { signer: String },
+
+    #[error("Signer {signer} submitted {max_inputs} inputs in this window, retry at height {retry_at}")]
+    RateLimited { signer: String, max_inputs: u32, retry_at: u64 },
+
+    #[error("Input payload of {size} bytes exceeds the maximum of {max}")]
+    PayloadTooLarge { size: u64, max: u32 },
+
+    #[error("Malformed input: {reason}")]
+    MalformedInput { reason: String },
+
+    #[error("Invalid signature")]
+    InvalidSignature {},
+
+    #[error("Signer address {address} is not valid on this chain, check bech32_prefix")]
+    InvalidAddress { address: String },
+
+    #[error("{signed} keys signed, the multisig needs {threshold}")]
+    ThresholdNotMet { signed: u32, threshold: u32 },
+
+    #[error("Input is not for this deployment: {reason}")]
+    WrongDomain { reason: String },
+
+    #[error("Nonce {nonce} of {signer} was already used, the next one is {next}")]
+    NonceReused { signer: String, nonce: u64, next: u64 },
     // Add any other custom errors you like here.
     // Look at https://docs.rs/thiserror/1.0.21/thiserror/ for details.
 }
@@ -0,0 +1,32 @@
+// Typed wasm events for the protocol transitions, so that an indexer can rebuild a run from tx events alone.
+// Every event carries the instance and the view it happened in, value digest and sender are added where
+// they apply. Handlers collect them in Context::events, the entry points add them to their response.
+
+use cosmwasm_std::Event;
+
+use crate::context::Context;
+
+/// A milestone of the view was reached, see timeline.rs
+pub const EVENT_PHASE: &str = "trustboost_phase";
+/// n - f chains agreed on a message type and value
+pub const EVENT_QUORUM: &str = "trustboost_quorum";
+/// We entered a view, the first view of an instance included
+pub const EVENT_VIEW_CHANGE: &str = "trustboost_view_change";
+/// A value was decided
+pub const EVENT_DECISION: &str = "trustboost_decision";
+/// The decided value was executed on the local contract
+pub const EVENT_EXECUTION: &str = "trustboost_execution";
+/// A chain misbehaved
+pub const EVENT_FAULT: &str = "trustboost_fault";
+
+pub fn protocol_event(kind: &str, instance: u32, view: u32) -> Event {
+    Event::new(kind)
+        .add_attribute("instance", instance.to_string())
+        .add_attribute("view", view.to_string())
+}
+
+// emit records an event of kind, for the current instance and view of ctx
+pub fn emit(ctx: &mut Context, kind: &str, attributes: Vec<(&str, String)>) {
+    let event = protocol_event(kind, ctx.state.instance, ctx.state.view).add_attributes(attributes);
+    ctx.events.push(event);
+}
@@ -0,0 +1,155 @@
+// Per-peer liveness. Packets, acknowledgements and timeouts are recorded against the chain bound to
+// their channel, so that a silent peer shows up in the GetPeerHealth query.
+
+use cosmwasm_std::{Env, Order, StdResult, Storage};
+
+use crate::msg::PeerHealthEntry;
+use crate::state::{
+    AckRecord, BlockRecord, PacketRecord, PeerHealth, PeerId, CHANNELS, PEER_HEALTH, SEND_ALL_UPON, STATE,
+};
+
+// peer_of finds the chain bound to channel_id, None before the handshake completed
+fn peer_of(store: &dyn Storage, channel_id: &str) -> StdResult<Option<PeerId>> {
+    for entry in CHANNELS.range(store, None, None, Order::Ascending) {
+        let (chain_id, channel) = entry?;
+        if channel == channel_id {
+            return Ok(Some(chain_id));
+        }
+    }
+    Ok(None)
+}
+
+fn block_record(env: &Env) -> BlockRecord {
+    BlockRecord {
+        height: env.block.height,
+        time: env.block.time,
+    }
+}
+
+// update applies f to the health of the peer on channel_id, channels without a peer are ignored
+fn update(store: &mut dyn Storage, channel_id: &str, f: impl FnOnce(&mut PeerHealth)) -> StdResult<()> {
+    let chain_id = match peer_of(store, channel_id)? {
+        Some(chain_id) => chain_id,
+        None => return Ok(()),
+    };
+    let mut health = PEER_HEALTH.may_load(store, chain_id.clone())?.unwrap_or_default();
+    f(&mut health);
+    PEER_HEALTH.save(store, chain_id, &health)
+}
+
+pub fn record_packet(store: &mut dyn Storage, env: &Env, channel_id: &str, view: u32, msg_types: Vec<String>) -> StdResult<()> {
+    let record = PacketRecord {
+        block: block_record(env),
+        view,
+        msg_types,
+    };
+    update(store, channel_id, |health| health.last_received = Some(record))
+}
+
+pub fn record_ack(store: &mut dyn Storage, env: &Env, channel_id: &str, success: bool) -> StdResult<()> {
+    let record = AckRecord {
+        block: block_record(env),
+        success,
+    };
+    update(store, channel_id, |health| health.last_ack = Some(record))
+}
+
+pub fn record_timeout(store: &mut dyn Storage, env: &Env, channel_id: &str) -> StdResult<()> {
+    let record = block_record(env);
+    update(store, channel_id, |health| health.last_timeout = Some(record))
+}
+
+// peer_health reports every member but ourselves, including those we never heard from
+pub fn peer_health(store: &dyn Storage) -> StdResult<Vec<PeerHealthEntry>> {
+    let state = STATE.load(store)?;
+    let mut peers = Vec::new();
+    for chain_id in state.members.iter().filter(|chain_id| **chain_id != state.chain_id) {
+        let health = PEER_HEALTH.may_load(store, chain_id.clone())?.unwrap_or_default();
+        peers.push(PeerHealthEntry {
+            chain_id: chain_id.clone(),
+            channel_id: CHANNELS.may_load(store, chain_id.clone())?,
+            last_received: health.last_received,
+            last_ack: health.last_ack,
+            last_timeout: health.last_timeout,
+            send_all_upon: SEND_ALL_UPON.may_load(store, chain_id.clone())?.map_or(0, |msgs| msgs.len() as u32),
+        });
+    }
+    Ok(peers)
+}
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::testing::{mock_env, mock_ibc_packet_ack};
+    use cosmwasm_std::{to_binary, IbcAcknowledgement};
+
+    use crate::ibc::ibc_packet_ack;
+    use crate::ibc_msg::{AcknowledgementMsg, Msg, MsgQueueResponse, PacketMsg};
+    use crate::msg::InstantiateMsg;
+    use crate::state::Member;
+    use crate::test_utils::{channel, deliver, failing_msg, instantiate_msg, instantiate_with, setup};
+
+    use super::*;
+
+    fn health_of(store: &dyn Storage, chain_id: &str) -> PeerHealthEntry {
+        peer_health(store).unwrap().into_iter().find(|peer| peer.chain_id == chain_id).unwrap()
+    }
+
+    #[test]
+    fn packets_acks_and_timeouts_are_recorded_per_peer() {
+        let mut deps = setup(0);
+        deliver(&mut deps, 1, vec![Msg::Request { view: 1, chain_id: "1".to_string() }]);
+        record_ack(&mut deps.storage, &mock_env(), &channel(1), false).unwrap();
+        record_timeout(&mut deps.storage, &mock_env(), &channel(2)).unwrap();
+
+        let peer = health_of(&deps.storage, "1");
+        assert_eq!(peer.channel_id, Some(channel(1)));
+        let received = peer.last_received.unwrap();
+        assert_eq!(received.msg_types, vec!["Request"]);
+        assert_eq!(received.block.height, mock_env().block.height);
+        assert!(!peer.last_ack.unwrap().success);
+        assert_eq!(peer.last_timeout, None);
+
+        let peer = health_of(&deps.storage, "2");
+        assert_eq!(peer.last_received, None);
+        assert_eq!(peer.last_timeout.unwrap().height, mock_env().block.height);
+    }
+
+    #[test]
+    fn failing_packets_still_show_the_peer_alive() {
+        let mut deps = setup(0);
+        let failing = failing_msg(&mut deps);
+        deliver(&mut deps, 3, vec![failing]);
+        assert_eq!(health_of(&deps.storage, "3").last_received.unwrap().msg_types, vec!["FetchValue"]);
+    }
+
+    #[test]
+    fn replies_on_acknowledgements_are_not_received_packets() {
+        let mut deps = setup(0);
+        let sent = PacketMsg::MsgQueue(vec![Msg::Request { view: 0, chain_id: "0".to_string() }]);
+        let replies = AcknowledgementMsg::Ok(MsgQueueResponse { replies: vec![Msg::Request { view: 1, chain_id: "1".to_string() }] });
+        let ack = mock_ibc_packet_ack(&channel(1), &sent, IbcAcknowledgement::new(to_binary(&replies).unwrap())).unwrap();
+        ibc_packet_ack(deps.as_mut(), mock_env(), ack).unwrap();
+
+        let peer = health_of(&deps.storage, "1");
+        assert!(peer.last_ack.unwrap().success);
+        assert_eq!(peer.last_received, None);
+    }
+
+    #[test]
+    fn every_member_but_us_is_reported() {
+        let mut deps = setup(0);
+        let peers: Vec<PeerId> = peer_health(&deps.storage).unwrap().into_iter().map(|peer| peer.chain_id).collect();
+        assert_eq!(peers, vec!["1", "2", "3"]);
+
+        // channels without a handshake are not bound to any peer
+        record_timeout(&mut deps.storage, &mock_env(), "channel-9").unwrap();
+        assert_eq!(PEER_HEALTH.keys(&deps.storage, None, None, Order::Ascending).count(), 0);
+
+        // members we have no channel to yet are reported all the same
+        let members = vec![Member { chain_id: "1".to_string(), network_id: mock_env().block.chain_id, bech32_prefix: None }];
+        let deps = instantiate_with(InstantiateMsg { members, ..instantiate_msg(0) });
+        let peer = health_of(&deps.storage, "1");
+        assert_eq!(peer.channel_id, None);
+        assert_eq!(peer.last_received, None);
+    }
+}
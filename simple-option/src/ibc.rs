@@ -1,26 +1,37 @@
-use std::convert::TryInto;
 
 use cosmwasm_std::{
-    entry_point, from_slice, to_binary, Binary, DepsMut, Env, Event, StdResult,
+    entry_point, from_slice, to_binary, Binary, ContractResult, DepsMut, Env, Event, Order, StdError, StdResult,
 };
 use cosmwasm_std::{
     IbcBasicResponse, IbcChannelCloseMsg, IbcChannelConnectMsg, IbcChannelOpenMsg, IbcMsg, IbcPacketAckMsg, IbcPacketReceiveMsg, IbcPacketTimeoutMsg, IbcReceiveResponse,
 };
 
 use crate::ibc_msg::{
-    AcknowledgementMsg, PacketMsg, WhoAmIResponse, ProofResponse, EchoResponse, Key1Response, Key2Response, Key3Response, LockResponse, DoneResponse, Msg,
+    AcknowledgementMsg, MsgQueueResponse, PacketMsg, WireMsg, WhoAmIResponse, ProofResponse, EchoResponse, Key1Response, Key2Response, Key3Response, LockResponse, DoneResponse, Msg,
 };
 
 use crate::state::{
-    CHANNELS, STATE, HIGHEST_ABORT, IBC_MSG_SEND_DEBUG, InputType, DEBUG_RECEIVE_MSG
+    CHANNELS, STATE, HIGHEST_ABORT, InputType, LogCategory, LogLevel, MEMBERS, HANDSHAKES, Handshake, CHANNEL_CODEC_VERSION, CHANNEL_ENCODING
 };
-use crate::utils::{get_timeout};
-use crate::queue_handler::{receive_queue};
+use crate::utils::{get_timeout, config_fingerprint, MAX_BACKLOG, PROTOCOL_VERSION};
+use crate::values::{gc_values, resolve_queue};
+use crate::codec::{compact_version, decode_packet, negotiate_version, PacketEncoding};
+use crate::queue_handler::{receive_queue, run_backlog};
+use crate::outbox::into_packets;
+use crate::context::Context;
+use crate::events::{emit, protocol_event, EVENT_FAULT};
+use crate::log::{log, log_at};
+use crate::health::{record_ack, record_packet, record_timeout};
 
 #[entry_point]
 /// enforces ordering and versioing constraints
-pub fn ibc_channel_open(_deps: DepsMut, _env: Env, _msg: IbcChannelOpenMsg) -> StdResult<()> {
+pub fn ibc_channel_open(_deps: DepsMut, _env: Env, msg: IbcChannelOpenMsg) -> StdResult<()> {
     // verify_channel(msg)?;
+    // both ends have to agree on the packet encoding, the version we answer a proposal with may be an older layout
+    match msg.counterparty_version() {
+        Some(proposed) => negotiate_version(proposed, &msg.channel().version)?,
+        None => compact_version(&msg.channel().version)?,
+    };
     Ok(())
 }
 
@@ -35,12 +46,20 @@ pub fn ibc_channel_connect(
     // Retrieve the connecting channel_id
     let channel_id = &channel.endpoint.channel_id;
 
-    // Keep a record of connected channels
+    // Keep a record of connected channels, the chain behind it only counts once its handshake is accepted
     let mut state = STATE.load(deps.storage)?;
     state.channel_ids.push(channel_id.to_string());
-    // increment the total no of chains
-    state.n += 1;
     STATE.save(deps.storage, &state)?;
+    // on the end that proposed the version, the counterparty's answer is the one agreed on
+    let layout = match msg.counterparty_version() {
+        Some(agreed) => negotiate_version(&channel.version, agreed)?,
+        None => compact_version(&channel.version)?,
+    };
+    let encoding = if layout.is_some() { PacketEncoding::Compact } else { PacketEncoding::Json };
+    CHANNEL_ENCODING.save(deps.storage, channel_id.to_string(), &encoding)?;
+    if let Some(layout) = layout {
+        CHANNEL_CODEC_VERSION.save(deps.storage, channel_id.to_string(), &layout)?;
+    }
     // let dst_port =  &channel.counterparty_endpoint.port_id;
 
 
@@ -59,6 +78,10 @@ pub fn ibc_channel_connect(
     // construct a packet to send, using the WhoAmI specification
     let packet = PacketMsg::WhoAmI {
         chain_id: state.chain_id,
+        protocol_version: PROTOCOL_VERSION,
+        fingerprint: config_fingerprint(deps.storage, &env.block.chain_id)?,
+        contract_addr: env.contract.address.to_string(),
+        network_id: env.block.chain_id.clone(),
     };
     let msg = IbcMsg::SendPacket {
         channel_id: channel_id.clone(),
@@ -123,16 +146,28 @@ pub fn ibc_packet_receive(
         let packet = msg.packet;
         // which local channel did this packet come on
         let dest_channel_id = packet.dest.channel_id;
-        let msg: PacketMsg = from_slice(&packet.data)?;
+        let src_port_id = packet.src.port_id;
+        let msg: PacketMsg = decode_packet(&packet.data)?;
         match msg {
-            PacketMsg::MsgQueue(q) => 
-            {
-                let state = STATE.load(deps.storage)?;
-                let mut queue: Vec<Vec<Msg>> = vec!(Vec::new(); state.n.try_into().unwrap());
-                let result = receive_queue(deps.storage, get_timeout(&env), Some(dest_channel_id), q, &mut queue, &env, deps.api);
-                return result;
+            PacketMsg::MsgQueue(q) => {
+                // recorded whatever becomes of the queue, the peer is alive either way
+                let view = STATE.load(deps.storage)?.view;
+                let msg_types = q.iter().map(|msg| msg.name().to_string()).collect();
+                record_packet(deps.storage, &env, &dest_channel_id, view, msg_types)?;
+                receive_msg_queue(deps, &env, dest_channel_id, q, true)
+            },
+            PacketMsg::WhoAmI { chain_id, protocol_version, fingerprint, contract_addr, network_id } => {
+                let handshake = Handshake {
+                    chain_id,
+                    protocol_version,
+                    fingerprint,
+                    contract_addr,
+                    network_id,
+                    accepted: false,
+                    reason: None,
+                };
+                receive_who_am_i(deps, &env, dest_channel_id, &src_port_id, handshake)
             },
-            PacketMsg::WhoAmI { chain_id } => receive_who_am_i(deps, dest_channel_id, chain_id),
         }
     })()
     .or_else(|e| {
@@ -146,20 +181,109 @@ pub fn ibc_packet_receive(
 }
 
 
+// processes a MsgQueue arriving on channel_id, either as a packet or piggybacked on an acknowledgement
+fn receive_msg_queue(
+    deps: DepsMut,
+    env: &Env,
+    channel_id: String,
+    q: Vec<WireMsg>,
+    piggyback: bool,
+) -> StdResult<IbcReceiveResponse> {
+    // only accept protocol messages on channels that completed the handshake
+    match HANDSHAKES.may_load(deps.storage, channel_id.clone())? {
+        Some(handshake) if handshake.accepted => (),
+        _ => return Ok(IbcReceiveResponse::new()
+            .set_ack(encode_ibc_error("channel is not bound to a chain"))
+            .add_attribute("action", "receive_msg_queue")
+            .add_attribute("error", "unbound_channel")),
+    }
+    let count = q.len();
+    let mut ctx = Context::load(deps.storage, deps.api, env)?;
+    // the backlog is handled on its own first, the queue doesn't fail for a message it didn't carry
+    let (backlog_exec_messages, dropped) = run_backlog(&mut ctx)?;
+    let result = (|| {
+        if ctx.inbox.len() + q.len() > MAX_BACKLOG {
+            return Err(StdError::generic_err(format!("backlog is full, {} messages are waiting", ctx.inbox.len())));
+        }
+        // values waited for too long are fetched again, from whoever referenced them
+        for (channel_id, digest) in gc_values(&mut ctx.store, ctx.env.block.time)? {
+            if let Ok(chain_id) = ctx.chain_id_of(&channel_id) {
+                ctx.outbox.enqueue(chain_id, Msg::FetchValue { digest });
+            }
+        }
+        // values referenced by digest that we haven't seen yet are fetched from the sender
+        let (q, to_fetch) = resolve_queue(&mut ctx.store, ctx.env.block.time, &channel_id, q)?;
+        if !to_fetch.is_empty() {
+            let chain_id = ctx.chain_id_of(&channel_id)?;
+            for digest in to_fetch {
+                ctx.outbox.enqueue(chain_id.clone(), Msg::FetchValue { digest });
+            }
+        }
+        receive_queue(&mut ctx, Some(channel_id.clone()), q, piggyback)
+    })();
+    let response = match result {
+        Ok(response) => response,
+        // only the writes of the queue are undone, what the backlog sent still goes out
+        Err(err) => {
+            ctx.rollback();
+            let sender = ctx.chain_id_of(&channel_id).unwrap_or_else(|_| channel_id.clone());
+            let fault = if piggyback { "failing_queue" } else { "failing_replies" };
+            log(&mut ctx, LogLevel::Error, LogCategory::Packet, format!(
+                "dropped {} messages from {}: {}", count, sender, err
+            ))?;
+            emit(&mut ctx, EVENT_FAULT, vec![("fault", fault.to_string()), ("sender", sender), ("error", err.to_string())]);
+            let (msgs, _) = into_packets(&mut ctx, None)?;
+            IbcReceiveResponse::new()
+                .set_ack(encode_ibc_error(format!("invalid packet: {}", err)))
+                .add_messages(msgs)
+                .add_events(std::mem::take(&mut ctx.events))
+                .add_attribute("action", "receive_msg_queue")
+                .add_attribute("error", err.to_string())
+        },
+    };
+    ctx.commit()?;
+    let mut response = response.add_submessages(backlog_exec_messages);
+    for failure in dropped {
+        response = response.add_attribute("dropped", failure.msg_type());
+    }
+    Ok(response)
+}
+
 // processes PacketMsg::WhoAmI
 fn receive_who_am_i(
     deps: DepsMut,
+    env: &Env,
     channel_id: String,
-    chain_id: u32,
+    src_port_id: &str,
+    mut handshake: Handshake,
 ) -> StdResult<IbcReceiveResponse> {
-    let action = |_| -> StdResult<String> { Ok(channel_id.to_string()) };
-    CHANNELS.update(deps.storage, chain_id, action)?;
+    let chain_id = handshake.chain_id.clone();
+    handshake.reason = verify_handshake(&deps, env, &channel_id, src_port_id, &handshake)?;
+    handshake.accepted = handshake.reason.is_none();
+    HANDSHAKES.save(deps.storage, channel_id.clone(), &handshake)?;
+
+    if let Some(reason) = handshake.reason {
+        return Ok(IbcReceiveResponse::new()
+            .set_ack(encode_ibc_error(format!("handshake refused: {}", reason)))
+            .add_attribute("action", "receive_who_am_i")
+            .add_attribute("chain_id", chain_id.to_string())
+            .add_attribute("refused", reason));
+    }
+
+    // the peer takes its place in the leader rotation, a handshake repeated on its channel doesn't count it twice
+    let mut state = STATE.load(deps.storage)?;
+    if !CHANNELS.has(deps.storage, chain_id.clone()) {
+        state.n += 1;
+    }
+    CHANNELS.save(deps.storage, chain_id.clone(), &channel_id)?;
+    state.members.insert(chain_id.clone());
+    STATE.save(deps.storage, &state)?;
 
     // initialize the highest_request of that chain
     // let action = |_| -> StdResult<u32> { Ok(0) };
     // HIGHEST_REQ.update(deps.storage, chain_id, action)?;
     // initialize the highest_request of that chain
-    HIGHEST_ABORT.save(deps.storage, chain_id, &-1)?;
+    HIGHEST_ABORT.save(deps.storage, chain_id.clone(), &-1)?;
 
     let response = WhoAmIResponse {};
     let acknowledgement = to_binary(&AcknowledgementMsg::Ok(response))?;
@@ -170,27 +294,124 @@ fn receive_who_am_i(
         .add_attribute("chain_id", chain_id.to_string()))
 }
 
+// Returns the reason for refusing the handshake, or None if the counterparty is accepted
+fn verify_handshake(
+    deps: &DepsMut,
+    env: &Env,
+    channel_id: &str,
+    src_port_id: &str,
+    handshake: &Handshake,
+) -> StdResult<Option<String>> {
+    let state = STATE.load(deps.storage)?;
+
+    if handshake.protocol_version != PROTOCOL_VERSION {
+        return Ok(Some(format!(
+            "protocol version {} does not match {}",
+            handshake.protocol_version, PROTOCOL_VERSION
+        )));
+    }
+    if handshake.fingerprint != config_fingerprint(deps.storage, &env.block.chain_id)? {
+        return Ok(Some("config fingerprint mismatch".to_string()));
+    }
+    if handshake.chain_id == state.chain_id {
+        return Ok(Some(format!("chain_id {} is our own", handshake.chain_id)));
+    }
+    // when a member set is configured, the counterparty must be one of them
+    let has_members = MEMBERS
+        .keys(deps.storage, None, None, Order::Ascending)
+        .next()
+        .is_some();
+    if has_members {
+        match MEMBERS.may_load(deps.storage, handshake.chain_id.clone())? {
+            Some(network_id) if network_id == handshake.network_id => (),
+            Some(network_id) => {
+                return Ok(Some(format!(
+                    "chain_id {} belongs to network {}, not {}",
+                    handshake.chain_id, network_id, handshake.network_id
+                )))
+            }
+            None => return Ok(Some(format!("chain_id {} is not a member", handshake.chain_id))),
+        }
+    }
+    // the contract announced has to be the one bound to the port of the counterparty
+    if src_port_id != format!("wasm.{}", handshake.contract_addr) {
+        return Ok(Some(format!(
+            "contract {} is not behind port {}",
+            handshake.contract_addr, src_port_id
+        )));
+    }
+    match CHANNELS.may_load(deps.storage, handshake.chain_id.clone())? {
+        Some(bound) if bound != channel_id => Ok(Some(format!(
+            "chain_id {} is already bound to {}",
+            handshake.chain_id, bound
+        ))),
+        _ => Ok(None),
+    }
+}
+
 
 #[entry_point]
 pub fn ibc_packet_ack(
-    _deps: DepsMut,
-    _env: Env,
+    mut deps: DepsMut,
+    env: Env,
     msg: IbcPacketAckMsg,
 ) -> StdResult<IbcBasicResponse> {
-    let packet: PacketMsg = from_slice(&msg.original_packet.data)?;
+    let packet: PacketMsg = decode_packet(&msg.original_packet.data)?;
     match packet {
-        PacketMsg::MsgQueue(_q) => Ok(IbcBasicResponse::new()),
-        PacketMsg::WhoAmI { chain_id: _ } => Ok(IbcBasicResponse::new()),
+        PacketMsg::MsgQueue(_q) => {
+            // the receiver may have answered us through the acknowledgement
+            let data = msg.acknowledgement.data.as_slice();
+            let (success, replies) = match from_slice::<AcknowledgementMsg<MsgQueueResponse>>(data) {
+                Ok(ContractResult::Ok(response)) => (true, response.replies),
+                // error acks and acks of chains that don't piggyback carry nothing
+                Ok(ContractResult::Err(_)) => (false, Vec::new()),
+                // acks of chains that don't answer with a result at all, anything else is garbled
+                Err(_) => (data.is_empty() || data == b"{}", Vec::new()),
+            };
+            record_ack(deps.storage, &env, &msg.original_packet.src.channel_id, success)?;
+            if replies.is_empty() {
+                return Ok(IbcBasicResponse::new());
+            }
+            // failing here would fail the relayer's acknowledgement, the replies are dropped instead.
+            // Replies that fail to be handled are reported by receive_msg_queue, failing to get that far here
+            let channel_id = msg.original_packet.src.channel_id;
+            let count = replies.len();
+            let result = match receive_msg_queue(deps.branch(), &env, channel_id.clone(), replies, false) {
+                Ok(result) => result,
+                Err(err) => {
+                    log_at(deps.storage, &env, LogLevel::Error, LogCategory::Packet, format!(
+                        "dropped {} replies on {}: {}", count, channel_id, err
+                    ))?;
+                    let state = STATE.load(deps.storage)?;
+                    let fault = protocol_event(EVENT_FAULT, state.instance, state.view).add_attributes(vec![
+                        ("fault", "failing_replies".to_string()),
+                        ("sender", channel_id),
+                        ("error", err.to_string()),
+                    ]);
+                    return Ok(IbcBasicResponse::new()
+                        .add_event(fault)
+                        .add_attribute("action", "receive_ack_replies")
+                        .add_attribute("error", err.to_string()));
+                },
+            };
+            Ok(IbcBasicResponse::new()
+                .add_submessages(result.messages)
+                .add_attributes(result.attributes)
+                .add_events(result.events)
+                .add_attribute("action", "receive_ack_replies"))
+        },
+        PacketMsg::WhoAmI { .. } => Ok(IbcBasicResponse::new()),
     }
 }
 
 #[entry_point]
-/// This will never be called
+/// Packets are not resent, the timeout only counts against the liveness of the peer
 pub fn ibc_packet_timeout(
-    _deps: DepsMut,
-    _env: Env,
-    _msg: IbcPacketTimeoutMsg,
+    deps: DepsMut,
+    env: Env,
+    msg: IbcPacketTimeoutMsg,
 ) -> StdResult<IbcBasicResponse> {
+    record_timeout(deps.storage, &env, &msg.packet.src.channel_id)?;
     Ok(IbcBasicResponse::new().add_attribute("action", "ibc_packet_timeout"))
 }
 
@@ -622,3 +843,232 @@ mod tests {
 }
 
 */
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::testing::{mock_env, mock_ibc_channel, mock_ibc_packet_ack, mock_ibc_packet_recv};
+    use cosmwasm_std::{IbcAcknowledgement, IbcOrder};
+
+    use crate::codec::COMPACT_CODEC_VERSION;
+    use crate::health::peer_health;
+    use crate::log::query_logs;
+
+    use crate::msg::InstantiateMsg;
+    use crate::state::{Member, HIGHEST_REQ, VALUES};
+    use crate::utils::{convert_send_ibc_msg, IBC_APP_VERSION_COMPACT_PREFIX};
+    use crate::test_utils::{
+        ack, answer, channel, connect, deliver, failing_msg, instantiate_msg, instantiate_with, receive, setup, signed_input, TestDeps, CHAINS,
+    };
+
+    use super::*;
+
+    // the member list of chain_id, every other chain on the network of mock_env
+    fn members_of(chain_id: u32) -> InstantiateMsg {
+        let members = (0..CHAINS)
+            .filter(|peer| *peer != chain_id)
+            .map(|peer| Member { chain_id: peer.to_string(), network_id: mock_env().block.chain_id, bech32_prefix: None })
+            .collect();
+        InstantiateMsg { members, ..instantiate_msg(chain_id) }
+    }
+
+    fn handshake_of(deps: &TestDeps, peer: u32) -> Handshake {
+        HANDSHAKES.load(&deps.storage, channel(peer)).unwrap()
+    }
+
+    // connects chain 0 to chain 1 and hands it the WhoAmI of chain 1 after changing it with f
+    fn shake(mut deps: TestDeps, f: impl FnOnce(&mut PacketMsg)) -> (TestDeps, Handshake) {
+        let mut who_am_i = answer(connect(&mut deps, 1), 1);
+        f(&mut who_am_i);
+        receive(&mut deps, 1, &who_am_i);
+        let handshake = handshake_of(&deps, 1);
+        (deps, handshake)
+    }
+
+    fn wrong_version(who_am_i: &mut PacketMsg) {
+        if let PacketMsg::WhoAmI { protocol_version, .. } = who_am_i {
+            *protocol_version += 1;
+        }
+    }
+
+    fn wrong_fingerprint(who_am_i: &mut PacketMsg) {
+        if let PacketMsg::WhoAmI { fingerprint, .. } = who_am_i {
+            *fingerprint = "00".to_string();
+        }
+    }
+
+    fn wrong_network(who_am_i: &mut PacketMsg) {
+        if let PacketMsg::WhoAmI { network_id, .. } = who_am_i {
+            *network_id = "other-network".to_string();
+        }
+    }
+
+    fn wrong_contract(who_am_i: &mut PacketMsg) {
+        if let PacketMsg::WhoAmI { contract_addr, .. } = who_am_i {
+            *contract_addr = "other-contract".to_string();
+        }
+    }
+
+    fn compact(layout: u8) -> String {
+        format!("{}{}", IBC_APP_VERSION_COMPACT_PREFIX, layout)
+    }
+
+    #[test]
+    fn channels_negotiate_down_to_a_layout_both_ends_read() {
+        let mut deps = instantiate_with(instantiate_msg(0));
+        let try_open = |proposed: u8, chosen: u8| {
+            let end = mock_ibc_channel(&channel(1), IbcOrder::Unordered, &compact(chosen));
+            IbcChannelOpenMsg::new_try(end, compact(proposed))
+        };
+        ibc_channel_open(deps.as_mut(), mock_env(), try_open(COMPACT_CODEC_VERSION, 5)).unwrap();
+        ibc_channel_open(deps.as_mut(), mock_env(), try_open(9, COMPACT_CODEC_VERSION)).unwrap();
+        ibc_channel_open(deps.as_mut(), mock_env(), try_open(5, COMPACT_CODEC_VERSION)).unwrap_err();
+        ibc_channel_open(deps.as_mut(), mock_env(), try_open(COMPACT_CODEC_VERSION, 3)).unwrap_err();
+
+        // we proposed the current layout and the counterparty answered with an older one
+        let end = mock_ibc_channel(&channel(1), IbcOrder::Unordered, &compact(COMPACT_CODEC_VERSION));
+        let connect = IbcChannelConnectMsg::new_ack(end, compact(4));
+        ibc_channel_connect(deps.as_mut(), mock_env(), connect).unwrap();
+        assert_eq!(CHANNEL_ENCODING.load(&deps.storage, channel(1)).unwrap(), PacketEncoding::Compact);
+        assert_eq!(CHANNEL_CODEC_VERSION.load(&deps.storage, channel(1)).unwrap(), 4);
+
+        let packet = PacketMsg::MsgQueue(vec![Msg::Request { view: 0, chain_id: "0".to_string() }]);
+        match convert_send_ibc_msg(&deps.storage, channel(1), packet.clone(), get_timeout(&mock_env())).unwrap() {
+            IbcMsg::SendPacket { data, .. } => {
+                assert_eq!(data.0[0], 4);
+                assert_eq!(decode_packet(&data).unwrap(), packet);
+            },
+            msg => panic!("unexpected {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn matching_handshake_binds_the_chain() {
+        let (deps, handshake) = shake(instantiate_with(members_of(0)), |_| ());
+        assert!(handshake.accepted, "{:?}", handshake.reason);
+        assert_eq!(CHANNELS.load(&deps.storage, "1".to_string()).unwrap(), channel(1));
+        let state = STATE.load(&deps.storage).unwrap();
+        assert_eq!(state.n, 2);
+        assert!(state.members.contains("1"));
+    }
+
+    #[test]
+    fn members_agree_on_the_fingerprint() {
+        let fingerprint = |deps: &TestDeps| config_fingerprint(&deps.storage, &mock_env().block.chain_id).unwrap();
+        let chain_0 = instantiate_with(members_of(0));
+        let chain_1 = instantiate_with(members_of(1));
+        assert_eq!(fingerprint(&chain_0), fingerprint(&chain_1));
+
+        // a chain that leaves out a member, or moves itself to another network, disagrees
+        let mut partial = members_of(1);
+        partial.members.pop();
+        assert_ne!(fingerprint(&chain_0), fingerprint(&instantiate_with(partial)));
+        assert_ne!(fingerprint(&chain_0), config_fingerprint(&chain_1.storage, "other-network").unwrap());
+    }
+
+    #[test]
+    fn refused_handshakes_leave_the_chain_out() {
+        let cases = [
+            ("protocol version", wrong_version as fn(&mut PacketMsg)),
+            ("fingerprint mismatch", wrong_fingerprint),
+            ("belongs to network", wrong_network),
+            ("is not behind port", wrong_contract),
+        ];
+        for (reason, f) in cases {
+            let (deps, handshake) = shake(instantiate_with(members_of(0)), f);
+            assert!(!handshake.accepted);
+            assert!(handshake.reason.as_ref().unwrap().contains(reason), "{:?}", handshake.reason);
+            assert!(!CHANNELS.has(&deps.storage, "1".to_string()));
+            assert_eq!(STATE.load(&deps.storage).unwrap().n, 1);
+        }
+    }
+
+    #[test]
+    fn contract_addr_is_checked_against_the_port() {
+        let mut deps = instantiate_with(members_of(0));
+        let who_am_i = answer(connect(&mut deps, 1), 1);
+        // mock_ibc_packet_recv sends from a port that no contract owns
+        let recv = mock_ibc_packet_recv(&channel(1), &who_am_i).unwrap();
+        ibc_packet_receive(deps.as_mut(), mock_env(), recv).unwrap();
+        assert!(!handshake_of(&deps, 1).accepted);
+    }
+
+    #[test]
+    fn repeated_handshake_counts_the_chain_once() {
+        let (mut deps, _) = shake(instantiate_with(members_of(0)), |_| ());
+        let who_am_i = answer(connect(&mut deps, 1), 1);
+        receive(&mut deps, 1, &who_am_i);
+        assert!(handshake_of(&deps, 1).accepted);
+        assert_eq!(STATE.load(&deps.storage).unwrap().n, 2);
+    }
+
+    fn ack_with(deps: &mut TestDeps, peer: u32, ack: &AcknowledgementMsg<MsgQueueResponse>) -> IbcBasicResponse {
+        let sent = PacketMsg::MsgQueue(vec![Msg::Request { view: 0, chain_id: "0".to_string() }]);
+        let ack = IbcAcknowledgement::new(to_binary(ack).unwrap());
+        ibc_packet_ack(deps.as_mut(), mock_env(), mock_ibc_packet_ack(&channel(peer), &sent, ack).unwrap()).unwrap()
+    }
+
+    fn highest_req(deps: &TestDeps, chain_id: &str) -> u32 {
+        HIGHEST_REQ.may_load(&deps.storage, chain_id.to_string()).unwrap().unwrap_or_default()
+    }
+
+    fn replies(replies: Vec<WireMsg>) -> AcknowledgementMsg<MsgQueueResponse> {
+        ContractResult::Ok(MsgQueueResponse { replies })
+    }
+
+    #[test]
+    fn replies_to_the_sender_ride_on_the_acknowledgement() {
+        let mut deps = setup(0);
+        let val = signed_input(1, 0);
+        VALUES.save(&mut deps.storage, val.digest(), &val).unwrap();
+        let res = deliver(&mut deps, 1, vec![Msg::FetchValue { digest: val.digest() }]);
+        match ack(&res) {
+            ContractResult::Ok(response) => assert_eq!(response.replies, vec![Msg::Value { val }]),
+            ContractResult::Err(err) => panic!("{}", err),
+        }
+        assert!(res.messages.is_empty());
+    }
+
+    #[test]
+    fn replies_on_acknowledgements_are_processed() {
+        let mut deps = setup(0);
+        ack_with(&mut deps, 1, &replies(vec![Msg::Request { view: 1, chain_id: "1".to_string() }]));
+        assert_eq!(highest_req(&deps, "1"), 1);
+
+        // error acknowledgements carry nothing
+        let res = ack_with(&mut deps, 2, &ContractResult::Err("invalid packet".to_string()));
+        assert!(res.messages.is_empty());
+        assert_eq!(highest_req(&deps, "2"), 0);
+    }
+
+    #[test]
+    fn failing_replies_are_dropped_without_failing_the_acknowledgement() {
+        let mut deps = setup(0);
+        let failing = failing_msg(&mut deps);
+        let res = ack_with(&mut deps, 1, &replies(vec![Msg::Request { view: 1, chain_id: "1".to_string() }, failing]));
+        assert!(res.attributes.iter().any(|attr| attr.key == "error"));
+        assert_eq!(highest_req(&deps, "1"), 0);
+        // reported rather than swallowed
+        let fault = res.events.iter().find(|event| event.ty == EVENT_FAULT).unwrap();
+        assert!(fault.attributes.iter().any(|attr| attr.key == "fault" && attr.value == "failing_replies"));
+        let errors = query_logs(&deps.storage, Some(LogLevel::Error), None, None).unwrap().entries;
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].message.starts_with("dropped 2 messages from 1"), "{}", errors[0].message);
+    }
+
+    fn last_ack_success(deps: &TestDeps, peer: u32) -> bool {
+        let peers = peer_health(&deps.storage).unwrap();
+        let health = peers.iter().find(|health| health.chain_id == peer.to_string()).unwrap();
+        health.last_ack.as_ref().unwrap().success
+    }
+
+    #[test]
+    fn garbled_acknowledgements_count_as_failures() {
+        let mut deps = setup(0);
+        let sent = PacketMsg::MsgQueue(vec![Msg::Request { view: 0, chain_id: "0".to_string() }]);
+        for (data, success) in [(&b""[..], true), (b"{}", true), (b"garbled", false), (br#"{"ok":"#, false)] {
+            let ack = IbcAcknowledgement::new(data);
+            ibc_packet_ack(deps.as_mut(), mock_env(), mock_ibc_packet_ack(&channel(1), &sent, ack).unwrap()).unwrap();
+            assert_eq!(last_ack_success(&deps, 1), success, "{:?}", String::from_utf8_lossy(data));
+        }
+    }
+}
@@ -1,19 +1,39 @@
-use cosmwasm_std::{ContractResult};
+use cosmwasm_std::{Binary, ContractResult};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use crate::state::InputType;
+use crate::state::{peer_id, InputType, PeerId};
+
+/// How a value travels over the wire: in full, or by digest once the receiver is expected to have it.
+/// Values too large for a packet are referred to as Chunked, their chunks travel in packets of their own
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ValueRef {
+    Full(InputType),
+    Digest(String),
+    Chunked(String),
+}
+
+/// Msg as it is sent over IBC, see values::to_wire
+pub type WireMsg = Msg<ValueRef>;
 
 /// Messages that will be sent over the IBC channel
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum PacketMsg {
     MsgQueue (
-        Vec<Msg>
+        Vec<WireMsg>
     ),
     WhoAmI { 
-        chain_id: u32,
-        
+        #[serde(deserialize_with = "peer_id")]
+        chain_id: PeerId,
+        protocol_version: u32,
+        // fingerprint of the member set and fault threshold, see utils::config_fingerprint
+        fingerprint: String,
+        // address of the sending contract, it has to own the port the packet comes from
+        contract_addr: String,
+        // chain-id of the sending network
+        network_id: String,
     },
     // TimeoutMsg{
     //     time_view: u32
@@ -22,64 +42,83 @@ pub enum PacketMsg {
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
-pub enum Msg {
+pub enum Msg<V = InputType> {
 
     Request { 
         view: u32, 
-        chain_id: u32 
+        #[serde(deserialize_with = "peer_id")]
+        chain_id: PeerId 
     },
     Suggest { 
-        chain_id: u32,
+        #[serde(deserialize_with = "peer_id")]
+        chain_id: PeerId,
         view: u32,
         key2: u32,
-        key2_val: InputType,
+        key2_val: V,
         prev_key2: i32,
         key3: u32,
-        key3_val: InputType
+        key3_val: V
     },
     Proof {
         key1: u32,
-        key1_val: InputType,
+        key1_val: V,
         prev_key1: i32,
         view: u32
     },
     Abort {
         view: u32,
-        chain_id: u32,
+        #[serde(deserialize_with = "peer_id")]
+        chain_id: PeerId,
     },
     Propose { 
-        chain_id: u32,
+        #[serde(deserialize_with = "peer_id")]
+        chain_id: PeerId,
         k: u32, 
-        v: InputType,
+        v: V,
         view: u32 
     },
     Echo {
         // chain_id: u32,
-        val: InputType,
+        val: V,
         view: u32
     },
     Key1 {
-        val: InputType,
+        val: V,
         view: u32
     },
     Key2 {
-        val: InputType,
+        val: V,
         view: u32
     },
     Key3 {
-        val: InputType,
+        val: V,
         view: u32
     },
     Lock {
-        val: InputType,
+        val: V,
         view: u32
     },
     Done {
+        val: V
+    },
+    // Ask the receiver for the full value behind a digest it referenced
+    FetchValue {
+        digest: String
+    },
+    // Reply to FetchValue
+    Value {
         val: InputType
     },
+    // Part index of total of the Json encoding of the value with the given digest, see values::value_chunks
+    ValueChunk {
+        digest: String,
+        index: u32,
+        total: u32,
+        data: Binary
+    },
 }
 
-impl Msg {
+impl<V> Msg<V> {
     // name return the static str version of the Msg type
     pub(crate) fn name(&self) -> &'static str {
         match self {
@@ -94,8 +133,76 @@ impl Msg {
             Msg::Key3 { val: _, view : _} => stringify!(Key3),
             Msg::Lock { val: _, view : _} => stringify!(Lock),
             Msg::Done { val: _ } => stringify!(Done),
+            Msg::FetchValue { digest: _ } => stringify!(FetchValue),
+            Msg::Value { val: _ } => stringify!(Value),
+            Msg::ValueChunk { digest: _, index: _, total: _, data: _ } => stringify!(ValueChunk),
+        }
+    }
+
+    // view returns the view the message belongs to, None for messages that outlive views
+    pub(crate) fn view(&self) -> Option<u32> {
+        match self {
+            Msg::Request { view, .. }
+            | Msg::Suggest { view, .. }
+            | Msg::Proof { view, .. }
+            | Msg::Abort { view, .. }
+            | Msg::Propose { view, .. }
+            | Msg::Echo { view, .. }
+            | Msg::Key1 { view, .. }
+            | Msg::Key2 { view, .. }
+            | Msg::Key3 { view, .. }
+            | Msg::Lock { view, .. } => Some(*view),
+            Msg::Done { .. } | Msg::FetchValue { .. } | Msg::Value { .. } | Msg::ValueChunk { .. } => None,
         }
     }
+
+    // values returns the protocol values carried by the message
+    pub(crate) fn values(&self) -> Vec<&V> {
+        match self {
+            Msg::Suggest { key2_val, key3_val, .. } => vec![key2_val, key3_val],
+            Msg::Proof { key1_val, .. } => vec![key1_val],
+            Msg::Propose { v, .. } => vec![v],
+            Msg::Echo { val, .. }
+            | Msg::Key1 { val, .. }
+            | Msg::Key2 { val, .. }
+            | Msg::Key3 { val, .. }
+            | Msg::Lock { val, .. }
+            | Msg::Done { val } => vec![val],
+            Msg::Request { .. }
+            | Msg::Abort { .. }
+            | Msg::FetchValue { .. }
+            | Msg::Value { .. }
+            | Msg::ValueChunk { .. } => vec![],
+        }
+    }
+
+    // try_map_values converts the protocol values carried by the message, leaving the rest untouched
+    pub(crate) fn try_map_values<W, E>(self, mut f: impl FnMut(V) -> Result<W, E>) -> Result<Msg<W>, E> {
+        Ok(match self {
+            Msg::Request { view, chain_id } => Msg::Request { view, chain_id },
+            Msg::Suggest { chain_id, view, key2, key2_val, prev_key2, key3, key3_val } => Msg::Suggest {
+                chain_id,
+                view,
+                key2,
+                key2_val: f(key2_val)?,
+                prev_key2,
+                key3,
+                key3_val: f(key3_val)?,
+            },
+            Msg::Proof { key1, key1_val, prev_key1, view } => Msg::Proof { key1, key1_val: f(key1_val)?, prev_key1, view },
+            Msg::Abort { view, chain_id } => Msg::Abort { view, chain_id },
+            Msg::Propose { chain_id, k, v, view } => Msg::Propose { chain_id, k, v: f(v)?, view },
+            Msg::Echo { val, view } => Msg::Echo { val: f(val)?, view },
+            Msg::Key1 { val, view } => Msg::Key1 { val: f(val)?, view },
+            Msg::Key2 { val, view } => Msg::Key2 { val: f(val)?, view },
+            Msg::Key3 { val, view } => Msg::Key3 { val: f(val)?, view },
+            Msg::Lock { val, view } => Msg::Lock { val: f(val)?, view },
+            Msg::Done { val } => Msg::Done { val: f(val)? },
+            Msg::FetchValue { digest } => Msg::FetchValue { digest },
+            Msg::Value { val } => Msg::Value { val },
+            Msg::ValueChunk { digest, index, total, data } => Msg::ValueChunk { digest, index, total, data },
+        })
+    }
 }
 
 // #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -164,4 +271,7 @@ pub struct DoneResponse {
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct MsgQueueResponse {
+    /// Messages for the sender of the queue, they ride on the acknowledgement instead of a new packet
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub replies: Vec<WireMsg>,
 }
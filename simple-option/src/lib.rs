@@ -1,13 +1,33 @@
+pub mod codec;
 pub mod contract;
+pub mod context;
+pub mod diagnose;
+pub mod events;
+pub mod health;
 pub mod ibc;
 pub mod ibc_msg;
+pub mod log;
 mod error;
 pub mod msg;
 pub mod state;
+pub mod submission;
+pub mod timeline;
 pub mod utils;
+pub mod validation;
 pub mod queue_handler;
+pub mod replay;
+pub mod signature;
 pub mod view_change;
 pub mod abort;
+pub mod admin;
+#[cfg(feature = "testing")]
 pub mod malicious_trigger;
+pub mod metrics;
+pub mod migrate;
+pub mod outbox;
+pub mod values;
+#[cfg(test)]
+mod test_utils;
+// unused.rs keeps superseded handlers for reference and is not part of any build
 
 pub use crate::error::ContractError;
@@ -0,0 +1,157 @@
+// Leveled log with bounded retention.
+//
+// Entries are numbered in the order they are written and kept in a ring buffer of LogConfig::retention
+// entries (MAX_LOG_RETENTION at most), the oldest being dropped as new ones come in. Entries below the configured level are not
+// written at all, so that a quiet deployment only pays for reading the config.
+
+use cosmwasm_std::{Env, Order, StdError, StdResult, Storage};
+use cw_storage_plus::Bound;
+
+use crate::context::Context;
+use crate::msg::LogsResponse;
+use crate::state::{LogCategory, LogConfig, LogEntry, LogLevel, LOG_CONFIG, LOG_ENTRIES, LOG_SEQ};
+use crate::utils::{MAX_LOGS_PAGE, MAX_LOGS_SCAN, MAX_LOG_RETENTION};
+
+// log writes message for the current block of ctx
+pub fn log(ctx: &mut Context, level: LogLevel, category: LogCategory, message: impl Into<String>) -> StdResult<()> {
+    log_at(&mut ctx.store, ctx.env, level, category, message)
+}
+
+pub fn log_at(
+    store: &mut dyn Storage,
+    env: &Env,
+    level: LogLevel,
+    category: LogCategory,
+    message: impl Into<String>,
+) -> StdResult<()> {
+    let config = LOG_CONFIG.may_load(store)?.unwrap_or_default();
+    match config.level {
+        Some(min) if level >= min && config.retention > 0 => (),
+        _ => return Ok(()),
+    }
+    let seq = LOG_SEQ.may_load(store)?.unwrap_or_default();
+    let entry = LogEntry {
+        seq,
+        level,
+        category,
+        height: env.block.height,
+        message: message.into(),
+    };
+    LOG_ENTRIES.save(store, seq, &entry)?;
+    if let Some(expired) = seq.checked_sub(config.retention as u64) {
+        LOG_ENTRIES.remove(store, expired);
+    }
+    LOG_SEQ.save(store, &(seq + 1))
+}
+
+// set_log_config replaces the config, entries beyond a reduced retention are dropped right away
+pub fn set_log_config(store: &mut dyn Storage, config: &LogConfig) -> StdResult<()> {
+    if config.retention > MAX_LOG_RETENTION {
+        return Err(StdError::generic_err(format!("retention is at most {} entries", MAX_LOG_RETENTION)));
+    }
+    let next = LOG_SEQ.may_load(store)?.unwrap_or_default();
+    let keep_from = next.saturating_sub(config.retention as u64);
+    let expired: Vec<u64> = LOG_ENTRIES
+        .keys(store, None, Some(Bound::exclusive(keep_from)), Order::Ascending)
+        .collect::<StdResult<_>>()?;
+    for seq in expired {
+        LOG_ENTRIES.remove(store, seq);
+    }
+    LOG_CONFIG.save(store, config)
+}
+
+// query_logs pages through the entries numbered since and later that are at least of level.
+// next is where the following page starts, None once there is nothing left. A page ends after limit entries
+// or MAX_LOGS_SCAN entries looked at, so with a level it may come back short, even empty, with a next.
+pub fn query_logs(
+    store: &dyn Storage,
+    level: Option<LogLevel>,
+    since: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<LogsResponse> {
+    let limit = limit.unwrap_or(MAX_LOGS_PAGE).clamp(1, MAX_LOGS_PAGE) as usize;
+    let mut entries = Vec::new();
+    let mut next = None;
+    let range = LOG_ENTRIES.range(store, since.map(Bound::inclusive), None, Order::Ascending);
+    for (scanned, entry) in range.enumerate() {
+        let (seq, entry) = entry?;
+        if entries.len() == limit || scanned == MAX_LOGS_SCAN {
+            next = Some(seq);
+            break;
+        }
+        match level {
+            Some(level) if entry.level < level => (),
+            _ => entries.push(entry),
+        }
+    }
+    Ok(LogsResponse {
+        config: LOG_CONFIG.may_load(store)?.unwrap_or_default(),
+        entries,
+        next,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::testing::{mock_env, MockStorage};
+
+    use super::*;
+
+    // a log of count entries, every tenth one an error if errors is set
+    fn log_of(count: u32, errors: bool) -> MockStorage {
+        let mut store = MockStorage::new();
+        set_log_config(&mut store, &LogConfig { level: Some(LogLevel::Trace), retention: MAX_LOG_RETENTION }).unwrap();
+        for i in 0..count {
+            let level = if errors && i % 10 == 9 { LogLevel::Error } else { LogLevel::Trace };
+            log_at(&mut store, &mock_env(), level, LogCategory::Protocol, i.to_string()).unwrap();
+        }
+        store
+    }
+
+    // follows next from the start and returns every entry seen and the number of pages
+    fn read_all(store: &dyn Storage, level: Option<LogLevel>, limit: Option<u32>) -> (Vec<LogEntry>, usize) {
+        let (mut entries, mut pages, mut since) = (Vec::new(), 0, None);
+        loop {
+            let page = query_logs(store, level, since, limit).unwrap();
+            entries.extend(page.entries);
+            pages += 1;
+            match page.next {
+                Some(next) => since = Some(next),
+                None => return (entries, pages),
+            }
+        }
+    }
+
+    #[test]
+    fn a_zero_limit_still_makes_progress() {
+        let store = log_of(3, true);
+        let page = query_logs(&store, None, None, Some(0)).unwrap();
+        assert_eq!(page.entries.len(), 1);
+        assert_eq!(page.next, Some(1));
+        assert_eq!(read_all(&store, None, Some(0)), (query_logs(&store, None, None, None).unwrap().entries, 3));
+    }
+
+    #[test]
+    fn filtered_pages_look_at_a_bounded_number_of_entries() {
+        let store = log_of(2500, true);
+        let page = query_logs(&store, Some(LogLevel::Error), None, None).unwrap();
+        assert_eq!(page.entries.len(), MAX_LOGS_PAGE as usize);
+        assert_eq!(page.next, Some(1000));
+
+        let page = query_logs(&log_of(1500, false), Some(LogLevel::Error), None, None).unwrap();
+        assert!(page.entries.is_empty());
+        assert_eq!(page.next, Some(MAX_LOGS_SCAN as u64));
+
+        let (errors, pages) = read_all(&store, Some(LogLevel::Error), None);
+        assert_eq!(errors.len(), 250);
+        assert_eq!(pages, 3);
+    }
+
+    #[test]
+    fn retention_is_capped() {
+        let mut store = MockStorage::new();
+        let config = LogConfig { level: Some(LogLevel::Info), retention: MAX_LOG_RETENTION + 1 };
+        set_log_config(&mut store, &config).unwrap_err();
+        assert_eq!(query_logs(&store, None, None, None).unwrap().config, LogConfig::default());
+    }
+}
@@ -7,14 +7,16 @@ use cosmwasm_std::{
 
 use std::convert::TryInto;
 
+use crate::context::Context;
 use crate::error::ContractError;
 use crate::ibc_msg::{Msg, PacketMsg};
-use crate::queue_handler::{send_all_party};
+use crate::queue_handler::{run_inbox, send_all_party};
 use crate::utils::{get_timeout, get_id_channel_pair_from_storage, convert_send_ibc_msg};
 use crate::view_change::{convert_queue_to_ibc_msgs, testing_add2queue};
+use crate::values::queue_to_wire;
 // use crate::ibc_msg::PacketMsg;
 use crate::state::{
-    CHANNELS, STATE, InputType, TBInput,
+    CHANNELS, STATE, InputType, TBInput, InputDomain, SignatureScheme,
 };
 
 
@@ -27,17 +29,18 @@ pub fn trigger_done(
     Response::new()
         .add_attribute("action", "trigger")
         .add_attribute("trigger_behavior", "done");
-    let state = STATE.load(deps.storage)?;
+    let mut ctx = Context::load(deps.storage, deps.api, &env)?;
 
-    let mut queue: Vec<Vec<Msg>> = vec!(Vec::new(); state.n.try_into().unwrap());
     // self-send msg
     // receive_queue(store, timeout, None, vec![packet.clone()], queue)?;
     let done_packet = Msg::Done {
         // val: "MALICIOUS_VAL".to_string()
-        val: TBInput { binary: "TODO".to_string(), public_key: Vec::new(), signature: Vec::new()}
+        val: TBInput { binary: "TODO".to_string(), public_key: Vec::new(), signature: Vec::new(), scheme: SignatureScheme::Secp256k1, nonce: 0, domain: InputDomain::default(), multisig: None }
     };
-    send_all_party(deps.storage, &mut queue, done_packet, get_timeout(&env), &env, deps.api)?;
-    let msgs = convert_queue_to_ibc_msgs(deps.storage, &mut queue, get_timeout(&env))?;
+    send_all_party(&mut ctx, done_packet)?;
+    run_inbox(&mut ctx)?;
+    let msgs = convert_queue_to_ibc_msgs(&mut ctx)?;
+    ctx.commit()?;
 
     Ok(res
         .add_messages(msgs))
@@ -58,27 +61,31 @@ pub fn trigger_done_2(
     // receive_queue(store, timeout, None, vec![packet.clone()], queue)?;
     let packet_1 = Msg::Done {
         // val: "PACKET_A".to_string()
-        val: TBInput { binary: "TODO".to_string(), public_key: Vec::new(), signature: Vec::new()}
+        val: TBInput { binary: "TODO".to_string(), public_key: Vec::new(), signature: Vec::new(), scheme: SignatureScheme::Secp256k1, nonce: 0, domain: InputDomain::default(), multisig: None }
     };
 
     let packet_2 = Msg::Done {
         // val: "PACKET_B".to_string()
-        val: TBInput { binary: "TODO".to_string(), public_key: Vec::new(), signature: Vec::new() }
+        val: TBInput { binary: "TODO".to_string(), public_key: Vec::new(), signature: Vec::new(), scheme: SignatureScheme::Secp256k1, nonce: 0, domain: InputDomain::default(), multisig: None }
     };
 
-    let channel_id_1 = CHANNELS.load(deps.storage, 1)?;
-    let channel_id_2 = CHANNELS.load(deps.storage, 2)?;
+    // the first two peers get different values
+    let channel_ids = get_id_channel_pair_from_storage(deps.storage)?;
+    let (channel_id_1, channel_id_2) = match channel_ids.as_slice() {
+        [(_, first), (_, second), ..] => (first.clone(), second.clone()),
+        _ => return Ok(res.add_attribute("error", "less than two peers")),
+    };
 
     let mut vec_1:Vec<Msg> = Vec::new();
     let mut vec_2:Vec<Msg> = Vec::new();
     vec_1.push(packet_1);
     vec_2.push(packet_2);
 
-    let packet_queue_1 = PacketMsg::MsgQueue(vec_1);
-    let packet_queue_2 = PacketMsg::MsgQueue(vec_2);
+    let packet_queue_1 = PacketMsg::MsgQueue(queue_to_wire(deps.storage, &vec_1)?);
+    let packet_queue_2 = PacketMsg::MsgQueue(queue_to_wire(deps.storage, &vec_2)?);
 
-    let ibc_packet_1 = IbcMsg::SendPacket { channel_id: channel_id_1, data: to_binary(&packet_queue_1)?, timeout: get_timeout(&env) };
-    let ibc_packet_2 = IbcMsg::SendPacket { channel_id: channel_id_2, data: to_binary(&packet_queue_2)?, timeout: get_timeout(&env) };
+    let ibc_packet_1 = convert_send_ibc_msg(deps.storage, channel_id_1, packet_queue_1, get_timeout(&env))?;
+    let ibc_packet_2 = convert_send_ibc_msg(deps.storage, channel_id_2, packet_queue_2, get_timeout(&env))?;
 
     Ok(res
         .add_message(ibc_packet_1)
@@ -101,15 +108,17 @@ pub fn trigger_abort(
         .add_attribute("error", "is primary"));
     }
     // let mut msgs = Vec::new();
-    let mut queue: Vec<Vec<Msg>> = vec!(Vec::new(); state.n.try_into().unwrap());
+    let mut ctx = Context::load(deps.storage, deps.api, env)?;
     // self-send msg
     // receive_queue(store, timeout, None, vec![packet.clone()], queue)?;
     let abort_packet = Msg::Abort {
         view: state.view,
-        chain_id: state.chain_id,
+        chain_id: state.chain_id.clone(),
     };
-    send_all_party(deps.storage, &mut queue, abort_packet, get_timeout(&env), env, deps.api)?;
-    let msgs = convert_queue_to_ibc_msgs(deps.storage, &mut queue, get_timeout(&env))?;
+    send_all_party(&mut ctx, abort_packet)?;
+    run_inbox(&mut ctx)?;
+    let msgs = convert_queue_to_ibc_msgs(&mut ctx)?;
+    ctx.commit()?;
 
     Ok(res
         .add_messages(msgs))
@@ -137,12 +146,12 @@ pub fn trigger_key1_diff_val(
 
     for (chain_id, channel_id) in &channel_ids {
         let val = ["TRIGGER_", &chain_id.to_string()].join("");
-        let val = TBInput { binary: "TODO".to_string(), public_key: Vec::new(), signature: Vec::new()};
+        let val = TBInput { binary: "TODO".to_string(), public_key: Vec::new(), signature: Vec::new(), scheme: SignatureScheme::Secp256k1, nonce: 0, domain: InputDomain::default(), multisig: None };
         let msg_queue = vec![Msg::Key1 { val, view: state.view }];
-        testing_add2queue(deps.storage, *chain_id, msg_queue.clone())?;
-        let packet = PacketMsg::MsgQueue(msg_queue);
+        testing_add2queue(deps.storage, chain_id.clone(), msg_queue.clone())?;
+        let packet = PacketMsg::MsgQueue(queue_to_wire(deps.storage, &msg_queue)?);
     
-        let msg = convert_send_ibc_msg(channel_id.to_string(), packet, get_timeout(&env));
+        let msg = convert_send_ibc_msg(deps.storage, channel_id.to_string(), packet, get_timeout(&env))?;
         msgs.push(msg);
     }
 
@@ -172,12 +181,12 @@ pub fn trigger_multi_propose(
 
     for (chain_id, channel_id) in &channel_ids {
         let v = ["TRIGGER_", &chain_id.to_string()].join("");
-        let v = TBInput { binary: "TODO".to_string(), public_key: Vec::new(), signature: Vec::new() };
-        let msg_queue = vec![Msg::Propose {chain_id: state.chain_id, k: state.view, v, view: state.view}];
-        testing_add2queue(deps.storage, *chain_id, msg_queue.clone())?;
+        let v = TBInput { binary: "TODO".to_string(), public_key: Vec::new(), signature: Vec::new(), scheme: SignatureScheme::Secp256k1, nonce: 0, domain: InputDomain::default(), multisig: None };
+        let msg_queue = vec![Msg::Propose {chain_id: state.chain_id.clone(), k: state.view, v, view: state.view}];
+        testing_add2queue(deps.storage, chain_id.clone(), msg_queue.clone())?;
 
-        let packet = PacketMsg::MsgQueue(msg_queue);
-        let msg = convert_send_ibc_msg(channel_id.to_string(), packet, get_timeout(&env));
+        let packet = PacketMsg::MsgQueue(queue_to_wire(deps.storage, &msg_queue)?);
+        let msg = convert_send_ibc_msg(deps.storage, channel_id.to_string(), packet, get_timeout(&env))?;
         msgs.push(msg);
     }
     /* 
@@ -0,0 +1,86 @@
+// Protocol counters. Handlers count into Context::metrics, which commit() adds to the counters of the
+// current instance and to the cumulative ones. An instance starts with the Input message.
+
+use cosmwasm_std::{StdResult, Storage};
+
+use crate::msg::MetricsResponse;
+use crate::state::{Metrics, INSTANCE_METRICS, TOTAL_METRICS};
+
+pub fn record_metrics(store: &mut dyn Storage, delta: &Metrics) -> StdResult<()> {
+    for item in [INSTANCE_METRICS, TOTAL_METRICS] {
+        let mut metrics = item.may_load(store)?.unwrap_or_default();
+        metrics.add(delta);
+        item.save(store, &metrics)?;
+    }
+    Ok(())
+}
+
+pub fn reset_instance_metrics(store: &mut dyn Storage) {
+    INSTANCE_METRICS.remove(store);
+}
+
+pub fn query_metrics(store: &dyn Storage) -> StdResult<MetricsResponse> {
+    Ok(MetricsResponse {
+        instance: INSTANCE_METRICS.may_load(store)?.unwrap_or_default(),
+        total: TOTAL_METRICS.may_load(store)?.unwrap_or_default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::testing::MockStorage;
+
+    use crate::ibc_msg::Msg;
+    use crate::test_utils::{deliver, failing_msg, setup, signed_input, submit};
+
+    use super::*;
+
+    #[test]
+    fn counts_are_kept_per_key_in_key_order() {
+        let mut delta = Metrics::default();
+        delta.count_sent("3", "Echo");
+        delta.count_sent("1", "Echo");
+        delta.count_sent("1", "Abort");
+        delta.count_received("2", "Request");
+        delta.packets_sent = 2;
+
+        let mut store = MockStorage::new();
+        record_metrics(&mut store, &delta).unwrap();
+        record_metrics(&mut store, &delta).unwrap();
+        let total = query_metrics(&store).unwrap().total;
+        assert_eq!(total.sent, vec![("Abort".to_string(), 2), ("Echo".to_string(), 4)]);
+        assert_eq!(total.sent_to, vec![("1".to_string(), 4), ("3".to_string(), 2)]);
+        assert_eq!(total.received_from, vec![("2".to_string(), 2)]);
+        assert_eq!(total.packets_sent, 4);
+    }
+
+    #[test]
+    fn instance_counters_restart_with_each_input() {
+        let mut deps = setup(2);
+        submit(&mut deps, signed_input(7, 0));
+        let metrics = query_metrics(&deps.storage).unwrap();
+        assert_eq!((metrics.instance.views, metrics.total.views), (1, 1));
+        assert!(metrics.instance.packets_sent > 0);
+        assert_eq!(metrics.instance.sent_to.len(), 3);
+
+        submit(&mut deps, signed_input(7, 1));
+        let metrics = query_metrics(&deps.storage).unwrap();
+        assert_eq!((metrics.instance.views, metrics.total.views), (1, 2));
+        assert_eq!(metrics.total.packets_sent, 2 * metrics.instance.packets_sent);
+    }
+
+    #[test]
+    fn failing_packets_count_nothing() {
+        let mut deps = setup(2);
+        submit(&mut deps, signed_input(7, 0));
+        let before = query_metrics(&deps.storage).unwrap();
+        deliver(&mut deps, 1, vec![Msg::Request { view: 1, chain_id: "1".to_string() }]);
+        let counted = query_metrics(&deps.storage).unwrap();
+        assert_eq!(counted.instance.received_from, vec![("1".to_string(), 1)]);
+
+        let failing = failing_msg(&mut deps);
+        deliver(&mut deps, 1, vec![Msg::Request { view: 2, chain_id: "1".to_string() }, failing]);
+        assert_eq!(query_metrics(&deps.storage).unwrap(), counted);
+        assert_ne!(counted, before);
+    }
+}
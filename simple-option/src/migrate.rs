@@ -0,0 +1,140 @@
+// Storage migrations, run by the migrate entry point.
+//
+// Version 1 identified chains by dense u32 indexes, version 2 by PeerId. Values holding chain ids
+// (State, handshakes, queued messages) still load through state::peer_id, only map keys and the
+// received sets need rewriting. Version 3 replaced the debug maps by the log of log.rs, version 4
+// added the admin of admin.rs.
+
+use std::collections::{BTreeSet, HashSet};
+
+use cosmwasm_std::{Addr, Order, StdResult, Storage};
+use cw_storage_plus::{Item, Map, PrimaryKey, KeyDeserialize};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::ibc_msg::Msg;
+use crate::state::{
+    PeerId, ADMIN, CHANNELS, HIGHEST_ABORT, HIGHEST_REQ, MEMBERS, RECEIVED, RECEIVED_DONE, RECEIVED_ECHO, RECEIVED_KEY1,
+    RECEIVED_KEY2, RECEIVED_KEY3, RECEIVED_LOCK, SEND_ALL_UPON, STATE,
+};
+
+/// Layout version of the contract storage
+pub const STORAGE_VERSION: Item<u32> = Item::new("storage_version");
+pub const CURRENT_STORAGE_VERSION: u32 = 4;
+
+// the u32 keyed tables of version 1, same namespaces as their PeerId keyed successors
+const LEGACY_CHANNELS: Map<u32, String> = Map::new("channels");
+const LEGACY_MEMBERS: Map<u32, String> = Map::new("members");
+const LEGACY_HIGHEST_REQ: Map<u32, u32> = Map::new("highest_req");
+const LEGACY_HIGHEST_ABORT: Map<u32, i32> = Map::new("highest_abort");
+const LEGACY_SEND_ALL_UPON: Map<u32, Vec<Msg>> = Map::new("send_all_upon");
+const LEGACY_RECEIVED: Map<String, HashSet<u32>> = Map::new("received");
+
+// the debug maps of version 2
+const LEGACY_DEBUG: Map<u32, ()> = Map::new("debug");
+const LEGACY_IBC_MSG_SEND_DEBUG: Map<u32, ()> = Map::new("ibc_msg_send_debug");
+const LEGACY_DEBUG_RECEIVE_MSG: Map<String, ()> = Map::new("DEBUG_RECEIVE_MSG");
+const LEGACY_DEBUG_CTR: Item<u32> = Item::new("DEBUG_CTR");
+
+type VoteMap<T> = Map<'static, u64, HashSet<T>>;
+const LEGACY_VOTES: [(VoteMap<u32>, VoteMap<PeerId>); 6] = [
+    (Map::new("received_echo"), RECEIVED_ECHO),
+    (Map::new("received_key1"), RECEIVED_KEY1),
+    (Map::new("received_key2"), RECEIVED_KEY2),
+    (Map::new("received_key3"), RECEIVED_KEY3),
+    (Map::new("received_lock"), RECEIVED_LOCK),
+    (Map::new("received_done"), RECEIVED_DONE),
+];
+
+// migrate brings the storage to CURRENT_STORAGE_VERSION and returns the version it started from.
+// Deployments without a recorded version predate it and are at version 1. wasm_admin is the admin
+// of the contract in the wasm module, it becomes the admin of deployments that had none
+pub fn migrate(store: &mut dyn Storage, wasm_admin: Option<Addr>) -> StdResult<u32> {
+    let version = STORAGE_VERSION.may_load(store)?.unwrap_or(1);
+    if version < 2 {
+        migrate_peer_ids(store)?;
+    }
+    if version < 3 {
+        drop_debug_maps(store)?;
+    }
+    if version < 4 {
+        if let Some(admin) = wasm_admin {
+            ADMIN.save(store, &admin)?;
+        }
+    }
+    STORAGE_VERSION.save(store, &CURRENT_STORAGE_VERSION)?;
+    Ok(version)
+}
+
+// migrate_peer_ids rekeys the chain indexes of version 1 to PeerIds, index i becoming "i"
+fn migrate_peer_ids(store: &mut dyn Storage) -> StdResult<()> {
+    let channels = rekey(store, LEGACY_CHANNELS, CHANNELS)?;
+    let members = rekey(store, LEGACY_MEMBERS, MEMBERS)?;
+    rekey(store, LEGACY_HIGHEST_REQ, HIGHEST_REQ)?;
+    rekey(store, LEGACY_HIGHEST_ABORT, HIGHEST_ABORT)?;
+    rekey(store, LEGACY_SEND_ALL_UPON, SEND_ALL_UPON)?;
+
+    convert_sets(store, LEGACY_RECEIVED, RECEIVED)?;
+    for (legacy, map) in LEGACY_VOTES {
+        convert_sets(store, legacy, map)?;
+    }
+
+    // the rotation is rebuilt from everyone we know of, which keeps the primary of the current view
+    let mut state = STATE.load(store)?;
+    state.members = channels.into_iter().chain(members).collect::<BTreeSet<_>>();
+    state.members.insert(state.chain_id.clone());
+    state.primary = state.leader(state.view);
+    STATE.save(store, &state)
+}
+
+// drop_debug_maps removes the debug maps of version 2, replaced by the bounded log of log.rs
+fn drop_debug_maps(store: &mut dyn Storage) -> StdResult<()> {
+    clear(store, LEGACY_DEBUG)?;
+    clear(store, LEGACY_IBC_MSG_SEND_DEBUG)?;
+    clear(store, LEGACY_DEBUG_RECEIVE_MSG)?;
+    LEGACY_DEBUG_CTR.remove(store);
+    Ok(())
+}
+
+// clear removes every entry of map, the values are never read
+fn clear<'a, K>(store: &mut dyn Storage, map: Map<'a, K, ()>) -> StdResult<()>
+where
+    K: PrimaryKey<'a> + KeyDeserialize<Output = K> + 'static,
+{
+    let keys: Vec<K> = map.keys(store, None, None, Order::Ascending).collect::<StdResult<_>>()?;
+    for key in keys {
+        map.remove(store, key);
+    }
+    Ok(())
+}
+
+// rekey moves the entries of legacy to map and returns the new keys
+fn rekey<V>(store: &mut dyn Storage, legacy: Map<u32, V>, map: Map<PeerId, V>) -> StdResult<Vec<PeerId>>
+where
+    V: Serialize + DeserializeOwned,
+{
+    let entries: Vec<(u32, V)> = legacy.range(store, None, None, Order::Ascending).collect::<StdResult<_>>()?;
+    let mut keys = Vec::new();
+    for (chain_id, value) in entries {
+        legacy.remove(store, chain_id);
+        map.save(store, chain_id.to_string(), &value)?;
+        keys.push(chain_id.to_string());
+    }
+    Ok(keys)
+}
+
+// convert_sets rewrites sets of chain indexes as sets of PeerIds, keys are unchanged
+fn convert_sets<K>(
+    store: &mut dyn Storage,
+    legacy: Map<'static, K, HashSet<u32>>,
+    map: Map<'static, K, HashSet<PeerId>>,
+) -> StdResult<()>
+where
+    K: PrimaryKey<'static> + KeyDeserialize<Output = K> + 'static,
+{
+    let entries: Vec<(K, HashSet<u32>)> = legacy.range(store, None, None, Order::Ascending).collect::<StdResult<_>>()?;
+    for (key, set) in entries {
+        map.save(store, key, &set.iter().map(|chain_id| chain_id.to_string()).collect())?;
+    }
+    Ok(())
+}
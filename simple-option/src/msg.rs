@@ -1,31 +1,81 @@
 use std::{collections::HashSet, fmt, str};
 
-use cosmwasm_std::{Timestamp, to_binary, Binary};
+use cosmwasm_std::{Addr, Timestamp, to_binary, Binary};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use crate::{ibc_msg::Msg, state::{State, InputType}};
+use crate::{ibc_msg::Msg, state::{opt_peer_id, State, InputType, Member, Handshake, PeerId, PacketRecord, AckRecord, BlockRecord, Metrics, MilestoneRecord, LogConfig, LogEntry, LogLevel, SignatureScheme, SubmissionPolicy}};
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct InstantiateMsg {
-    pub chain_id: u32,
+    /// Id of this chain among the members, the chain-id of the network when unset
+    #[serde(default, deserialize_with = "opt_peer_id")]
+    pub chain_id: Option<PeerId>,
     pub input: InputType,
     pub contract_addr: String,
+    /// Expected member chains (excluding this one). Counterparties are checked against it during the handshake
+    #[serde(default)]
+    pub members: Vec<Member>,
+    /// Maximum number of messages handled per transaction, the rest is kept as backlog. Must be positive
+    #[serde(default)]
+    pub processing_budget: Option<u32>,
+    /// Values with a larger Json encoding are sent as chunks of this many bytes
+    #[serde(default)]
+    pub value_chunk_size: Option<u32>,
+    /// Account allowed to change the configuration, the instantiating account when unset
+    #[serde(default)]
+    pub admin: Option<String>,
+    /// Shared by the members of the deployment, inputs name it in their domain
+    #[serde(default)]
+    pub deployment_id: String,
+    /// Restrictions on Input, anyone may submit any input when unset
+    #[serde(default)]
+    pub submission_policy: Option<SubmissionPolicy>,
+    /// Prefix of account addresses on this chain and on the members that don't set theirs, "wasm" when unset
+    #[serde(default)]
+    pub bech32_prefix: Option<String>,
     // pub msg: ContractExecuteMsg
 }
 
+/// Brings the storage of an earlier deployment to the current layout, see migrate::migrate
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MigrateMsg {}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum ExecuteMsg {
     Input { value: InputType },
+    /// PreInput resets the state to value without starting a view, only the admin may call it
     PreInput { value: InputType},
+    /// ForceAbort aborts the current view without waiting for its timeout, only the admin may call it
     ForceAbort {},
     Abort {},
+    /// Trigger runs one of the faulty behaviours of malicious_trigger.rs
+    #[cfg(feature = "testing")]
     Trigger { behavior: String },
+    /// Key3, Lock and Done deliver the message as if it came from local_channel_id, or from us for "None"
+    #[cfg(feature = "testing")]
     Key3 {val: InputType,view: u32,local_channel_id: String},
+    #[cfg(feature = "testing")]
     Lock {val: InputType,view: u32,local_channel_id: String},
+    #[cfg(feature = "testing")]
     Done {val: InputType,view: u32,local_channel_id: String},
+    /// SetContractAddr changes the contract decided values are executed on, only the admin may call it
     SetContractAddr {addr: String},
+    /// ProcessBacklog handles messages left over by earlier transactions, anyone may call it
+    ProcessBacklog {},
+    /// SetLogConfig changes the level and retention of the log, only the admin may call it. Retention is at most 10000
+    SetLogConfig { config: LogConfig },
+    /// SetParams changes the parameters that are set, only the admin may call it
+    SetParams {
+        processing_budget: Option<u32>,
+        value_chunk_size: Option<u32>,
+    },
+    /// SetSubmissionPolicy replaces the restrictions on Input, only the admin may call it
+    SetSubmissionPolicy { policy: SubmissionPolicy },
+    /// TransferAdmin offers the admin role to new_admin, who takes it over with AcceptAdmin
+    TransferAdmin { new_admin: String },
+    AcceptAdmin {},
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -35,11 +85,31 @@ pub enum QueryMsg {
     GetState { },
     GetStateProgress { },
     GetChannels { },
+    GetHandshakes { },
+    #[cfg(feature = "testing")]
     GetTest { },
     GetHighestReq { },
     GetHighestAbort { },
     GetReceivedSuggest { },
     GetSendAllUpon { },
+    GetBacklog { },
+    /// GetPeerHealth reports what we last heard from each peer, for alerting on silent chains
+    GetPeerHealth { },
+    /// Admin returns the admin and the account a transfer of the role is pending for
+    Admin { },
+    GetSubmissionPolicy { },
+    /// GetNonce returns the nonce address has to sign its next input with
+    GetNonce { address: String },
+    /// Diagnose explains what the current view waits for
+    Diagnose { },
+    /// GetMetrics returns the protocol counters of the current instance and since instantiation
+    GetMetrics { },
+    /// GetTimeline returns when each phase of each view of instance completed, the current instance when unset.
+    /// Only the last 16 instances are kept, older ones come back without views
+    GetTimeline {
+        instance: Option<u32>
+    },
+    #[cfg(feature = "testing")]
     GetTestQueue { },
     GetEcho { },
     GetKey1 { },
@@ -48,15 +118,30 @@ pub enum QueryMsg {
     GetLock { },
     GetDone { },
     GetAbortInfo { },
-    GetDebug { },
-    GetIbcDebug {},
-    GetDebugReceive {},
+    /// GetLogs pages through the log entries numbered since and later that are at least of level.
+    /// A page holds 1 to 100 entries and looks at no more than 1000, follow next until it is None
+    GetLogs {
+        level: Option<LogLevel>,
+        since: Option<u64>,
+        limit: Option<u32>,
+    },
     CheckSignature {
         val: InputType
     },
     GetAddress {
         val: InputType
-    }
+    },
+    /// DeriveAddresses returns the address of a key on this chain first, then on each other member, signer
+    /// addresses depend on the chain
+    DeriveAddresses {
+        public_key: Vec<u8>,
+        #[serde(default)]
+        scheme: SignatureScheme,
+    },
+    /// DecodePacket turns packet data of any encoding back into its Json form, for debugging
+    DecodePacket {
+        data: Binary
+    },
 }
 
 // We define a custom struct for each query response
@@ -87,51 +172,171 @@ pub enum StateResponse {
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct ChannelsResponse {
-    pub port_chan_pair: Vec<(u32,String)>
+    pub port_chan_pair: Vec<(PeerId,String)>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct HandshakesResponse {
+    pub handshakes: Vec<(String, Handshake)>
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct HighestReqResponse {
-    pub highest_request: Vec<(u32, u32)>
+    pub highest_request: Vec<(PeerId, u32)>
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct ReceivedSuggestResponse {
-    pub received_suggest: HashSet<u32>
+    pub received_suggest: HashSet<PeerId>
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct SendAllUponResponse {
-    pub send_all_upon: Vec<(u32, Vec<Msg>)>
+    pub send_all_upon: Vec<(PeerId, Vec<Msg>)>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct NonceResponse {
+    pub address: String,
+    pub next: u64,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct AddressesResponse {
+    pub addresses: Vec<MemberAddress>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MemberAddress {
+    pub chain_id: PeerId,
+    pub bech32_prefix: String,
+    pub address: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct AdminResponse {
+    pub admin: Addr,
+    /// Account the role was offered to and that has yet to accept it
+    pub pending_admin: Option<Addr>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct BacklogResponse {
+    pub depth: u32,
+    pub budget: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PeerHealthEntry {
+    pub chain_id: PeerId,
+    /// None until the handshake with the peer completed
+    pub channel_id: Option<String>,
+    pub last_received: Option<PacketRecord>,
+    pub last_ack: Option<AckRecord>,
+    pub last_timeout: Option<BlockRecord>,
+    /// Messages held back for the peer until it catches up
+    pub send_all_upon: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PeerHealthResponse {
+    pub peers: Vec<PeerHealthEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct QuorumStatus {
+    /// Message type the quorum is counted on
+    pub msg_type: String,
+    pub current: u32,
+    pub required: u32,
+    pub contributed: Vec<PeerId>,
+    pub missing: Vec<PeerId>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct HeldMessages {
+    pub chain_id: PeerId,
+    /// Latest view the peer sent Request for
+    pub highest_req: u32,
+    pub msg_types: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct DiagnoseResponse {
+    pub view: u32,
+    pub primary: PeerId,
+    pub phase: String,
+    /// None once decided
+    pub next_quorum: Option<QuorumStatus>,
+    /// Messages waiting in SEND_ALL_UPON for peers that haven't sent Request
+    pub held: Vec<HeldMessages>,
+    pub deadline: Timestamp,
+    pub deadline_passed: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MetricsResponse {
+    pub instance: Metrics,
+    pub total: Metrics,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ViewTimeline {
+    pub view: u32,
+    pub milestones: Vec<MilestoneRecord>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct TimelineResponse {
+    pub instance: u32,
+    pub views: Vec<ViewTimeline>,
+}
+
+#[cfg(feature = "testing")]
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct TestQueueResponse {
-    pub test_queue: Vec<(u32, Vec<(u32, Vec<Msg>)>)>
+    pub test_queue: Vec<(u32, Vec<(PeerId, Vec<Msg>)>)>
+}
+/// Voters of a value, keyed by the hash the vote maps use
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct VoteEntry {
+    pub hash: u64,
+    /// None when the value is missing from the registry, e.g. for votes cast before an upgrade
+    pub value: Option<VoteValue>,
+    pub voters: HashSet<PeerId>,
 }
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct VoteValue {
+    pub digest: String,
+    pub binary: String,
+    /// Address derived from the public key of the value, None if it isn't a valid key
+    pub signer: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct EchoQueryResponse { 
-    pub echo: Vec<(u64, HashSet<u32>)>
+    pub echo: Vec<VoteEntry>
 }
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct Key1QueryResponse { 
-    pub key1: Vec<(u64, HashSet<u32>)>
+    pub key1: Vec<VoteEntry>
 }
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct Key2QueryResponse { 
-    pub key2: Vec<(u64, HashSet<u32>)>
+    pub key2: Vec<VoteEntry>
 }
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct Key3QueryResponse { 
-    pub key3: Vec<(u64, HashSet<u32>)>
+    pub key3: Vec<VoteEntry>
 }
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct LockQueryResponse { 
-    pub lock: Vec<(u64, HashSet<u32>)>
+    pub lock: Vec<VoteEntry>
 }
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct DoneQueryResponse { 
-    pub done: Vec<(u64, HashSet<u32>)>
+    pub done: Vec<VoteEntry>
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -145,11 +350,14 @@ pub struct AbortResponse {
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
-pub struct DebugResponse { 
-    pub debug: Vec<(u32, String)>,
+pub struct LogsResponse {
+    pub config: LogConfig,
+    pub entries: Vec<LogEntry>,
+    /// Where the next page starts, None when there are no more entries
+    pub next: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct HighestAbortResponse {
-    pub highest_abort: Vec<(u32, i32)>
+    pub highest_abort: Vec<(PeerId, i32)>
 }
@@ -0,0 +1,123 @@
+use std::collections::BTreeMap;
+
+use cosmwasm_std::{IbcMsg, StdError, StdResult, Storage};
+
+use crate::context::Context;
+use crate::ibc_msg::{Msg, PacketMsg, WireMsg};
+use crate::log::log;
+use crate::state::{LogCategory, LogLevel, PeerId};
+#[cfg(feature = "testing")]
+use crate::state::TEST_QUEUE;
+use crate::utils::convert_send_ibc_msg;
+use crate::values::queue_to_wire;
+
+// Outbox collects the messages for other chains, keyed by peer.
+// Messages sent upon join are held per peer until that peer joins the current view, held messages
+// outlive the transaction (SEND_ALL_UPON) while queued ones are turned into packets at its end.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Outbox {
+    queued: BTreeMap<PeerId, Vec<Msg>>,
+    held: BTreeMap<PeerId, Vec<Msg>>,
+}
+
+impl Outbox {
+    pub fn new(held: BTreeMap<PeerId, Vec<Msg>>) -> Self {
+        Outbox { queued: BTreeMap::new(), held }
+    }
+
+    pub fn enqueue(&mut self, peer: PeerId, msg: Msg) {
+        self.queued.entry(peer).or_default().push(msg);
+    }
+
+    pub fn broadcast<'a>(&mut self, peers: impl IntoIterator<Item = &'a PeerId>, msg: &Msg) {
+        for peer in peers {
+            self.enqueue(peer.clone(), msg.clone());
+        }
+    }
+
+    // keeps msg for peer until release is called for it
+    pub fn hold(&mut self, peer: PeerId, msg: Msg) {
+        self.held.entry(peer).or_default().push(msg);
+    }
+
+    // queues whatever was held for peer
+    pub fn release(&mut self, peer: &str) {
+        if let Some(msgs) = self.held.remove(peer) {
+            let peer = peer.to_string();
+            self.queued.entry(peer).or_default().extend(msgs);
+        }
+    }
+
+    pub fn held(&self) -> &BTreeMap<PeerId, Vec<Msg>> {
+        &self.held
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queued.values().all(|msgs| msgs.is_empty())
+    }
+}
+
+// into_packets turns the queued messages into one MsgQueue packet per peer, plus one packet per chunk
+// of the values too large to travel in it.
+// The messages for reply_to are returned on the wire instead, to be piggybacked on the acknowledgement.
+pub fn into_packets(ctx: &mut Context, reply_to: Option<&str>) -> StdResult<(Vec<IbcMsg>, Vec<WireMsg>)> {
+    let queued = std::mem::take(&mut ctx.outbox.queued);
+    let mut msgs = Vec::new();
+    let mut replies = Vec::new();
+    for (peer, msg_queue) in queued {
+        if msg_queue.is_empty() {
+            continue;
+        }
+        #[cfg(feature = "testing")]
+        record_test_queue(&mut ctx.store, ctx.state.current_tx_id, &peer, &msg_queue)?;
+
+        // messages addressed to ourselves were already delivered through the inbox
+        if peer == ctx.state.chain_id {
+            continue;
+        }
+        for msg in &msg_queue {
+            ctx.metrics.count_sent(&peer, msg.name());
+        }
+        // chunks of oversized values travel in packets of their own, replies included
+        let (chunks, mut wire): (Vec<_>, Vec<_>) = queue_to_wire(&mut ctx.store, &msg_queue)?
+            .into_iter()
+            .partition(|msg| matches!(msg, Msg::ValueChunk { .. }));
+        let reply = reply_to == Some(peer.as_str());
+        if reply {
+            replies = std::mem::take(&mut wire);
+            if chunks.is_empty() {
+                continue;
+            }
+        }
+        let channel_id = ctx.channels.get(&peer).cloned()
+            .ok_or_else(|| StdError::not_found(format!("channel of chain {}", peer)))?;
+        log(ctx, LogLevel::Debug, LogCategory::Packet, format!(
+            "sending {} messages to {}, starting with {}", msg_queue.len(), peer, msg_queue[0].name()
+        ))?;
+        for chunk in chunks {
+            let packet = PacketMsg::MsgQueue(vec![chunk]);
+            msgs.push(convert_send_ibc_msg(&ctx.store, channel_id.clone(), packet, ctx.timeout.clone())?);
+        }
+        if !wire.is_empty() {
+            msgs.push(convert_send_ibc_msg(&ctx.store, channel_id, PacketMsg::MsgQueue(wire), ctx.timeout.clone())?);
+        }
+    }
+    ctx.metrics.packets_sent += msgs.len() as u64;
+    for msg in &msgs {
+        if let IbcMsg::SendPacket { data, .. } = msg {
+            ctx.metrics.bytes_sent += data.len() as u64;
+        }
+    }
+    //// TESTING ////
+    ctx.state.current_tx_id += 1;
+    //// TESTING ////
+    Ok((msgs, replies))
+}
+
+// keeps what each transaction sent for the test queue query
+#[cfg(feature = "testing")]
+fn record_test_queue(store: &mut dyn Storage, tx_id: u32, peer: &str, msg_queue: &[Msg]) -> StdResult<()> {
+    let mut sent = TEST_QUEUE.may_load(store, tx_id)?.unwrap_or_default();
+    sent.push((peer.to_string(), msg_queue.to_vec()));
+    TEST_QUEUE.save(store, tx_id, &sent)
+}
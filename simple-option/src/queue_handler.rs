@@ -1,6 +1,6 @@
 
 use cosmwasm_std::{
-    StdResult, IbcReceiveResponse, to_binary, IbcMsg, StdError, Storage, IbcTimeout, Env, wasm_execute, WasmMsg, Binary, SubMsg, Api
+    StdResult, IbcReceiveResponse, to_binary, StdError, WasmMsg, SubMsg
 };
 use serde_json::to_string;
 
@@ -8,54 +8,78 @@ use std::collections::HashSet;
 use std::convert::TryInto;
 use std::hash::Hash;
 
+use crate::context::{Context, Vote};
+use crate::state::{InputType, Milestone, PeerId, State, VOTE_VALUES};
+use crate::timeline::{check_request_quorum, record_milestone};
+use crate::events::{emit, EVENT_DECISION, EVENT_FAULT, EVENT_QUORUM};
+use crate::utils::{convert_send_ibc_msg, append_binary_string, clear_vote_values};
+use crate::replay::use_nonce;
+use crate::validation::verify_input;
 use crate::ContractError;
-use crate::state::{RECEIVED_DONE, InputType, TBInput};
-use crate::utils::{get_id_channel_pair_from_storage, get_chain_id, check_signature, append_binary_string, derive_addr_from_pubkey, get_and_increment_debug_ctr, debug_log};
+use crate::log::log;
 use crate::ibc_msg::{Msg,AcknowledgementMsg, MsgQueueResponse, PacketMsg};
 use crate::{state::{
-    HIGHEST_REQ, STATE, SEND_ALL_UPON, CHANNELS, TEST_QUEUE, TEST, RECEIVED, RECEIVED_ECHO, RECEIVED_KEY1, RECEIVED_KEY2, RECEIVED_KEY3,
-    DEBUG, RECEIVED_LOCK, DEBUG_RECEIVE_MSG, DEBUG_CTR, IBC_MSG_SEND_DEBUG
+    VALUES, LogCategory, LogLevel
 }, abort::handle_abort};
+#[cfg(feature = "testing")]
+use crate::state::TEST;
+use crate::outbox::into_packets;
+use crate::values::{receive_value, retire_values};
 
 // Handle Propose
 fn handle_propose(
-    store: &mut dyn Storage,
-    queue: &mut Vec<Vec<Msg>>,
-    timeout: IbcTimeout,
+    ctx: &mut Context,
     _local_channel_id: Option<String>,
-    chain_id: u32,
+    chain_id: PeerId,
     k: u32, 
     v: InputType,
     view: u32 ,
-    env: &Env,
-    api: &dyn Api,
 ) -> StdResult<()> {
-    let mut state = STATE.load(store)?;
-
     // ignore messages from other views, other than abort, done and request messages
-    if view != state.view {
+    if view != ctx.state.view {
+    } else if chain_id != ctx.state.primary {
+        emit(ctx, EVENT_FAULT, vec![
+            ("fault", "propose_from_non_primary".to_string()),
+            ("digest", v.digest()),
+            ("sender", chain_id),
+        ]);
     } else {
         // upon receiving the first propose message from a chain
-        if !state.received_propose && chain_id == state.primary {
+        if !ctx.state.received_propose && chain_id == ctx.state.primary {
             // RECEIVED_PROPOSE.save(store, chain_id, &true)?;
             let mut broadcast = false;
-            state.received_propose = true;
-            STATE.save(store, &state)?;
-            
+            ctx.state.received_propose = true;
+            record_milestone(ctx, Milestone::ProposeReceived)?;
+
+            // a primary proposing an invalid value is faulty, there is no point in waiting for the view to time out
+            if let Err(err) = verify_input(&ctx.store, ctx.api, &ctx.state, &v) {
+                log(ctx, LogLevel::Warn, LogCategory::Protocol, format!("primary {} proposed an invalid value: {}", chain_id, err))?;
+                emit(ctx, EVENT_FAULT, vec![
+                    ("fault", "invalid_proposal".to_string()),
+                    ("digest", v.digest()),
+                    ("sender", chain_id),
+                ]);
+                let abort_packet = Msg::Abort { view, chain_id: ctx.state.chain_id.clone() };
+                ctx.metrics.aborts += 1;
+                ctx.outbox.broadcast(ctx.channels.keys(), &abort_packet);
+                ctx.send_to_self(abort_packet);
+                return Ok(());
+            }
+
             // First case we should broadcast Echo message
-            if state.lock == 0 || v == state.lock_val {
+            if ctx.state.lock == 0 || v == ctx.state.lock_val {
                 broadcast = true;
-            } else if view > k && k >= state.lock {
+            } else if view > k && k >= ctx.state.lock {
                 // upon open_lock(proofs) == true
                 // Second case we should broadcast Echo message
-                if open_lock(store, state.proofs)? {
+                if open_lock(&ctx.state, &ctx.state.proofs) {
                     broadcast = true;
                 }
             }
             // send_all_upon_join_queue(<echo, k, v, view>)
             if broadcast {
                 let echo_packet = Msg::Echo { val: v, view };
-                send_all_upon_join_queue(store, queue, echo_packet, timeout, env, api)?;
+                send_all_upon_join_queue(ctx, echo_packet)?;
             }
             // send_all_upon_join_queue(<echo, k, v, view>)/
 
@@ -66,24 +90,19 @@ fn handle_propose(
 
 // Handle Request
 fn handle_request(
-    store: &mut dyn Storage,
-    queue: &mut Vec<Vec<Msg>>,
+    ctx: &mut Context,
     view: u32,
-    chain_id: u32,
-    api: &dyn Api,
+    chain_id: PeerId,
 ) -> StdResult<()> {
-    let mut state = STATE.load(store)?;
-
-    // state.key2_proofs.push((state.current_tx_id,"received_request".to_string(), chain_id as i32));
-    // STATE.save(store, &state)?;
     // Update stored highest_request for that blockchain accordingly
-    let highest_request = HIGHEST_REQ.load(store, chain_id)?;
+    let highest_request = ctx.highest_req(&chain_id);
     if highest_request < view {
-        HIGHEST_REQ.save(store, chain_id, &view)?;
+        ctx.highest_req.insert(chain_id.clone(), view);
             
+        let state = &mut ctx.state;
         if view == state.view {
             let packet = Msg::Suggest {
-                chain_id: state.chain_id,
+                chain_id: state.chain_id.clone(),
                 view: state.view,
                 key2: state.key2,
                 key2_val: state.key2_val.clone(),
@@ -95,21 +114,12 @@ fn handle_request(
             if chain_id == state.primary && !state.sent.contains(packet.name()) {
                 
                 state.sent.insert(packet.name().to_string());
-                STATE.save(store, &state)?;
-                queue[chain_id as usize].push(packet);
+                ctx.outbox.enqueue(chain_id.clone(), packet);
             }
 
             // Check if any pending send_all_upon_join
-            let packets = SEND_ALL_UPON.may_load(store, chain_id)?;
-            match packets {
-                Some(p) => {
-                    // Add to queue and remove from the buffer
-                    queue[chain_id as usize].extend(p);
-                    SEND_ALL_UPON.remove(store, chain_id);
-
-                },
-                None => (),
-            };
+            ctx.outbox.release(&chain_id);
+            check_request_quorum(ctx)?;
         }
     }
     Ok(())
@@ -117,89 +127,65 @@ fn handle_request(
 
 // Handle Suggest msg within MsgQueue
 fn handle_suggest(
-    store: &mut dyn Storage,
-    queue: &mut Vec<Vec<Msg>>,
-    timeout: IbcTimeout,
-    chain_id: u32,
+    ctx: &mut Context,
+    chain_id: PeerId,
     view: u32,
     key2: u32,
     key2_val: InputType,
     prev_key2: i32,
     key3: u32,
     key3_val: InputType,
-    env: &Env,
-    api: &dyn Api,
 ) -> StdResult<()> {
-    let mut state = STATE.load(store)?;
-
     // When I'm the primary
-    if state.primary == state.chain_id {
+    if ctx.state.primary == ctx.state.chain_id {
 
 
-        let mut receive_set= RECEIVED.load(store, "Suggest".to_string())?;
+        let receive_set = ctx.received.entry("Suggest".to_string()).or_default();
         // upon receiving the first suggest message from a chain
         if !receive_set.contains(&chain_id) {
             // Update the state
-            receive_set.insert(chain_id);
-            RECEIVED.save(store, "Suggest".to_string(), &receive_set)?;
+            receive_set.insert(chain_id.clone());
+            // only valid values may be proposed
+            let valid = match verify_input(&ctx.store, ctx.api, &ctx.state, &key3_val) {
+                Ok(_) => true,
+                Err(err) => {
+                    log(ctx, LogLevel::Warn, LogCategory::Protocol, format!("{} suggested an invalid value: {}", chain_id, err))?;
+                    emit(ctx, EVENT_FAULT, vec![
+                        ("fault", "invalid_suggestion".to_string()),
+                        ("digest", key3_val.digest()),
+                        ("sender", chain_id),
+                    ]);
+                    false
+                }
+            };
+            let state = &mut ctx.state;
             // Check if the following conditions hold
             if prev_key2 < key2 as i32 && key2 < view {
                 state.key2_proofs.push((key2, key2_val, prev_key2));
-                STATE.save(store, &state)?;
             }
-            if key3 == 0 {
+            if !valid {
+            } else if key3 == 0 {
                 state.suggestions.push((key3, key3_val));
-                STATE.save(store, &state)?;
             } else if key3 < view {
                 // Upon accept_key = true
                 if accept_key(key3, key3_val.clone(), state.key2_proofs.clone()) {
                     state.suggestions.push((key3, key3_val.clone()));
-                    STATE.save(store, &state)?;
                 }
             }
 
             // Check if |suggestions| >= n - f
             if !state.sent.contains("Propose") && state.suggestions.len() >= (state.n - state.F) as usize {
                 state.sent.insert("Propose".to_string());
-                STATE.save(store, &state)?;
                 // Retrive the entry with the largest k
                 let (k, v) = state.suggestions.iter().max_by(|x, y| y.0.cmp(&x.0)).unwrap();
                 let propose_packet = Msg::Propose {
-                    chain_id: state.chain_id,
+                    chain_id: state.chain_id.clone(),
                     k: k.clone(),
                     v: v.clone(),
                     view: state.view,
                 };
                 
-                send_all_upon_join_queue(store, queue, propose_packet, timeout, env, api)?;
-                /*
-
-                // send_all_upon_join_queue(<propose, k, v, view>)
-                let channel_ids = get_id_channel_pair(store)?;
-                // let state = STATE.load(store)?;
-                for (chain_id, _channel_id) in &channel_ids {
-                    let highest_request = HIGHEST_REQ.load(store, chain_id.clone())?;
-                    if highest_request == state.view {
-                        queue[*chain_id as usize].push(propose_packet.clone());
-
-                    }
-                    // Otherwise, we need the msg to be recorded in queue so that it could be triggered when condition satisfies
-                    else {
-                        let action = |packets: Option<Vec<Msg>>| -> StdResult<Vec<Msg>> {
-                            match packets {
-                                Some(mut p) => {
-                                    p.push(propose_packet.clone());
-                                    Ok(p)
-                                },
-                                None => Ok(vec!(propose_packet.clone())),
-                            }
-                            
-                        };
-                        SEND_ALL_UPON.update(store, *chain_id, action)?;
-                    }
-                }
-                */
-
+                send_all_upon_join_queue(ctx, propose_packet)?;
             }
             
         }
@@ -211,36 +197,24 @@ fn handle_suggest(
 
 // Handle Proof
 fn handle_proof(
-    store: &mut dyn Storage,
+    ctx: &mut Context,
     local_channel_id: Option<String>,
     key1: u32,
     key1_val: InputType,
     prev_key1: i32,
     view: u32,
-    _env: &Env,
-    api: &dyn Api,
 ) -> StdResult<()> {
-    let state = STATE.load(store)?;
     // detect if self-send
-    let chain_id = match local_channel_id.clone() {
-        Some(id) => {
-            // Get the chain_id of the sender
-            get_chain_id(store, id)
-        },
-        None => state.chain_id,
-    };
+    let chain_id = ctx.sender(&local_channel_id)?;
 
     // let received_proof = RECEIVED_PROOF.load(store, chain_id)?;
-    let mut receive_set= RECEIVED.load(store, "Proof".to_string())?;
+    let receive_set = ctx.received.entry("Proof".to_string()).or_default();
     if !receive_set.contains(&chain_id) {
         // Update the state
         receive_set.insert(chain_id);
-        RECEIVED.save(store, "Proof".to_string(), &receive_set)?;
         
         if view > key1 && key1 as i32 > prev_key1 {
-            let mut state = STATE.load(store)?;
-            state.proofs.push((key1, key1_val, prev_key1));
-            STATE.save(store, &state)?;
+            ctx.state.proofs.push((key1, key1_val, prev_key1));
         } 
         // if condition is met, update the proofs accordingly
         
@@ -251,27 +225,23 @@ fn handle_proof(
 
 // Handle Echo
 fn handle_echo(
-    store: &mut dyn Storage,
-    queue: &mut Vec<Vec<Msg>>,
-    timeout: IbcTimeout,
+    ctx: &mut Context,
     local_channel_id: Option<String>,
     val: InputType,
     view: u32,
-    env: &Env,
-    api: &dyn Api,
 ) -> StdResult<()> {
     let key1_packet = Msg::Key1 { val: val.clone(), view };
 
     // ignore messages from other views, other than abort, done and request messages
     // if this condition holds, we have received Echo from n - f parties on same val
-    if message_transfer_hop(store, val.clone(), view, queue, RECEIVED_ECHO, key1_packet.clone(), timeout.clone(), local_channel_id.clone(), env, api)? {
-        let mut state = STATE.load(store)?;
+    if message_transfer_hop(ctx, val.clone(), view, Vote::Echo, key1_packet, local_channel_id)? {
+        record_milestone(ctx, Milestone::EchoQuorum)?;
+        let state = &mut ctx.state;
         if state.key1_val != val {
             state.prev_key1 = state.key1 as i32;
             state.key1_val = val;                    
         }
         state.key1 = view;
-        STATE.save(store, &state)?; 
     }
     
     Ok(())
@@ -279,27 +249,23 @@ fn handle_echo(
 
 // Handle Key1
 fn handle_key1(
-    store: &mut dyn Storage,
-    queue: &mut Vec<Vec<Msg>>,
-    timeout: IbcTimeout,
+    ctx: &mut Context,
     local_channel_id: Option<String>,
     val: InputType,
     view: u32,
-    env: &Env,
-    api: &dyn Api,
 ) -> StdResult<()> {
 
  
     // ignore messages from other views, other than abort, done and request messages
     let key2_packet = Msg::Key2 { val: val.clone(), view };
-    if message_transfer_hop(store, val.clone(), view, queue, RECEIVED_KEY1, key2_packet.clone(), timeout.clone(), local_channel_id.clone(), env, api)? {
-        let mut state = STATE.load(store)?;
+    if message_transfer_hop(ctx, val.clone(), view, Vote::Key1, key2_packet, local_channel_id)? {
+        record_milestone(ctx, Milestone::Key1Quorum)?;
+        let state = &mut ctx.state;
         if state.key2_val != val {
             state.prev_key2 = state.key2 as i32;
             state.key2_val = val;                    
         }
         state.key2 = view;
-        STATE.save(store, &state)?; 
     }
     
     Ok(())
@@ -307,21 +273,16 @@ fn handle_key1(
 
 // Handle Key2
 fn handle_key2(
-    store: &mut dyn Storage,
-    queue: &mut Vec<Vec<Msg>>,
-    timeout: IbcTimeout,
+    ctx: &mut Context,
     local_channel_id: Option<String>,
     val: InputType,
     view: u32,
-    env: &Env,
-    api: &dyn Api,
 ) -> StdResult<()> {
     let key3_packet = Msg::Key3 { val: val.clone(), view };
-    if message_transfer_hop(store, val.clone(), view, queue, RECEIVED_KEY2, key3_packet.clone(),timeout.clone(), local_channel_id.clone(), env, api)? {
-        let mut state = STATE.load(store)?;
-        state.key3 = view;
-        state.key3_val = val.clone();
-        STATE.save(store, &state)?;    
+    if message_transfer_hop(ctx, val.clone(), view, Vote::Key2, key3_packet, local_channel_id)? {
+        record_milestone(ctx, Milestone::Key2Quorum)?;
+        ctx.state.key3 = view;
+        ctx.state.key3_val = val;
     }
 
     Ok(())
@@ -329,171 +290,237 @@ fn handle_key2(
 
 // Handle Key3
 fn handle_key3(
-    store: &mut dyn Storage,
-    queue: &mut Vec<Vec<Msg>>,
-    timeout: IbcTimeout,
+    ctx: &mut Context,
     local_channel_id: Option<String>,
     val: InputType,
     view: u32,
-    env: &Env,
-    api: &dyn Api,
 ) -> StdResult<()> {
     let lock_packet = Msg::Lock { val: val.clone(), view }; 
 
-    DEBUG.save(store, 33330, &queue.len().to_string())?;
-    if message_transfer_hop(store, val.clone(), view, queue, RECEIVED_KEY3, lock_packet.clone(), timeout.clone(), local_channel_id.clone(),env, api)? {
-        let mut state = STATE.load(store)?;
-        state.lock = view;
-        state.lock_val = val;
-        STATE.save(store, &state)?;    
-        DEBUG.save(store, 33333, &"HANDLE_KEY_3_TRUE".to_string())?;
-    } else {
-        DEBUG.save(store, 3333, &"HANDLE_KEY_3_FALSE".to_string())?;
+    if message_transfer_hop(ctx, val.clone(), view, Vote::Key3, lock_packet, local_channel_id)? {
+        record_milestone(ctx, Milestone::Key3Quorum)?;
+        log(ctx, LogLevel::Debug, LogCategory::Protocol, format!("locked {} in view {}", val.digest(), view))?;
+        ctx.state.lock = view;
+        ctx.state.lock_val = val;
     }
     Ok(())
 }
 
 // Handle Lock
 fn handle_lock(
-    store: &mut dyn Storage,
-    queue: &mut Vec<Vec<Msg>>,
-    timeout: IbcTimeout,
+    ctx: &mut Context,
     local_channel_id: Option<String>,
     val: InputType,
     view: u32,
-    env: &Env,
-    api: &dyn Api,
-) -> Result<Vec<SubMsg>, ContractError> {        
+) -> StdResult<()> {        
     let done_packet = Msg::Done { val: val.clone() };
     // ignore messages from other views, other than abort, done and request messages
-    // upon receiving from n - f parties with the same val
-    let result = message_transfer_hop(store, val.clone(), view, queue, RECEIVED_LOCK, 
-                                            done_packet.clone(), timeout.clone(), local_channel_id.clone(), env, api)?;
-
-    // handle self-execute done
-    // Since 
-    if result {
-        let mut vec_msgs:Vec<SubMsg> = Vec::new();
-        let mut state = STATE.load(store).unwrap();
-        if state.done.is_some() && !state.done_executed && check_signature(api, val.clone()){
-            DEBUG.save(store, 7777777, &"EXECUTED ME AFTER LOCK!!!!!".to_string())?;
-            let address = derive_addr_from_pubkey(&val.public_key).unwrap();
-            let appended_binary = append_binary_string(val.binary, &"tb_user".to_string(), &address.to_string());
-            let stringified_binary = appended_binary.to_string();
-            let wasm_msg = WasmMsg::Execute{
-                contract_addr: state.contract_addr.to_string(),
-                msg: appended_binary,
-                funds: vec![]
-            };
-            let sub_msg: SubMsg = SubMsg::reply_always(wasm_msg, 1234);    
-            state.done_timestamp = Some(env.block.time);
-            state.done_block_height = Some(env.block.height);
-            state.done_executed = true;
-            STATE.save(store, &state)?;
-            vec_msgs.push(sub_msg);
-            return Ok(vec_msgs);    
-        }
+    // upon receiving from n - f parties with the same val, we send <done, val> to every party
+    // including ourselves, the decision is taken by handle_done
+    if message_transfer_hop(ctx, val, view, Vote::Lock, done_packet, local_channel_id)? {
+        record_milestone(ctx, Milestone::LockQuorum)?;
     }
-
-    // send_all_party(store, queue, done_packet, timeout.clone())?;
-    return Ok(Vec::new());
+    Ok(())
 }
 
 // Handle Done
 fn handle_done(
-    store: &mut dyn Storage,
-    queue: &mut Vec<Vec<Msg>>,
-    timeout: IbcTimeout,
+    ctx: &mut Context,
     local_channel_id: Option<String>,
     val: InputType,
-    env: &Env,
-    api: &dyn Api,
-) -> Result<Vec<SubMsg>, ContractError> {   
-    let mut state = STATE.load(store).unwrap();
+) -> StdResult<Vec<SubMsg>> {   
+    let view = ctx.state.view;
 
     // upon receiving from n - f parties with the same val
-    if message_transfer_hop(store, val.clone(), state.view, queue, RECEIVED_DONE, Msg::Done { val: val.clone() }, timeout.clone(), local_channel_id.clone(), env, api).unwrap() {
+    if message_transfer_hop(ctx, val.clone(), view, Vote::Done, Msg::Done { val: val.clone() }, local_channel_id)? {
         // decide and terminate
-        state.done = Some(val.clone());
+        ctx.state.done = Some(val.clone());
+        clear_vote_values(&mut ctx.store)?;
+        retire_values(&mut ctx.store, Some(&val))?;
+        record_milestone(ctx, Milestone::Done)?;
+        emit(ctx, EVENT_DECISION, vec![("digest", val.digest())]);
         let mut vec_msgs:Vec<SubMsg> = Vec::new();
 
-        // Only handle if it is not self send..., self send case is handled in handle_lock...
-        if !local_channel_id.is_none() && !state.done_executed && check_signature(api, val.clone()){
-            DEBUG.save(store, 7777777, &"EXECUTED ME HELLO!!!!!".to_string())?;
+        let verified = verify_input(&ctx.store, ctx.api, &ctx.state, &val);
+        if let (false, Ok(address)) = (ctx.state.done_executed, &verified) {
+            use_nonce(&mut ctx.store, address.clone(), val.nonce)?;
+            let state = &mut ctx.state;
             state.done_executed = true;
-            let address = derive_addr_from_pubkey(&val.public_key).unwrap();
             let appended_binary = append_binary_string(val.binary, &"tb_user".to_string(), &address.to_string());
             let stringified_binary = appended_binary.to_string();
-            state.done_timestamp = Some(env.block.time);
-            state.done_block_height = Some(env.block.height);
+            state.done_timestamp = Some(ctx.env.block.time);
+            state.done_block_height = Some(ctx.env.block.height);
 
+            let contract_addr = state.contract_addr.to_string();
             let wasm_msg = WasmMsg::Execute{
-                contract_addr: state.contract_addr.to_string(),
+                contract_addr: contract_addr.clone(),
                 msg: appended_binary,
                 funds: vec![]
             };
-            DEBUG.save(store, 777777777, &stringified_binary)?;
+            log(ctx, LogLevel::Info, LogCategory::Execution, format!("executing {} on {}", stringified_binary, contract_addr))?;
             let sub_msg = SubMsg::reply_always(wasm_msg, 1234);    
             vec_msgs.push(sub_msg)
-        } else if(!check_signature(api, val.clone())) {
-            DEBUG.save(store, 777777777, &"FALSE SIG NOT DONE".to_string())?;    
-            //return Err(ContractError::CustomError { val: "INVALID SIGNATURE".to_string() });
+        } else if let Err(err) = verified {
+            log(ctx, LogLevel::Warn, LogCategory::Execution, format!("decided {} is not executed: {}", val.digest(), err))?;
+            let fault = match err {
+                ContractError::InvalidSignature {} => "invalid_signature",
+                ContractError::ThresholdNotMet { .. } => "threshold_not_met",
+                ContractError::InvalidAddress { .. } => "invalid_address",
+                ContractError::WrongDomain { .. } => "wrong_domain",
+                ContractError::NonceReused { .. } => "nonce_reused",
+                _ => "invalid_input",
+            };
+            emit(ctx, EVENT_FAULT, vec![("fault", fault.to_string()), ("digest", val.digest())]);
         }
-        STATE.save(store, &state)?;
         return Ok(vec_msgs);
     }
     return Ok(Vec::new());
 }
 
+// receive_queue processes a queue of messages received on local_channel_id and
+// turns the messages produced for other chains into packets. The caller commits ctx,
+// or drops it when an error is returned so that a failing queue leaves no trace in the protocol state.
 pub fn receive_queue(
-    store: &mut dyn Storage,
-    timeout: IbcTimeout,
+    ctx: &mut Context,
     local_channel_id: Option<String>,
     queue_to_process: Vec<Msg>,
-    queue: &mut Vec<Vec<Msg>>,
-    env: &Env,
-    api: &dyn Api,
+    // whether messages for the sender of the queue may be returned in the acknowledgement
+    piggyback: bool,
 ) -> StdResult<IbcReceiveResponse> {
-    // let mut queue: Vec<Vec<Msg>> = vec!(Vec::new(); state.n.try_into().unwrap());
-    if let Some(_) = STATE.load(store)?.done {
+    // once done, only requests for values are still served
+    if ctx.state.done.is_some() && !queue_to_process.iter().any(|msg| matches!(msg, Msg::FetchValue { .. })) {
         return Ok(IbcReceiveResponse::new())
     }
+    let wasm_exec_messages = process_queue(ctx, local_channel_id.clone(), queue_to_process)?;
+
+    let mut res = IbcReceiveResponse::new();
+    if ctx.state.done.is_some() && !wasm_exec_messages.is_empty() {
+        res = res.add_submessages(wasm_exec_messages);
+    }
+
+    match local_channel_id {
+        Some(_) => {
+            // After handling all msgs in queue sucessfully
+            // Generate msg queue to send
+
+            let reply_to = match (&local_channel_id, piggyback) {
+                (Some(id), true) => Some(ctx.chain_id_of(id)?),
+                _ => None,
+            };
+            let (msgs, replies) = into_packets(ctx, reply_to.as_deref())?;
+
+            let acknowledgement = to_binary(&AcknowledgementMsg::Ok(MsgQueueResponse { replies }))?;            
+            // Add to Response if there are pending messages
+            if msgs.len() > 0 {
+                #[cfg(feature = "testing")]
+                TEST.save(&mut ctx.store, ctx.state.current_tx_id, &msgs)?;
+                res = res.add_messages(msgs);
+            }
+                    
+            Ok(res
+                .set_ack(acknowledgement)
+                .add_events(std::mem::take(&mut ctx.events))
+                .add_attribute("action", "receive_msg_queue")
+                .add_attribute("backlog", ctx.inbox.len().to_string()))
+        },
+        None => { 
+            Ok(res.set_ack(b"{}")
+                .add_events(std::mem::take(&mut ctx.events))
+                .add_attribute("action", "ibc_packet_ack"))
+        }
+    }
+}
+
+// process_queue appends queue_to_process to the inbox and runs it
+fn process_queue(
+    ctx: &mut Context,
+    local_channel_id: Option<String>,
+    queue_to_process: Vec<Msg>,
+) -> StdResult<Vec<SubMsg>> {
+    ctx.inbox.extend(queue_to_process.into_iter().map(|msg| (local_channel_id.clone(), msg)));
+    Ok(run_inbox(ctx)?)
+}
+
+/// The message run_inbox failed on, as it was in the inbox. It is the one after the first `handled` messages,
+/// so replaying those from the same state leaves it at the front of the inbox
+#[derive(Debug)]
+pub struct InboxError {
+    pub handled: u32,
+    pub entry: Box<(Option<String>, Msg)>,
+    pub error: StdError,
+}
+
+impl InboxError {
+    pub fn msg_type(&self) -> &'static str {
+        self.entry.1.name()
+    }
+}
+
+impl From<InboxError> for StdError {
+    fn from(failure: InboxError) -> Self {
+        failure.error
+    }
+}
+
+impl From<InboxError> for ContractError {
+    fn from(failure: InboxError) -> Self {
+        ContractError::Std(failure.error)
+    }
+}
+
+// run_inbox handles the messages of ctx.inbox in order and returns the wasm messages to execute.
+// Messages we send to ourselves are appended to the inbox by the handlers, so self-delivery is a loop
+// rather than a recursion. Every message handled takes one from ctx.budget, once it is used up the rest
+// stays in the inbox and is committed as backlog for the next transaction. The first error stops processing
+// and is returned, the caller then drops or rolls back ctx so that none of the writes of the queue are stored.
+pub fn run_inbox(ctx: &mut Context) -> Result<Vec<SubMsg>, InboxError> {
     let mut wasm_exec_messages: Vec<SubMsg> = Vec::new();
+    let mut handled = 0;
 
-    for msg in queue_to_process {
-        let msg_string = msg.name().to_string();
+    while ctx.budget > 0 {
+        let (local_channel_id, msg) = match ctx.inbox.pop_front() {
+            Some(next) => next,
+            None => break,
+        };
+        ctx.budget -= 1;
+        handled += 1;
+        // once done, only requests for values are still served
+        if ctx.state.done.is_some() && !matches!(msg, Msg::FetchValue { .. }) {
+            continue;
+        }
+        let msg_type = msg.name();
+        let entry = Box::new((local_channel_id.clone(), msg.clone()));
 
-        let chain_id_debug: String = match local_channel_id.clone() {
+        let chain_id_debug: String = match &local_channel_id {
             Some(id) => {
                 // Get the chain_id of the sender
-                format!("IBC-{}",get_chain_id(store, id))
+                match ctx.chain_id_of(id) {
+                    Ok(chain_id) => {
+                        ctx.metrics.count_received(&chain_id, msg_type);
+                        format!("IBC-{}", chain_id)
+                    },
+                    Err(_) => id.clone(),
+                }
             },
             None => "SELF".to_string(),
         };
 
 
-        debug_log(store, &format!("RECV QUEUE... {} From... {}", msg_string, chain_id_debug));
-
-
-        // TODO skip...
-        // let key = msg.name().to_string();
-        // if(RECEIVED.load(store,key)?.contains(local_channel_id.unwrap()?)) {
-        //     continue;
-        // }
-        let result: StdResult<()> = match msg {
+        let result: StdResult<()> = log(ctx, LogLevel::Trace, LogCategory::Protocol, format!("handling {} from {}", msg_type, chain_id_debug))
+            .and_then(|_| match msg {
             Msg::Propose {
                 chain_id,
                 k,
                 v,
                 view,
             } => { 
-                handle_propose(store, queue, timeout.clone(), local_channel_id.clone(), chain_id, k, v, view, env, api) 
+                handle_propose(ctx, local_channel_id.clone(), chain_id, k, v, view) 
             },
             Msg::Request { 
                 view, 
                 chain_id 
             } => {
-                handle_request(store, queue, view, chain_id,api)
+                handle_request(ctx, view, chain_id)
             },
             Msg::Suggest {
                 chain_id,
@@ -504,7 +531,7 @@ pub fn receive_queue(
                 key3,
                 key3_val,
             } => { 
-                handle_suggest(store, queue, timeout.clone(), chain_id,view, key2, key2_val, prev_key2, key3, key3_val, env, api)
+                handle_suggest(ctx, chain_id, view, key2, key2_val, prev_key2, key3, key3_val)
             },
             Msg::Proof {
                 key1,
@@ -512,136 +539,97 @@ pub fn receive_queue(
                 prev_key1,
                 view,
             } => { 
-                handle_proof(store, local_channel_id.clone(), key1, key1_val, prev_key1, view,env,api)
+                handle_proof(ctx, local_channel_id.clone(), key1, key1_val, prev_key1, view)
             },
             Msg::Echo { val, view } => { 
-                handle_echo(store, queue, timeout.clone(), local_channel_id.clone(), val, view,env,api)
+                handle_echo(ctx, local_channel_id.clone(), val, view)
             },
-            Msg::Key1 { val, view } => handle_key1(store, queue, timeout.clone(), local_channel_id.clone(), val, view,env,api),
-            Msg::Key2 { val, view } => handle_key2(store, queue, timeout.clone(), local_channel_id.clone(), val, view,env,api),
+            Msg::Key1 { val, view } => handle_key1(ctx, local_channel_id.clone(), val, view),
+            Msg::Key2 { val, view } => handle_key2(ctx, local_channel_id.clone(), val, view),
             Msg::Key3 { val, view } => {
                 handle_key3(
-                    store, queue, timeout.clone(), local_channel_id.clone(), val, view,env, api
+                    ctx, local_channel_id.clone(), val, view
             )},
-            Msg::Lock { val, view } => {
-                // DEBUG_RECEIVE_MSG.update(store, "handle_lock".to_string(), | mut state| -> Result<_, ContractError> {
-                //     match state {
-                //         Some(mut vec) => {
-                //             vec.push(val.clone().binary);
-                //             Ok(vec)
-                //         },
-                //         None => {
-                //             Ok(vec![val.clone().binary])
-                //         }
-                //     }
-                // });                            
-                wasm_exec_messages = handle_lock(store, queue, timeout.clone(), local_channel_id.clone(), val, view,env,api).unwrap();
-                Ok(())
-            },
+            Msg::Lock { val, view } => handle_lock(ctx, local_channel_id.clone(), val, view),
             Msg::Done { val } => { 
-                wasm_exec_messages = handle_done(store, queue, timeout.clone(), local_channel_id.clone(), val,env,api).unwrap();
-                Ok(())
+                handle_done(ctx, local_channel_id.clone(), val).map(|msgs| wasm_exec_messages.extend(msgs))
             }
             Msg::Abort { view, chain_id } => 
             {
-                DEBUG.save(store, 200+chain_id, &"RECEIVED_ABORT".to_string())?;
-                handle_abort(store, queue, view, chain_id, timeout.clone(), env, api)
+                log(ctx, LogLevel::Debug, LogCategory::Abort, format!("abort of view {} from {}", view, chain_id))
+                    .and_then(|_| handle_abort(ctx, view, chain_id))
             },
-        };
-        
-        // unwrap the result to handle any errors
-        match result {
-            Ok(res) => (),
-            Err(err) => {
-                debug_log(store,  &format!("RESULT ERROR Rocessing {} From... {} Error {}", msg_string, chain_id_debug, err));        
-                let mut res = IbcReceiveResponse::new();
-                return Ok(res.set_ack(b"{}")
-                .add_attribute("action", "ibc_packet_ack"));
+            Msg::FetchValue { digest } => handle_fetch_value(ctx, local_channel_id.clone(), digest),
+            Msg::Value { val } => {
+                // messages that were waiting for this value are processed right after
+                match &local_channel_id {
+                    Some(id) => receive_value(&mut ctx.store, ctx.env.block.time, id, val).map(|msgs| {
+                        ctx.inbox.extend(msgs.into_iter().map(|msg| (Some(id.clone()), msg)))
+                    }),
+                    None => Ok(()),
+                }
             },
-        };
+            // chunks are reassembled by values::resolve_queue and never reach the inbox
+            Msg::ValueChunk { .. } => Ok(()),
+        });
+
+        if let Err(error) = result {
+            return Err(InboxError { handled: handled - 1, entry, error });
+        }
     }
 
+    Ok(wasm_exec_messages)
+}
 
-    let mut res = IbcReceiveResponse::new();
-    let mut state = STATE.load(store)?;
-    if let Some(val) = state.done {
-        if wasm_exec_messages.len() > 0 {
-            DEBUG.save(store, 88888888, &"EXECUTED ME HELLO OUTSIDE!!!!!".to_string())?;
-            res = res.add_submessages(wasm_exec_messages);
+// run_backlog handles what is left in the inbox from previous transactions, on its own before anything new
+// joins it, and commits ctx. A message of the backlog that fails is dropped instead of failing the caller,
+// or every later transaction would fail on it too: ctx goes back to its last commit, the messages before
+// the failing one are handled again, it is removed and the rest of the backlog goes on.
+// The failures are returned for the caller to report.
+pub fn run_backlog(ctx: &mut Context) -> StdResult<(Vec<SubMsg>, Vec<InboxError>)> {
+    let mut wasm_exec_messages = Vec::new();
+    let mut dropped = Vec::new();
+    while !ctx.inbox.is_empty() && ctx.budget > 0 {
+        match run_inbox(ctx) {
+            Ok(msgs) => wasm_exec_messages.extend(msgs),
+            Err(failure) => {
+                ctx.rollback();
+                let budget = ctx.budget;
+                ctx.budget = failure.handled;
+                wasm_exec_messages.extend(run_inbox(ctx)?);
+                if ctx.inbox.front() != Some(&*failure.entry) {
+                    return Err(failure.error);
+                }
+                ctx.inbox.pop_front();
+                ctx.budget = budget - failure.handled - 1;
+                log(ctx, LogLevel::Error, LogCategory::Protocol, format!(
+                    "dropped {} from the backlog: {}", failure.msg_type(), failure.error
+                ))?;
+                dropped.push(failure);
+            },
         }
-    }   
+        ctx.commit()?;
+    }
+    Ok((wasm_exec_messages, dropped))
+}
 
-    match local_channel_id {
-        Some(_) => {
-            // After handling all msgs in queue sucessfully
-            // Generate msg queue to send
-            let mut msgs = Vec::new();
-            // let timeout = get_timeout(env);
-            DEBUG.save(store, 300, &"LOCAL_CHANNEL_ID".to_string())?;
-
-            //// TESTING /////
-            let state = STATE.load(store)?;
-            let mut i = 0;
-            for (chain_id, msg_queue) in queue.iter().enumerate() {
-                //// TESTING /////
-                let chain_msg_pair = (chain_id as u32, msg_queue.to_vec());
-                let action = |packets: Option<Vec<_>>| -> StdResult<Vec<_>> {
-                    match packets {
-                        Some(mut p) => {
-                            p.push(chain_msg_pair.clone());
-                            Ok(p)
-                        },
-                        None => Ok(vec!(chain_msg_pair.clone())),
-                    }
-                };
-                TEST_QUEUE.update(store, state.current_tx_id, action)?;
-                //// TESTING /////
-
-                if chain_id != state.chain_id as usize {
-                    // When chain wish to send some msgs to dest chain
-                    if msg_queue.len() > 0 {
-                        let channel_id = CHANNELS.load(store, chain_id.try_into().unwrap())?;
-                        i = i+1;
-                        let first_msg_name = msg_queue[0].name();
-                        let debug_str = format!("{} {} FIRST MESSAGE LEN {} TO CHAIN_ID: {}" , 
-                                                        "SEND_PACKET QUEUE SIZE", msg_queue.len(), first_msg_name, chain_id);   
-                        DEBUG.save(store, 400+i, &debug_str)?;
-                        let msg = IbcMsg::SendPacket {
-                            channel_id,
-                            data: to_binary(&PacketMsg::MsgQueue ( msg_queue.to_vec() ) )?,
-                            timeout: timeout.clone(),
-                        };
-                        msgs.push(msg);
-                    }
-                }
-            }
-            //// TESTING ////
-            let mut state = STATE.load(store)?;
-            state.current_tx_id += 1;
-            STATE.save(store, &state)?;
-            //// TESTING ////
 
-            let acknowledgement = to_binary(&AcknowledgementMsg::Ok(MsgQueueResponse { }))?;            
-            // Add to Response if there are pending messages
-            if msgs.len() > 0 {
-                TEST.save(store, state.current_tx_id, &msgs)?;
-                // state.current_tx_id += 1;
-                STATE.save(store, &state)?;
-                res = res.add_messages(msgs);
-            }
-                    
-            Ok(res
-                .set_ack(acknowledgement)
-                .add_attribute("action", "receive_msg_queue"))
-        },
-        None => { 
-            Ok(res.set_ack(b"{}")
-                .add_attribute("action", "ibc_packet_ack"))
+// Handle FetchValue, answering with the full value if we know it
+fn handle_fetch_value(
+    ctx: &mut Context,
+    local_channel_id: Option<String>,
+    digest: String,
+) -> StdResult<()> {
+    if let Some(id) = local_channel_id {
+        if let Some(val) = VALUES.may_load(&ctx.store, digest)? {
+            let chain_id = ctx.chain_id_of(&id)?;
+            ctx.metrics.retransmissions += 1;
+            ctx.outbox.enqueue(chain_id, Msg::Value { val });
         }
     }
+    Ok(())
 }
 
-
 fn accept_key(key: u32, value: InputType, proofs: Vec<(u32, InputType, i32)>) -> bool {
     let mut supporting = 0;
     for (k, v, pk) in proofs {
@@ -658,87 +646,78 @@ fn accept_key(key: u32, value: InputType, proofs: Vec<(u32, InputType, i32)>) ->
 }
 
 
-fn open_lock(store: &mut dyn Storage, proofs: Vec<(u32, InputType, i32)>) -> StdResult<bool> {
+fn open_lock(state: &State, proofs: &[(u32, InputType, i32)]) -> bool {
     let mut supporting: u32 = 0;
-    let state = STATE.load(store)?;
     for (k, v, pk) in proofs {
-        if (state.lock as i32) <= pk {
+        if (state.lock as i32) <= *pk {
             supporting += 1;
-        } else if state.lock <= k && v != state.lock_val {
+        } else if state.lock <= *k && *v != state.lock_val {
             supporting += 1;
         }
     }
-    if supporting >= (state.F + 1) {
-        Ok(true)
-    } else {
-        Ok(false)
-    }
+    supporting >= (state.F + 1)
 }
 
 fn message_transfer_hop(
-    storage: &mut dyn Storage, 
+    ctx: &mut Context,
     val: InputType, 
     view: u32,
-    queue: &mut Vec<Vec<Msg>>, 
-    message_type: cw_storage_plus::Map<u64, HashSet<u32>>, 
+    vote: Vote,
     msg_to_send: Msg, 
-    timeout: IbcTimeout, 
     channel_id: Option<String>, 
-    env: &Env,
-    api: &dyn Api
 ) -> Result<bool, StdError> {
-        let state = STATE.load(storage)?;
         // ignore messages from other views, other than abort, done and request messages
-        if view != state.view && message_type.namespace() != "received_done".as_bytes(){
+        if view != ctx.state.view && vote != Vote::Done {
             return Ok(false);
         }
         // detect if self-send
-        let chain_id = match channel_id {
-            Some(id) => {
-                // Get the chain_id of the sender
-                get_chain_id(storage, id)
-            },
-            None => state.chain_id,
-        };
+        let chain_id = ctx.sender(&channel_id)?;
         // Initialize local record of messages of type key
-        let action = |set: Option<HashSet<u32>>| -> StdResult<HashSet<u32>> {
-            match set {
-                Some(set) => Ok(set),
-                None => Ok(HashSet::new()),
-            }
-        };
-        let val_hash = val.calculate_hash();
-        let mut set = message_type.update(storage, val_hash, action)?;
+        let val_hash = val.clone().calculate_hash();
+        // the registry lets queries show the value behind the hash
+        if !ctx.votes(vote).contains_key(&val_hash) && !VOTE_VALUES.has(&ctx.store, val_hash) {
+            VOTE_VALUES.save(&mut ctx.store, val_hash, &val)?;
+        }
+        // a chain voting for two values in the same view is faulty
+        let equivocates = ctx.votes(vote).iter().any(|(hash, voters)| *hash != val_hash && voters.contains(&chain_id));
+        if equivocates && vote != Vote::Done {
+            emit(ctx, EVENT_FAULT, vec![
+                ("fault", "equivocation".to_string()),
+                ("msg_type", format!("{:?}", vote)),
+                ("digest", val.digest()),
+                ("sender", chain_id.clone()),
+            ]);
+        }
+        let set = ctx.votes(vote).entry(val_hash).or_default();
         if !set.contains(&chain_id) {
-            set.insert(chain_id);
-            message_type.save(storage, val_hash, &set)?;
+            set.insert(chain_id.clone());
+            let received = set.len();
+            let state = &mut ctx.state;
 
             // If received Done, operate accordingly
-            if message_type.namespace() == "received_done".as_bytes() {
+            if vote == Vote::Done {
                 // check if have not sent Done && received from f + 1 parties 
-                if !state.sent.contains(msg_to_send.name()) && set.len() >= (state.F + 1).try_into().unwrap() {
-                    let mut state = STATE.load(storage)?;
+                if !state.sent.contains(msg_to_send.name()) && received >= (state.F + 1).try_into().unwrap() {
                     state.sent.insert(msg_to_send.name().to_string());
-                    STATE.save(storage, &state)?;
-                    send_all_party(storage, queue, msg_to_send, timeout.clone(), env, api)?;
+                    send_all_party(ctx, msg_to_send)?;
                 }
                 // upon receiving from n - f parties with the same val
-                if set.len() >= (state.n - state.F).try_into().unwrap() {
+                if received >= (ctx.state.n - ctx.state.F).try_into().unwrap() {
+                    emit_quorum(ctx, vote, &val, chain_id, received);
                     return Ok(true);
                 }
                 return Ok(false);
             } else {
                 // upon receiving from n - f parties with the same val
-                if !state.sent.contains(msg_to_send.name()) && set.len() >= (state.n - state.F).try_into().unwrap() {
-                    let mut state = STATE.load(storage)?;
+                if !state.sent.contains(msg_to_send.name()) && received >= (state.n - state.F).try_into().unwrap() {
                     state.sent.insert(msg_to_send.name().to_string());
-                    STATE.save(storage, &state)?;
+                    emit_quorum(ctx, vote, &val, chain_id, received);
                     // if received Lock, ensure we send <done, val> to every party
-                    if message_type.namespace() == "received_lock".as_bytes() {
-                        send_all_party(storage, queue, msg_to_send, timeout, env, api)?;
+                    if vote == Vote::Lock {
+                        send_all_party(ctx, msg_to_send)?;
                         return Ok(true);
                     } else {
-                        send_all_upon_join_queue(storage, queue, msg_to_send, timeout, env, api)?;
+                        send_all_upon_join_queue(ctx, msg_to_send)?;
                         return Ok(true);    
                     }
                 } else {
@@ -749,52 +728,226 @@ fn message_transfer_hop(
         Ok(false)
     }
 
+// emit_quorum reports that sender completed the quorum of vote on val
+fn emit_quorum(ctx: &mut Context, vote: Vote, val: &InputType, sender: PeerId, received: usize) {
+    emit(ctx, EVENT_QUORUM, vec![
+        ("msg_type", format!("{:?}", vote)),
+        ("digest", val.digest()),
+        ("sender", sender),
+        ("count", received.to_string()),
+    ]);
+}
+
 // send_all_upon_join_queue Operation
-pub fn send_all_upon_join_queue(storage: &mut dyn Storage, queue: &mut Vec<Vec<Msg>>, packet_msg: Msg, timeout: IbcTimeout, env: &Env, api: &dyn Api) -> Result<(), StdError> {
-    let state = STATE.load(storage)?;
-    let channel_ids = get_id_channel_pair_from_storage(storage)?;
+pub fn send_all_upon_join_queue(ctx: &mut Context, packet_msg: Msg) -> Result<(), StdError> {
     // self-send msg
-    receive_queue(storage, timeout, None, vec![packet_msg.clone()], queue, env, api)?;
+    ctx.send_to_self(packet_msg.clone());
 
-    for (chain_id, _channel_id) in &channel_ids {
-        let highest_request = HIGHEST_REQ.load(storage, chain_id.clone())?;
-        if highest_request == state.view {
-            //DEBUG.save(storage, 10000000+chain_id, &chain_id.to_string())?;
+    let chain_ids: Vec<PeerId> = ctx.channels.keys().cloned().collect();
+    for chain_id in chain_ids {
+        let highest_request = ctx.highest_req(&chain_id);
+        if highest_request == ctx.state.view {
+            log(ctx, LogLevel::Trace, LogCategory::Packet, format!("sending {} to {}", packet_msg.name(), chain_id))?;
 
-            debug_log(storage, &format!("Send All Upon Join Packet INSTANT {} TO IBC-{} ", &packet_msg.name(), chain_id));
-
-            queue[*chain_id as usize].push(packet_msg.clone());
+            ctx.outbox.enqueue(chain_id, packet_msg.clone());
         } else {
             // Otherwise, we need the msg to be recorded in queue so that it could be triggered when condition satisfies
-            let action = |packets: Option<Vec<Msg>>| -> StdResult<Vec<Msg>> {
-                match packets {
-                    Some(mut p) => {
-                        p.push(packet_msg.clone());
-                        Ok(p)
-                    },
-                    None => Ok(vec!(packet_msg.clone())),
-                }
-                
-            };
-            debug_log( storage, &format!("Send All Upon Join Packet SEND_UPON_JOIN {} TO IBC-{} ", &packet_msg.name(), chain_id));
+            log(ctx, LogLevel::Trace, LogCategory::Packet, format!("holding {} for {} until it joins the view", packet_msg.name(), chain_id))?;
 
-            SEND_ALL_UPON.update(storage, *chain_id, action)?;
+            ctx.outbox.hold(chain_id, packet_msg.clone());
         }
     }
     Ok(())
 }
 
-pub fn send_all_party(store: &mut dyn Storage, queue: &mut Vec<Vec<Msg>>, packet: Msg, timeout: IbcTimeout, env: &Env, api: &dyn Api) -> Result<(), StdError> {
-    let channel_ids = get_id_channel_pair_from_storage(store)?;
+pub fn send_all_party(ctx: &mut Context, packet: Msg) -> Result<(), StdError> {
     // self-send msg
-    receive_queue(store, timeout, None, vec![packet.clone()], queue, env, api)?;
+    ctx.send_to_self(packet.clone());
 
-    for (chain_id, _channel_id) in &channel_ids {
-
-        debug_log(store,  &format!("Send All Party {} TO IBC-{} ", &packet.name(), chain_id));
-    
-        queue[*chain_id as usize].push(packet.clone());
-    }
+    log(ctx, LogLevel::Trace, LogCategory::Packet, format!("sending {} to all", packet.name()))?;
+    ctx.outbox.broadcast(ctx.channels.keys(), &packet);
     
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::{ContractResult, CosmosMsg, IbcReceiveResponse, Order};
+    use cw_storage_plus::Map;
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+
+    use crate::ibc_msg::{ValueRef, WireMsg};
+    use crate::replay::query_nonce;
+    use crate::signature::signer;
+    use crate::state::{HIGHEST_ABORT, HIGHEST_REQ, RECEIVED_ECHO, STATE};
+    use crate::test_utils::{ack, deliver, failing_msg, setup, signed_input, submit, TestDeps};
+
+    use super::*;
+
+    fn entries<V: Serialize + DeserializeOwned>(deps: &TestDeps, map: Map<u64, V>) -> usize {
+        map.keys(&deps.storage, None, None, Order::Ascending).count()
+    }
+
+    fn full(val: &InputType) -> ValueRef {
+        ValueRef::Full(val.clone())
+    }
+
+    fn next_nonce(deps: &TestDeps, val: &InputType) -> u64 {
+        let address = signer(&deps.api, "wasm", val).unwrap().address;
+        query_nonce(&deps.storage, address).unwrap().next
+    }
+
+    // chain 2 sends its own Done after a Lock quorum and has the Done of chain 0, the Done of chain 1 decides
+    fn one_done_short() -> (TestDeps, InputType) {
+        let val = signed_input(7, 0);
+        let mut deps = setup(2);
+        submit(&mut deps, val.clone());
+        for from in [0, 1, 3] {
+            deliver(&mut deps, from, vec![Msg::Lock { val: full(&val), view: 0 }]);
+        }
+        deliver(&mut deps, 0, vec![Msg::Done { val: full(&val) }]);
+        assert!(STATE.load(&deps.storage).unwrap().done.is_none());
+        (deps, val)
+    }
+
+    #[test]
+    fn done_executes_and_uses_the_nonce() {
+        let (mut deps, val) = one_done_short();
+        let res = deliver(&mut deps, 1, vec![Msg::Done { val: full(&val) }]);
+        assert!(matches!(ack(&res), ContractResult::Ok(_)));
+        assert!(matches!(&res.messages[0].msg, CosmosMsg::Wasm(WasmMsg::Execute { .. })));
+        assert!(STATE.load(&deps.storage).unwrap().done_executed);
+        assert_eq!(next_nonce(&deps, &val), 1);
+    }
+
+    #[test]
+    fn decision_retires_the_values_of_the_instance() {
+        let (mut deps, val) = one_done_short();
+        let other = signed_input(8, 0);
+        deliver(&mut deps, 3, vec![Msg::Echo { val: full(&other), view: 0 }]);
+        assert!(VALUES.has(&deps.storage, other.digest()));
+
+        deliver(&mut deps, 1, vec![Msg::Done { val: full(&val) }]);
+        // the decided value is kept for the peers still fetching it
+        let values: Vec<String> = VALUES.keys(&deps.storage, None, None, Order::Ascending).map(Result::unwrap).collect();
+        assert_eq!(values, vec![val.digest()]);
+        assert_eq!(entries(&deps, VOTE_VALUES), 0);
+    }
+
+    #[test]
+    fn failing_packet_keeps_the_nonce() {
+        let (mut deps, val) = one_done_short();
+        let failing = failing_msg(&mut deps);
+        let res = deliver(&mut deps, 1, vec![Msg::Done { val: full(&val) }, failing]);
+
+        // the decision is rolled back along with the nonce, the Done of chain 1 can be delivered again
+        assert!(matches!(ack(&res), ContractResult::Err(_)));
+        assert!(res.messages.is_empty());
+        let state = STATE.load(&deps.storage).unwrap();
+        assert!(state.done.is_none());
+        assert!(!state.done_executed);
+        assert_eq!(next_nonce(&deps, &val), 0);
+    }
+
+    #[test]
+    fn request_of_a_chain_without_entry_is_handled() {
+        // no input yet, so highest_req has no entries
+        let mut deps = setup(2);
+        let res = deliver(&mut deps, 1, vec![Msg::Request { view: 1, chain_id: "1".to_string() }]);
+        assert!(matches!(ack(&res), ContractResult::Ok(_)));
+        assert_eq!(HIGHEST_REQ.load(&deps.storage, "1".to_string()).unwrap(), 1);
+    }
+
+    #[test]
+    fn failing_message_rolls_back_the_packet() {
+        let val = signed_input(7, 0);
+        let mut deps = setup(2);
+        submit(&mut deps, val.clone());
+        let handled: Vec<WireMsg> = vec![
+            Msg::Request { view: 1, chain_id: "1".to_string() },
+            Msg::Echo { val: full(&val), view: 0 },
+        ];
+        let failing = failing_msg(&mut deps);
+        let res = deliver(&mut deps, 1, [handled.clone(), vec![failing]].concat());
+        assert!(matches!(ack(&res), ContractResult::Err(_)));
+        assert_eq!(HIGHEST_REQ.load(&deps.storage, "1".to_string()).unwrap(), 0);
+        assert_eq!(entries(&deps, RECEIVED_ECHO), 0);
+        assert_eq!(entries(&deps, VOTE_VALUES), 0);
+
+        // without it the same messages are handled
+        let res = deliver(&mut deps, 1, handled);
+        assert!(matches!(ack(&res), ContractResult::Ok(_)));
+        assert_eq!(HIGHEST_REQ.load(&deps.storage, "1".to_string()).unwrap(), 1);
+        assert_eq!(entries(&deps, RECEIVED_ECHO), 1);
+        assert_eq!(entries(&deps, VOTE_VALUES), 1);
+    }
+
+    fn tampered(val: &InputType) -> InputType {
+        let mut val = val.clone();
+        val.signature[0] ^= 1;
+        val
+    }
+
+    fn propose(val: &InputType) -> WireMsg {
+        // chain 1 is the primary of view 0
+        Msg::Propose { chain_id: "1".to_string(), k: 0, v: full(val), view: 0 }
+    }
+
+    fn faults(res: &IbcReceiveResponse) -> Vec<String> {
+        res.events
+            .iter()
+            .filter(|event| event.ty == EVENT_FAULT)
+            .flat_map(|event| event.attributes.iter().filter(|attr| attr.key == "fault").map(|attr| attr.value.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn invalid_proposal_aborts_the_view_without_echo() {
+        let val = signed_input(7, 0);
+        let mut deps = setup(2);
+        submit(&mut deps, val.clone());
+        let res = deliver(&mut deps, 1, vec![propose(&tampered(&val))]);
+        assert!(matches!(ack(&res), ContractResult::Ok(_)));
+        assert_eq!(faults(&res), vec!["invalid_proposal"]);
+        assert_eq!(entries(&deps, RECEIVED_ECHO), 0);
+        assert_eq!(HIGHEST_ABORT.load(&deps.storage, "2".to_string()).unwrap(), 0);
+
+        // the valid value is echoed
+        let mut deps = setup(2);
+        submit(&mut deps, val.clone());
+        let res = deliver(&mut deps, 1, vec![propose(&val)]);
+        assert!(faults(&res).is_empty());
+        assert_eq!(entries(&deps, RECEIVED_ECHO), 1);
+        assert_eq!(HIGHEST_ABORT.load(&deps.storage, "2".to_string()).unwrap(), -1);
+    }
+
+    #[test]
+    fn invalid_suggestions_are_left_out() {
+        let val = signed_input(7, 0);
+        let suggest = |chain_id: &str, val: &InputType| -> WireMsg {
+            Msg::Suggest {
+                chain_id: chain_id.to_string(),
+                view: 0,
+                key2: 0,
+                key2_val: full(val),
+                prev_key2: -1,
+                key3: 0,
+                key3_val: full(val),
+            }
+        };
+        // chain 1 is the primary of view 0
+        let mut deps = setup(1);
+        submit(&mut deps, val.clone());
+        let suggestions = |deps: &TestDeps| STATE.load(&deps.storage).unwrap().suggestions;
+        let before = suggestions(&deps);
+
+        let res = deliver(&mut deps, 0, vec![suggest("0", &tampered(&val))]);
+        assert!(matches!(ack(&res), ContractResult::Ok(_)));
+        assert_eq!(faults(&res), vec!["invalid_suggestion"]);
+        assert_eq!(suggestions(&deps), before);
+
+        deliver(&mut deps, 2, vec![suggest("2", &val)]);
+        assert_eq!(suggestions(&deps), [before, vec![(0, val)]].concat());
+    }
+}
@@ -0,0 +1,49 @@
+// Replay protection for signed inputs.
+//
+// An input is only executed on the deployment named by its domain, and at most once: each signer
+// numbers its inputs, executing one moves the next nonce of the signer past it. Inputs are checked
+// again when executed, since the value decided may come from another chain.
+
+use std::collections::BTreeSet;
+
+use cosmwasm_std::{StdResult, Storage};
+
+use crate::msg::NonceResponse;
+use crate::state::{InputType, State, DEPLOYMENT_ID, NONCES};
+use crate::ContractError;
+
+// check_nonce fails unless signer didn't use nonce yet
+pub fn check_nonce(store: &dyn Storage, signer: &str, nonce: u64) -> Result<(), ContractError> {
+    let next = NONCES.may_load(store, signer.to_string())?.unwrap_or_default();
+    if nonce < next {
+        return Err(ContractError::NonceReused { signer: signer.to_string(), nonce, next });
+    }
+    Ok(())
+}
+
+// check_domain fails unless input names our deployment, our members and our target contract
+pub fn check_domain(store: &dyn Storage, state: &State, input: &InputType) -> Result<(), ContractError> {
+    let wrong_domain = |reason: &str| Err(ContractError::WrongDomain { reason: reason.to_string() });
+    let domain = &input.domain;
+    if domain.deployment_id != DEPLOYMENT_ID.may_load(store)?.unwrap_or_default() {
+        return wrong_domain("deployment_id differs");
+    }
+    let chains: BTreeSet<_> = domain.targets.iter().map(|target| &target.chain_id).collect();
+    if chains.len() != domain.targets.len() || !chains.into_iter().eq(state.members.iter()) {
+        return wrong_domain("targets don't name each member once");
+    }
+    if !domain.targets.iter().any(|target| target.chain_id == state.chain_id && target.contract == state.contract_addr.as_str()) {
+        return wrong_domain("target contract differs");
+    }
+    Ok(())
+}
+
+// use_nonce records that signer executed nonce, the earlier nonces are given up
+pub fn use_nonce(store: &mut dyn Storage, signer: String, nonce: u64) -> StdResult<()> {
+    NONCES.save(store, signer, &(nonce + 1))
+}
+
+pub fn query_nonce(store: &dyn Storage, address: String) -> StdResult<NonceResponse> {
+    let next = NONCES.may_load(store, address.clone())?.unwrap_or_default();
+    Ok(NonceResponse { address, next })
+}
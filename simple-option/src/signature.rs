@@ -0,0 +1,463 @@
+// Signature schemes of user inputs.
+//
+// secp256k1 inputs are signed the way Cosmos wallets sign, over the sha256 of sign_bytes. ed25519 inputs
+// are signed over sign_bytes themselves, and eth_personal_sign inputs the way Ethereum wallets sign a
+// message with personal_sign. With either secp256k1 scheme public_key may be left empty, the signature
+// is then the 65 byte r | s | v and the key is recovered from it. The signer address depends on the
+// scheme, it is the identity inputs are executed, numbered and rate limited under. Addresses are bech32
+// with the prefix of the chain, an Ethereum key has the bech32 form of its Ethereum address, as on
+// Ethermint chains.
+//
+// A multisig input is signed by at least threshold of its keys, each with its own scheme. Its address is
+// derived from the threshold and every key, whichever of them signed, and its keys are never recovered.
+
+use cosmwasm_std::{Api, StdError, StdResult, Storage};
+use sha2::{Digest, Sha256};
+use sha3::Keccak256;
+
+use crate::msg::{AddressesResponse, MemberAddress};
+use crate::state::{InputType, Multisig, MultisigKey, SignatureScheme, STATE};
+use crate::utils::{bech32_address, bech32_prefix, derive_addr_from_pubkey, local_bech32_prefix};
+use crate::ContractError;
+
+const SIGNATURE_LEN: usize = 64;
+const RECOVERABLE_SIGNATURE_LEN: usize = 65;
+const COMPRESSED_KEY_LEN: usize = 33;
+const UNCOMPRESSED_KEY_LEN: usize = 65;
+const ED25519_KEY_LEN: usize = 32;
+const ETH_MESSAGE_PREFIX: &[u8] = b"\x19Ethereum Signed Message:\n";
+/// Most keys a multisig may have
+pub const MAX_MULTISIG_KEYS: usize = 16;
+
+/// Who signed an input, with the key the signature verifies against. The key of a multisig is the
+/// encoding its address is derived from
+#[derive(Debug)]
+pub struct Signer {
+    pub address: String,
+    pub public_key: Vec<u8>,
+}
+
+// check_lengths fails unless the keys and the signatures of input have lengths their schemes allow
+pub fn check_lengths(input: &InputType) -> Result<(), ContractError> {
+    match &input.multisig {
+        None => check_credential(input.scheme, &input.public_key, &input.signature),
+        Some(multisig) => {
+            if !input.public_key.is_empty() || !input.signature.is_empty() {
+                return malformed("public key and signature must be empty for a multisig");
+            }
+            check_multisig(multisig)
+        }
+    }
+}
+
+fn malformed(reason: &str) -> Result<(), ContractError> {
+    Err(ContractError::MalformedInput { reason: reason.to_string() })
+}
+
+fn check_credential(scheme: SignatureScheme, public_key: &[u8], signature: &[u8]) -> Result<(), ContractError> {
+    if public_key.is_empty() && scheme != SignatureScheme::Ed25519 {
+        if signature.len() != RECOVERABLE_SIGNATURE_LEN || recovery_param(signature[SIGNATURE_LEN]).is_none() {
+            return malformed("signature must be 65 bytes ending with a recovery id when the public key is omitted");
+        }
+        return Ok(());
+    }
+    check_key(scheme, public_key)?;
+    if signature.len() != SIGNATURE_LEN {
+        return malformed("signature must be 64 bytes");
+    }
+    Ok(())
+}
+
+fn check_key(scheme: SignatureScheme, public_key: &[u8]) -> Result<(), ContractError> {
+    let key_len = public_key.len();
+    match scheme {
+        SignatureScheme::Ed25519 if key_len != ED25519_KEY_LEN => malformed("public key must be a 32 byte ed25519 key"),
+        SignatureScheme::Secp256k1 if key_len != COMPRESSED_KEY_LEN && key_len != UNCOMPRESSED_KEY_LEN => {
+            malformed("public key must be a compressed or uncompressed secp256k1 key")
+        }
+        SignatureScheme::EthPersonalSign if key_len != UNCOMPRESSED_KEY_LEN => malformed("public key must be an uncompressed secp256k1 key"),
+        _ => Ok(()),
+    }
+}
+
+fn check_multisig(multisig: &Multisig) -> Result<(), ContractError> {
+    let keys = &multisig.keys;
+    if keys.len() > MAX_MULTISIG_KEYS {
+        return malformed("multisig has too many keys");
+    }
+    if multisig.threshold == 0 || multisig.threshold as usize > keys.len() {
+        return malformed("threshold must be between 1 and the number of keys");
+    }
+    if multisig.signatures.len() != keys.len() {
+        return malformed("multisig needs one signature per key, empty when the key didn't sign");
+    }
+    for (i, (key, signature)) in keys.iter().zip(&multisig.signatures).enumerate() {
+        // a key that didn't sign still makes up the address, so keys can't be omitted and recovered
+        check_key(key.scheme, &key.public_key)?;
+        if keys[..i].iter().any(|other| same_key(other, key)) {
+            return malformed("multisig keys must be distinct");
+        }
+        if !signature.is_empty() {
+            check_credential(key.scheme, &key.public_key, signature)?;
+        }
+    }
+    Ok(())
+}
+
+// same_key tells whether two well formed keys are one, whatever their encoding. Both secp256k1 schemes
+// use the same keys, so a key counts once even when it signs as Secp256k1 and as EthPersonalSign
+fn same_key(a: &MultisigKey, b: &MultisigKey) -> bool {
+    match (a.scheme, b.scheme) {
+        (SignatureScheme::Ed25519, SignatureScheme::Ed25519) => a.public_key == b.public_key,
+        (SignatureScheme::Ed25519, _) | (_, SignatureScheme::Ed25519) => false,
+        _ => compress(&a.public_key) == compress(&b.public_key),
+    }
+}
+
+// verify_signature fails unless input is signed as its scheme says and returns the signer, with an
+// address for prefix
+pub fn verify_signature(api: &dyn Api, prefix: &str, input: &InputType) -> Result<Signer, ContractError> {
+    check_lengths(input)?;
+    let msg = input.sign_bytes();
+    let verified = match &input.multisig {
+        Some(multisig) => {
+            let mut signed = 0;
+            for (key, signature) in multisig.keys.iter().zip(&multisig.signatures) {
+                if signature.is_empty() {
+                    continue;
+                }
+                if !verify(api, key.scheme, &key.public_key, signature, &msg) {
+                    return Err(ContractError::InvalidSignature {});
+                }
+                signed += 1;
+            }
+            if signed < multisig.threshold {
+                return Err(ContractError::ThresholdNotMet { signed, threshold: multisig.threshold });
+            }
+            true
+        }
+        // a recovered key is the one the signature verifies against
+        None if input.public_key.is_empty() => true,
+        None => verify(api, input.scheme, &input.public_key, &input.signature, &msg),
+    };
+    if !verified {
+        return Err(ContractError::InvalidSignature {});
+    }
+    signer(api, prefix, input)
+}
+
+// verify checks one signature over msg, malformed keys and signatures don't verify either
+fn verify(api: &dyn Api, scheme: SignatureScheme, public_key: &[u8], signature: &[u8], msg: &[u8]) -> bool {
+    match scheme {
+        SignatureScheme::Ed25519 => api.ed25519_verify(msg, signature, public_key).unwrap_or(false),
+        scheme => api.secp256k1_verify(&message_hash(scheme, msg), signature, public_key).unwrap_or(false),
+    }
+}
+
+// signer returns who signed input without verifying the signature, a key is only recovered when omitted
+pub fn signer(api: &dyn Api, prefix: &str, input: &InputType) -> Result<Signer, ContractError> {
+    if let Some(multisig) = &input.multisig {
+        let public_key = multisig_key(multisig);
+        let address = bech32_address(&Sha256::digest(&public_key)[..20], prefix)?.to_string();
+        return Ok(Signer { address, public_key });
+    }
+    let public_key = if input.public_key.is_empty() && input.scheme != SignatureScheme::Ed25519 {
+        recover_key(api, input)?
+    } else {
+        input.public_key.clone()
+    };
+    let address = key_address(input.scheme, &public_key, prefix)?;
+    Ok(Signer { address, public_key })
+}
+
+// key_address derives the account address of a key for prefix
+pub fn key_address(scheme: SignatureScheme, public_key: &[u8], prefix: &str) -> Result<String, ContractError> {
+    let address = match scheme {
+        SignatureScheme::Secp256k1 => derive_addr_from_pubkey(&compress(public_key), prefix)?,
+        SignatureScheme::Ed25519 => bech32_address(&Sha256::digest(public_key)[..20], prefix)?,
+        SignatureScheme::EthPersonalSign => bech32_address(&eth_address(public_key)?, prefix)?,
+    };
+    Ok(address.to_string())
+}
+
+// query_addresses derives the address of a key on this chain, with the local prefix, then on every other
+// member with the prefix of the member
+pub fn query_addresses(store: &dyn Storage, scheme: SignatureScheme, public_key: &[u8]) -> StdResult<AddressesResponse> {
+    let to_std = |err: ContractError| StdError::generic_err(err.to_string());
+    check_key(scheme, public_key).map_err(to_std)?;
+    let state = STATE.load(store)?;
+    let mut members = vec![(state.chain_id.clone(), local_bech32_prefix(store)?)];
+    for chain_id in state.members.iter().filter(|chain_id| **chain_id != state.chain_id).cloned() {
+        let bech32_prefix = bech32_prefix(store, &chain_id)?;
+        members.push((chain_id, bech32_prefix));
+    }
+    let mut addresses = Vec::new();
+    for (chain_id, bech32_prefix) in members {
+        let address = key_address(scheme, public_key, &bech32_prefix).map_err(to_std)?;
+        addresses.push(MemberAddress { chain_id, bech32_prefix, address });
+    }
+    Ok(AddressesResponse { addresses })
+}
+
+// multisig_key is "multisig" | threshold: u32 | (scheme: u8 | len: u32 | public_key)*, integers big endian
+fn multisig_key(multisig: &Multisig) -> Vec<u8> {
+    let mut out = b"multisig".to_vec();
+    out.extend_from_slice(&multisig.threshold.to_be_bytes());
+    for key in &multisig.keys {
+        out.push(key.scheme.tag());
+        out.extend_from_slice(&(key.public_key.len() as u32).to_be_bytes());
+        out.extend_from_slice(&key.public_key);
+    }
+    out
+}
+
+// message_hash is what a secp256k1 scheme signs
+fn message_hash(scheme: SignatureScheme, msg: &[u8]) -> Vec<u8> {
+    match scheme {
+        SignatureScheme::EthPersonalSign => {
+            let mut hasher = Keccak256::new();
+            hasher.update(ETH_MESSAGE_PREFIX);
+            hasher.update(msg.len().to_string());
+            hasher.update(msg);
+            hasher.finalize().to_vec()
+        }
+        _ => Sha256::digest(msg).to_vec(),
+    }
+}
+
+// recovery_param accepts both the raw recovery id and the 27 + id form of Ethereum wallets
+fn recovery_param(v: u8) -> Option<u8> {
+    match v {
+        0 | 1 => Some(v),
+        27 | 28 => Some(v - 27),
+        _ => None,
+    }
+}
+
+fn recover_key(api: &dyn Api, input: &InputType) -> Result<Vec<u8>, ContractError> {
+    if input.signature.len() != RECOVERABLE_SIGNATURE_LEN {
+        return Err(ContractError::InvalidSignature {});
+    }
+    let param = recovery_param(input.signature[SIGNATURE_LEN]).ok_or(ContractError::InvalidSignature {})?;
+    let hash = message_hash(input.scheme, &input.sign_bytes());
+    api.secp256k1_recover_pubkey(&hash, &input.signature[..SIGNATURE_LEN], param)
+        .map_err(|_| ContractError::InvalidSignature {})
+}
+
+// compress turns an uncompressed secp256k1 key into the compressed form account addresses are derived from
+fn compress(public_key: &[u8]) -> Vec<u8> {
+    if public_key.len() != UNCOMPRESSED_KEY_LEN {
+        return public_key.to_vec();
+    }
+    let mut compressed = vec![2 | (public_key[UNCOMPRESSED_KEY_LEN - 1] & 1)];
+    compressed.extend_from_slice(&public_key[1..COMPRESSED_KEY_LEN]);
+    compressed
+}
+
+// eth_address is the 20 byte Ethereum address of a key, the end of the keccak256 of the uncompressed key
+fn eth_address(public_key: &[u8]) -> Result<Vec<u8>, ContractError> {
+    if public_key.len() != UNCOMPRESSED_KEY_LEN {
+        return Err(ContractError::MalformedInput { reason: "public key must be an uncompressed secp256k1 key".to_string() });
+    }
+    let hash = Keccak256::digest(&public_key[1..]);
+    Ok(hash[12..].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::testing::MockApi;
+    use k256::ecdsa::signature::Signer as _;
+    use k256::ecdsa::{recoverable, Signature, SigningKey};
+    use k256::elliptic_curve::sec1::ToEncodedPoint;
+
+    use crate::test_utils::{signing_key, unsigned_input, BINARY};
+    use crate::utils::{from_hex, to_hex};
+
+    use super::*;
+
+    fn compressed_key(seed: u8) -> Vec<u8> {
+        signing_key(seed).verifying_key().to_bytes().to_vec()
+    }
+
+    fn uncompressed_key(seed: u8) -> Vec<u8> {
+        signing_key(seed).verifying_key().to_encoded_point(false).as_bytes().to_vec()
+    }
+
+    fn secp_key(public_key: Vec<u8>) -> MultisigKey {
+        MultisigKey { scheme: SignatureScheme::Secp256k1, public_key }
+    }
+
+    // a multisig input of keys, signed by the seeds given for each of them, None leaving the slot empty
+    fn multisig_input(threshold: u32, keys: Vec<MultisigKey>, signers: &[Option<u8>]) -> InputType {
+        let mut input = unsigned_input(1, 0, BINARY);
+        input.public_key = vec![];
+        input.multisig = Some(Multisig { threshold, keys, signatures: vec![vec![]; signers.len()] });
+        let msg = input.sign_bytes();
+        let signatures = signers
+            .iter()
+            .map(|seed| seed.map_or(vec![], |seed| {
+                let signature: Signature = signing_key(seed).sign(&msg);
+                signature.as_ref().to_vec()
+            }))
+            .collect();
+        input.multisig.as_mut().unwrap().signatures = signatures;
+        input
+    }
+
+    fn two_of_three(signers: &[Option<u8>]) -> InputType {
+        let keys = (1..4).map(|seed| secp_key(compressed_key(seed))).collect();
+        multisig_input(2, keys, signers)
+    }
+
+    #[test]
+    fn multisig_verifies_with_threshold_signatures() {
+        let api = MockApi::default();
+        let signer = verify_signature(&api, "wasm", &two_of_three(&[Some(1), Some(2), None])).unwrap();
+        assert!(signer.address.starts_with("wasm1"));
+        // whichever keys signed, the address is the one of the multisig
+        let other = verify_signature(&api, "wasm", &two_of_three(&[None, Some(2), Some(3)])).unwrap();
+        assert_eq!(signer.address, other.address);
+    }
+
+    #[test]
+    fn multisig_below_threshold_is_refused() {
+        let err = verify_signature(&MockApi::default(), "wasm", &two_of_three(&[None, Some(2), None])).unwrap_err();
+        assert!(matches!(err, ContractError::ThresholdNotMet { signed: 1, threshold: 2 }), "{:?}", err);
+    }
+
+    #[test]
+    fn multisig_with_a_bad_signature_is_refused() {
+        // the third slot is signed by a key that isn't the third one
+        let err = verify_signature(&MockApi::default(), "wasm", &two_of_three(&[Some(1), Some(2), Some(4)])).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidSignature {}), "{:?}", err);
+    }
+
+    #[test]
+    fn multisig_key_counts_once_whatever_its_encoding() {
+        let api = MockApi::default();
+        let twice = multisig_input(2, vec![secp_key(compressed_key(1)), secp_key(uncompressed_key(1))], &[Some(1), Some(1)]);
+        let err = verify_signature(&api, "wasm", &twice).unwrap_err();
+        assert!(matches!(err, ContractError::MalformedInput { .. }), "{:?}", err);
+
+        let eth = MultisigKey { scheme: SignatureScheme::EthPersonalSign, public_key: uncompressed_key(1) };
+        let twice = multisig_input(2, vec![secp_key(compressed_key(1)), eth], &[Some(1), None]);
+        let err = verify_signature(&api, "wasm", &twice).unwrap_err();
+        assert!(matches!(err, ContractError::MalformedInput { .. }), "{:?}", err);
+
+        // an ed25519 key is never the same as a secp256k1 one
+        let ed25519 = MultisigKey { scheme: SignatureScheme::Ed25519, public_key: compressed_key(1)[1..].to_vec() };
+        let distinct = multisig_input(1, vec![secp_key(compressed_key(1)), ed25519], &[Some(1), None]);
+        verify_signature(&api, "wasm", &distinct).unwrap();
+    }
+
+    #[test]
+    fn multisig_address_is_derived_from_threshold_and_keys() {
+        let api = MockApi::default();
+        let address = |input: &InputType| signer(&api, "wasm", input).unwrap().address;
+        let input = two_of_three(&[Some(1), Some(2), None]);
+        let expected = bech32_address(&Sha256::digest(multisig_key(input.multisig.as_ref().unwrap()))[..20], "wasm").unwrap();
+        assert_eq!(address(&input), expected.to_string());
+        assert_eq!(signer(&api, "osmo", &input).unwrap().address[..5], *"osmo1");
+
+        let keys = (1..4).map(|seed| secp_key(compressed_key(seed))).collect();
+        assert_ne!(address(&input), address(&multisig_input(3, keys, &[Some(1), Some(2), Some(3)])));
+        let reordered = vec![secp_key(compressed_key(2)), secp_key(compressed_key(1)), secp_key(compressed_key(3))];
+        assert_ne!(address(&input), address(&multisig_input(2, reordered, &[Some(2), Some(1), None])));
+    }
+
+    // a secp256k1 input with the key omitted, signed by seed and ending with the recovery id offset by v_offset
+    fn recoverable_input(scheme: SignatureScheme, seed: u8, v_offset: u8) -> InputType {
+        let mut input = unsigned_input(seed, 0, BINARY);
+        input.scheme = scheme;
+        input.public_key = vec![];
+        let msg = input.sign_bytes();
+        let mut signature = match scheme {
+            // the recoverable signatures of k256 are over the keccak256 of the message
+            SignatureScheme::EthPersonalSign => {
+                let mut eth_msg = ETH_MESSAGE_PREFIX.to_vec();
+                eth_msg.extend_from_slice(msg.len().to_string().as_bytes());
+                eth_msg.extend_from_slice(&msg);
+                let signature: recoverable::Signature = signing_key(seed).sign(&eth_msg);
+                signature.as_ref().to_vec()
+            }
+            _ => {
+                let signature: Signature = signing_key(seed).sign(&msg);
+                let mut signature = signature.as_ref().to_vec();
+                let hash = message_hash(scheme, &msg);
+                let api = MockApi::default();
+                let id = (0..2)
+                    .find(|id| api.secp256k1_recover_pubkey(&hash, &signature, *id).map(|key| compress(&key)) == Ok(compressed_key(seed)))
+                    .unwrap();
+                signature.push(id);
+                signature
+            }
+        };
+        signature[SIGNATURE_LEN] += v_offset;
+        input.signature = signature;
+        input
+    }
+
+    #[test]
+    fn known_keys_have_known_addresses() {
+        // the key of the secret key made of 32 bytes 0x01, the address computed independently of the contract
+        let secp = compressed_key(1);
+        assert_eq!(to_hex(&secp), "031b84c5567b126440995d3ed5aaba0565d71e1834604819ff9c17f5e9d5dd078f");
+        assert_eq!(key_address(SignatureScheme::Secp256k1, &secp, "wasm").unwrap(), "wasm10xcqpzrky6eff2g52qdye53xkk9jxkvrtutffg");
+        assert_eq!(key_address(SignatureScheme::Secp256k1, &uncompressed_key(1), "wasm").unwrap(), "wasm10xcqpzrky6eff2g52qdye53xkk9jxkvrtutffg");
+
+        // the public key of the first test vector of RFC 8032
+        let ed25519 = from_hex("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a").unwrap();
+        assert_eq!(key_address(SignatureScheme::Ed25519, &ed25519, "wasm").unwrap(), "wasm1y8lrrhap2j3xzcntlp2qgm7jyudhhm2tjzxr4j");
+
+        // a well known Ethereum account, 0x2c7536E3605D9C16a7a3D7b1898e529396a65c23
+        let secret = from_hex("4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318").unwrap();
+        let key = SigningKey::from_bytes(&secret).unwrap().verifying_key().to_encoded_point(false).as_bytes().to_vec();
+        assert_eq!(to_hex(&eth_address(&key).unwrap()), "2c7536e3605d9c16a7a3d7b1898e529396a65c23");
+        assert_eq!(key_address(SignatureScheme::EthPersonalSign, &key, "wasm").unwrap(), "wasm1936ndcmqtkwpdfar67ccnrjjjwt2vhpr064jsc");
+    }
+
+    #[test]
+    fn omitted_keys_are_recovered_with_either_recovery_id_form() {
+        let api = MockApi::default();
+        for (scheme, key) in [(SignatureScheme::Secp256k1, compressed_key(1)), (SignatureScheme::EthPersonalSign, uncompressed_key(1))] {
+            let expected = key_address(scheme, &key, "wasm").unwrap();
+            for v_offset in [0, 27] {
+                let signer = verify_signature(&api, "wasm", &recoverable_input(scheme, 1, v_offset)).unwrap();
+                assert_eq!(signer.address, expected, "{:?} with v offset {}", scheme, v_offset);
+            }
+        }
+
+        // 29 is neither form
+        let mut input = recoverable_input(SignatureScheme::Secp256k1, 1, 0);
+        input.signature[SIGNATURE_LEN] = 29;
+        let err = verify_signature(&api, "wasm", &input).unwrap_err();
+        assert!(matches!(err, ContractError::MalformedInput { .. }), "{:?}", err);
+    }
+
+    #[test]
+    fn wrong_lengths_are_refused() {
+        let input = |scheme: SignatureScheme, key_len: usize, signature_len: usize| {
+            let mut input = unsigned_input(1, 0, BINARY);
+            input.scheme = scheme;
+            input.public_key = vec![2; key_len];
+            input.signature = vec![1; signature_len];
+            input
+        };
+        for (scheme, key_len, signature_len) in [
+            (SignatureScheme::Secp256k1, 32, 64),
+            (SignatureScheme::Secp256k1, 33, 65),
+            (SignatureScheme::Secp256k1, 0, 64),
+            (SignatureScheme::EthPersonalSign, 33, 64),
+            (SignatureScheme::EthPersonalSign, 0, 66),
+            (SignatureScheme::Ed25519, 33, 64),
+            (SignatureScheme::Ed25519, 0, 65),
+            (SignatureScheme::Ed25519, 32, 63),
+        ] {
+            let err = check_lengths(&input(scheme, key_len, signature_len)).unwrap_err();
+            assert!(matches!(err, ContractError::MalformedInput { .. }), "{:?} {} {}: {:?}", scheme, key_len, signature_len, err);
+        }
+        for (scheme, key_len) in [(SignatureScheme::Secp256k1, 33), (SignatureScheme::Secp256k1, 65), (SignatureScheme::EthPersonalSign, 65), (SignatureScheme::Ed25519, 32)] {
+            check_lengths(&input(scheme, key_len, 64)).unwrap();
+        }
+    }
+}
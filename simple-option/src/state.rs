@@ -1,45 +1,215 @@
-use std::collections::{HashSet, hash_map::DefaultHasher};
+use std::collections::{BTreeSet, HashSet, VecDeque, hash_map::DefaultHasher};
+use std::fmt;
 use std::hash::{Hash, Hasher};
 
 
-use cosmwasm_std::{IbcMsg, Timestamp, SubMsg, Addr};
+use cosmwasm_std::{to_vec, IbcMsg, Timestamp, SubMsg, Addr, Binary};
 use schemars::JsonSchema;
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize};
 
 use cw_storage_plus::{Item, Map, PrimaryKey, Key};
+use sha2::{Digest, Sha256};
 
-use crate::{ibc_msg::Msg};
+use crate::{codec::PacketEncoding, ibc_msg::{Msg, WireMsg}, utils::{to_hex, DEFAULT_LOG_RETENTION}};
 
 
+/// A value signed by a user. The signature is over sign_bytes, hashed as the scheme says, which
+/// binds the binary to the nonce and the domain so that the value can't be executed twice
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema, Hash)]
 #[serde(rename_all = "snake_case")]
 pub struct TBInput {
     pub binary: String,
+    /// Empty for a secp256k1 scheme when the signature is recoverable, the key is then recovered from it
     pub public_key: Vec<u8>,
     pub signature: Vec<u8>,
+    #[serde(default)]
+    pub scheme: SignatureScheme,
+    /// Executed once the previous nonce of the signer was, see GetNonce
+    #[serde(default)]
+    pub nonce: u64,
+    #[serde(default)]
+    pub domain: InputDomain,
+    /// Signs in place of public_key and signature, which are then empty, the signer is the multisig
+    #[serde(default)]
+    pub multisig: Option<Multisig>,
+}
+
+/// A threshold of keys out of a fixed set, its address is derived from the threshold and every key
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema, Hash)]
+pub struct Multisig {
+    pub threshold: u32,
+    pub keys: Vec<MultisigKey>,
+    /// One per key, in the same order, empty for the keys that didn't sign
+    pub signatures: Vec<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema, Hash)]
+pub struct MultisigKey {
+    #[serde(default)]
+    pub scheme: SignatureScheme,
+    pub public_key: Vec<u8>,
+}
+
+/// How an input is signed and how its signer address is derived, see signature.rs
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum SignatureScheme {
+    /// secp256k1 over the sha256 of sign_bytes, with a bech32 account address as in the Cosmos SDK
+    Secp256k1,
+    /// ed25519 over sign_bytes themselves, the address is the truncated sha256 of the key
+    Ed25519,
+    /// secp256k1 over the Ethereum personal_sign hash of sign_bytes, the address is the 20 byte Ethereum address in bech32
+    EthPersonalSign,
+}
+
+impl Default for SignatureScheme {
+    fn default() -> Self {
+        SignatureScheme::Secp256k1
+    }
+}
+
+impl SignatureScheme {
+    pub fn tag(self) -> u8 {
+        match self {
+            SignatureScheme::Secp256k1 => 0,
+            SignatureScheme::Ed25519 => 1,
+            SignatureScheme::EthPersonalSign => 2,
+        }
+    }
+
+    pub fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(SignatureScheme::Secp256k1),
+            1 => Some(SignatureScheme::Ed25519),
+            2 => Some(SignatureScheme::EthPersonalSign),
+            _ => None,
+        }
+    }
+}
+
+/// The deployment an input is meant for
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema, Hash)]
+pub struct InputDomain {
+    /// deployment_id the members were instantiated with
+    pub deployment_id: String,
+    /// Contract executing the value on each chain, exactly one per member
+    pub targets: Vec<InputTarget>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema, Hash)]
+pub struct InputTarget {
+    pub chain_id: PeerId,
+    pub contract: String,
+}
+
+// the document a user signs, in this field order. The keys of a multisig also sign the multisig, so
+// that their signatures only count towards it
+#[derive(Serialize)]
+struct SignDoc<'a> {
+    binary: &'a str,
+    nonce: u64,
+    domain: &'a InputDomain,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    multisig: Option<MultisigDoc<'a>>,
+}
+
+#[derive(Serialize)]
+struct MultisigDoc<'a> {
+    threshold: u32,
+    keys: &'a [MultisigKey],
 }
 
 impl TBInput {
 
+    // sign_bytes is the Json encoding of {"binary", "nonce", "domain"}, followed by "multisig": {"threshold",
+    // "keys"} for a multisig, without whitespace
+    pub fn sign_bytes(&self) -> Vec<u8> {
+        let doc = SignDoc {
+            binary: &self.binary,
+            nonce: self.nonce,
+            domain: &self.domain,
+            multisig: self.multisig.as_ref().map(|multisig| MultisigDoc {
+                threshold: multisig.threshold,
+                keys: &multisig.keys,
+            }),
+        };
+        // a struct of strings and integers always serializes
+        to_vec(&doc).unwrap()
+    }
+
     pub fn calculate_hash(self) -> u64 {
         let mut s = DefaultHasher::new();
         self.hash(&mut s);
         s.finish()
     }
+
+    // digest identifies the value across chains, unlike calculate_hash it does not depend on the compiler
+    pub fn digest(&self) -> String {
+        let mut hasher = Sha256::new();
+        let nonce = self.nonce.to_be_bytes();
+        let domain = to_vec(&self.domain).unwrap();
+        let multisig = to_vec(&self.multisig).unwrap();
+        for field in [self.binary.as_bytes(), &self.public_key, &self.signature, &[self.scheme.tag()], &nonce, &domain, &multisig] {
+            hasher.update((field.len() as u32).to_be_bytes());
+            hasher.update(field);
+        }
+        to_hex(&hasher.finalize())
+    }
 }
 
 
 pub type InputType = TBInput;
 
+/// Identifies a member chain, either its chain-id or any opaque id the members agree on
+pub type PeerId = String;
+
+// deserializes a PeerId, also accepting the numeric chain ids of earlier versions
+pub fn peer_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PeerId, D::Error> {
+    struct PeerIdVisitor;
+
+    impl<'de> de::Visitor<'de> for PeerIdVisitor {
+        type Value = PeerId;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a peer id or a numeric chain id")
+        }
+
+        fn visit_u64<E: de::Error>(self, v: u64) -> Result<PeerId, E> {
+            Ok(v.to_string())
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<PeerId, E> {
+            Ok(v.to_string())
+        }
+    }
+
+    deserializer.deserialize_any(PeerIdVisitor)
+}
+
+// same as peer_id, for optional fields
+pub fn opt_peer_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<PeerId>, D::Error> {
+    #[derive(Deserialize)]
+    struct Wrapped(#[serde(deserialize_with = "peer_id")] PeerId);
+    Ok(Option::<Wrapped>::deserialize(deserializer)?.map(|Wrapped(id)| id))
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct State {
     // pub role: String,
     pub n: u32,
-    pub chain_id: u32,
+    #[serde(deserialize_with = "peer_id")]
+    pub chain_id: PeerId,
+    // all members including ourselves, the primary of view v is the (v + 1)-th of them (mod n) in this order
+    #[serde(default)]
+    pub members: BTreeSet<PeerId>,
+    // number of the current instance, every Input starts a new one
+    #[serde(default)]
+    pub instance: u32,
     pub channel_ids: Vec<String>,
     pub current_tx_id: u32,
     pub view: u32,
-    pub primary: u32,
+    #[serde(deserialize_with = "peer_id")]
+    pub primary: PeerId,
     pub key1: u32,
     pub key2: u32,
     pub key3: u32,
@@ -71,14 +241,16 @@ pub struct State {
 
 impl State {
     // Another associated function, taking two arguments:
-    pub(crate) fn new(chain_id: u32, input: InputType, contract_addr: Addr, start_time: Timestamp) -> Self {
+    pub(crate) fn new(chain_id: PeerId, input: InputType, contract_addr: Addr, start_time: Timestamp) -> Self {
         Self {
             n: 1,
+            members: BTreeSet::from([chain_id.clone()]),
+            instance: 0,
+            primary: chain_id.clone(),
             chain_id,
             channel_ids: Vec::new(),
             current_tx_id: 0,
             view: 0,
-            primary: 1,
             key1: 0,
             key2: 0,
             key3: 0,
@@ -107,6 +279,7 @@ impl State {
         }
     }
     pub(crate) fn re_init(&mut self, input: InputType, start_time: Timestamp) -> () {
+        self.instance += 1;
         self.sent = HashSet::new();
         self.done = None;
         self.view = 0;
@@ -127,8 +300,7 @@ impl State {
         // Use block time..
         self.start_time = start_time;
 
-        // Set the primary to be (view mod n) + 1
-        self.primary = self.view % self.n + 1;
+        self.primary = self.leader(self.view);
 
         ////    process_messages() part     ////
         // initialize proofs to an empty set
@@ -139,11 +311,7 @@ impl State {
         self.done_executed = false;
         self.done_timestamp = None;
         self.done_block_height = None;
-        if (self.n == 3) {
-            self.F = 1;
-        } else {
-            self.F = (self.n-1)/3;
-        }
+        self.F = fault_threshold(self.n);
         ()
 
     }
@@ -161,8 +329,7 @@ impl State {
         //self view
         self.view = new_view;
 
-        // Set the primary to be (view mod n) + 1
-        self.primary = new_view % self.n + 1;
+        self.primary = self.leader(new_view);
 
         ////    process_messages() part     ////
         // initialize proofs to an empty set
@@ -171,46 +338,305 @@ impl State {
         // reset values
         self.received_propose = false;
 
-        if (self.n == 3) {
-            self.F = 1;
-        } else {
-            self.F = (self.n-1)/3;
-        }
+        self.F = fault_threshold(self.n);
         ()
 
     }
 
+    // leader returns the primary of view. Members are rotated in sorted order starting from the second one,
+    // which keeps the primaries of the dense chain ids 0..n of earlier versions
+    pub fn leader(&self, view: u32) -> PeerId {
+        let idx = (view as usize + 1) % self.members.len().max(1);
+        self.members.iter().nth(idx).cloned().unwrap_or_else(|| self.chain_id.clone())
+    }
+}
 
+// Number of faulty chains tolerated among n members (3 chains is a special case with f = 1)
+pub fn fault_threshold(n: u32) -> u32 {
+    if n == 3 {
+        1
+    } else {
+        n.saturating_sub(1) / 3
+    }
 }
 
+/// A chain that is expected to take part in the protocol
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Member {
+    #[serde(deserialize_with = "peer_id")]
+    pub chain_id: PeerId,
+    /// chain-id of the network, as tracked by the light client on the other end of the channel
+    pub network_id: String,
+    /// Prefix of account addresses on the member, bech32_prefix of the instantiate message when unset
+    #[serde(default)]
+    pub bech32_prefix: Option<String>,
+}
 
-pub const STATE: Item<State> = Item::new("state");
-pub const CHANNELS: Map<u32, String> = Map::new("channels");
+/// Outcome of the WhoAmI handshake received on a channel
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Handshake {
+    #[serde(deserialize_with = "peer_id")]
+    pub chain_id: PeerId,
+    pub protocol_version: u32,
+    pub fingerprint: String,
+    pub contract_addr: String,
+    pub network_id: String,
+    pub accepted: bool,
+    pub reason: Option<String>,
+}
 
-pub const HIGHEST_REQ: Map<u32, u32> = Map::new("highest_req");
-pub const HIGHEST_ABORT: Map<u32, i32> = Map::new("highest_abort");
+/// Messages waiting for a value, see values::resolve_queue
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PendingValue {
+    pub msgs: Vec<WireMsg>,
+    /// When the value was last asked for, it is asked for again once a packet lifetime passed
+    pub requested: Timestamp,
+}
 
-pub const SEND_ALL_UPON: Map<u32, Vec<Msg>> = Map::new("send_all_upon");
+/// A value being received in chunks, see values::receive_chunk
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct ChunkedTransfer {
+    pub chunks: Vec<Option<Binary>>,
+    pub started: Timestamp,
+}
+
+/// A point in the history of the chain
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct BlockRecord {
+    pub height: u64,
+    pub time: Timestamp,
+}
+
+/// Last packet received from a peer
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PacketRecord {
+    pub block: BlockRecord,
+    /// Our view when the packet arrived
+    pub view: u32,
+    pub msg_types: Vec<String>,
+}
+
+/// Last acknowledgement received from a peer
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct AckRecord {
+    pub block: BlockRecord,
+    /// false for error acknowledgements
+    pub success: bool,
+}
+
+/// What we last heard from a peer, see health.rs
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub struct PeerHealth {
+    pub last_received: Option<PacketRecord>,
+    pub last_ack: Option<AckRecord>,
+    pub last_timeout: Option<BlockRecord>,
+}
+
+/// Protocol steps whose completion is recorded in the timeline, see timeline.rs
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Milestone {
+    RequestQuorum,
+    ProposeReceived,
+    EchoQuorum,
+    Key1Quorum,
+    Key2Quorum,
+    Key3Quorum,
+    LockQuorum,
+    Done,
+    /// The decided value was executed on the local contract
+    Executed,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct MilestoneRecord {
+    pub milestone: Milestone,
+    pub block: BlockRecord,
+}
+
+/// Counts per key, sorted by key. Pairs rather than a map, as serde-json-wasm doesn't serialize maps
+pub type Counts = Vec<(String, u64)>;
+
+fn bump(counts: &mut Counts, key: &str, n: u64) {
+    match counts.binary_search_by(|(k, _)| k.as_str().cmp(key)) {
+        Ok(i) => counts[i].1 += n,
+        Err(i) => counts.insert(i, (key.to_string(), n)),
+    }
+}
+
+/// Protocol counters, kept for the current instance and since instantiation, see metrics.rs
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub struct Metrics {
+    /// Messages sent to other chains, per message type
+    pub sent: Counts,
+    /// Messages sent, per peer
+    pub sent_to: Counts,
+    /// Messages handled from other chains, per message type
+    pub received: Counts,
+    /// Messages handled, per peer
+    pub received_from: Counts,
+    pub packets_sent: u64,
+    pub bytes_sent: u64,
+    /// Views entered, the first view of an instance included
+    pub views: u64,
+    /// Abort broadcasts we started or joined
+    pub aborts: u64,
+    /// Values sent again to peers that asked for them with FetchValue
+    pub retransmissions: u64,
+    /// Messages delivered to ourselves through the inbox
+    pub self_deliveries: u64,
+}
+
+impl Metrics {
+    pub fn count_sent(&mut self, peer: &str, msg_type: &str) {
+        bump(&mut self.sent, msg_type, 1);
+        bump(&mut self.sent_to, peer, 1);
+    }
+
+    pub fn count_received(&mut self, peer: &str, msg_type: &str) {
+        bump(&mut self.received, msg_type, 1);
+        bump(&mut self.received_from, peer, 1);
+    }
+
+    pub fn add(&mut self, other: &Metrics) {
+        for (counts, other_counts) in [
+            (&mut self.sent, &other.sent),
+            (&mut self.sent_to, &other.sent_to),
+            (&mut self.received, &other.received),
+            (&mut self.received_from, &other.received_from),
+        ] {
+            for (key, count) in other_counts {
+                bump(counts, key, *count);
+            }
+        }
+        self.packets_sent += other.packets_sent;
+        self.bytes_sent += other.bytes_sent;
+        self.views += other.views;
+        self.aborts += other.aborts;
+        self.retransmissions += other.retransmissions;
+        self.self_deliveries += other.self_deliveries;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Metrics::default()
+    }
+}
+
+pub const STATE: Item<State> = Item::new("state");
+pub const CHANNELS: Map<PeerId, String> = Map::new("channels");
+// Configured member set, chain_id -> network_id. Empty when the deployment predates member configuration
+pub const MEMBERS: Map<PeerId, String> = Map::new("members");
+// Packet encoding negotiated through the channel version, per local channel_id
+pub const CHANNEL_ENCODING: Map<String, PacketEncoding> = Map::new("channel_encoding");
+// Compact layout agreed on for a channel using the compact encoding, absent for channels opened with the current one
+pub const CHANNEL_CODEC_VERSION: Map<String, u8> = Map::new("channel_codec_version");
+// Handshake result per local channel_id
+pub const HANDSHAKES: Map<String, Handshake> = Map::new("handshakes");
+// Liveness of each peer, updated as its packets, acks and timeouts come in
+pub const PEER_HEALTH: Map<PeerId, PeerHealth> = Map::new("peer_health");
+
+pub const HIGHEST_REQ: Map<PeerId, u32> = Map::new("highest_req");
+pub const HIGHEST_ABORT: Map<PeerId, i32> = Map::new("highest_abort");
+
+pub const SEND_ALL_UPON: Map<PeerId, Vec<Msg>> = Map::new("send_all_upon");
+
+// Maximum number of messages handled per transaction
+pub const PROCESSING_BUDGET: Item<u32> = Item::new("processing_budget");
+// Messages left over once the budget of a transaction ran out, with the channel they arrived on (None for self-sent)
+pub const BACKLOG: Item<VecDeque<(Option<String>, Msg)>> = Item::new("backlog");
+
+// Values seen in the current instance, by digest, so that messages can refer to them instead of carrying them.
+// Once decided only the decided value is kept, see values::retire_values
+pub const VALUES: Map<String, InputType> = Map::new("values");
+// Messages waiting for an unknown value, by <Channel_Id, digest>. Dropped with the view they belong to
+pub const PENDING_VALUES: Map<(String, String), PendingValue> = Map::new("pending_values");
+// Size of the Json encoding above which values are sent in chunks
+pub const VALUE_CHUNK_SIZE: Item<u32> = Item::new("value_chunk_size");
+// Values being received in chunks, by <Channel_Id, digest>. Dropped once older than a packet lifetime
+pub const CHUNKED_TRANSFERS: Map<(String, String), ChunkedTransfer> = Map::new("chunked_transfers");
+
+// Milestones reached, by <instance, view>, in the order they were reached
+pub const TIMELINE: Map<(u32, u32), Vec<MilestoneRecord>> = Map::new("timeline");
+
+// Counters of the current instance, reset by Input, and since instantiation
+pub const INSTANCE_METRICS: Item<Metrics> = Item::new("instance_metrics");
+pub const TOTAL_METRICS: Item<Metrics> = Item::new("total_metrics");
+
+// Values voted on in the current view, by the hash the vote maps (RECEIVED_ECHO..RECEIVED_DONE) are keyed with
+pub const VOTE_VALUES: Map<u64, InputType> = Map::new("vote_values");
 
 // FOR DEDUPING MESSAGES <Channel_Id, has_received_the_message_before>
-pub const RECEIVED: Map<String, HashSet<u32>> = Map::new("received");
+pub const RECEIVED: Map<String, HashSet<PeerId>> = Map::new("received");
 // pub const RECEIVED_SUGGEST: Map<String, HashSet<u32>> = Map::new("received_suggest");
 // pub const RECEIVED_PROOF: Map<String, HashSet<u32>> = Map::new("received_proof");
-pub const RECEIVED_ECHO: Map<u64, HashSet<u32>> = Map::new("received_echo");
-pub const RECEIVED_KEY1: Map<u64, HashSet<u32>> = Map::new("received_key1");
-pub const RECEIVED_KEY2: Map<u64, HashSet<u32>> = Map::new("received_key2");
-pub const RECEIVED_KEY3: Map<u64, HashSet<u32>> = Map::new("received_key3");
-pub const RECEIVED_LOCK: Map<u64, HashSet<u32>> = Map::new("received_lock");
-pub const RECEIVED_DONE: Map<u64, HashSet<u32>> = Map::new("received_done");
+pub const RECEIVED_ECHO: Map<u64, HashSet<PeerId>> = Map::new("received_echo");
+pub const RECEIVED_KEY1: Map<u64, HashSet<PeerId>> = Map::new("received_key1");
+pub const RECEIVED_KEY2: Map<u64, HashSet<PeerId>> = Map::new("received_key2");
+pub const RECEIVED_KEY3: Map<u64, HashSet<PeerId>> = Map::new("received_key3");
+pub const RECEIVED_LOCK: Map<u64, HashSet<PeerId>> = Map::new("received_lock");
+pub const RECEIVED_DONE: Map<u64, HashSet<PeerId>> = Map::new("received_done");
 
 
 //// TESTING.. ////
+#[cfg(feature = "testing")]
 pub const TEST: Map<u32, Vec<IbcMsg>> = Map::new("test");
-pub const TEST_QUEUE: Map<u32, Vec<(u32, Vec<Msg>)> > = Map::new("test_queue");
-pub const DEBUG: Map<u32, String> = Map::new("debug");
-pub const IBC_MSG_SEND_DEBUG: Map<u32, String> = Map::new("ibc_msg_send_debug");
-pub const DEBUG_CTR: Item<u32> = Item::new("DEBUG_CTR");
-pub const DEBUG_RECEIVE_MSG: Map<String, Vec<String>> = Map::new("DEBUG_RECEIVE_MSG");
+#[cfg(feature = "testing")]
+pub const TEST_QUEUE: Map<u32, Vec<(PeerId, Vec<Msg>)> > = Map::new("test_queue");
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+/// Part of the contract an entry comes from
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum LogCategory {
+    /// Message handling and phase changes
+    Protocol,
+    /// Packets and messages sent
+    Packet,
+    Abort,
+    /// Execution of the decided value
+    Execution,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct LogConfig {
+    /// Entries below this level are discarded, None turns the log off
+    pub level: Option<LogLevel>,
+    /// Number of entries kept, the oldest are dropped first
+    pub retention: u32,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: Some(LogLevel::Info),
+            retention: DEFAULT_LOG_RETENTION,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct LogEntry {
+    pub seq: u64,
+    pub level: LogLevel,
+    pub category: LogCategory,
+    pub height: u64,
+    pub message: String,
+}
+
+// Log entries by sequence number, see log.rs
+pub const LOG_CONFIG: Item<LogConfig> = Item::new("log_config");
+pub const LOG_ENTRIES: Map<u64, LogEntry> = Map::new("log_entries");
+// Sequence number of the next entry
+pub const LOG_SEQ: Item<u64> = Item::new("log_seq");
 
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -221,3 +647,46 @@ pub struct Test {
     pub dest_chan_id: String,
 }
 
+
+// Account allowed to change the configuration, see admin.rs
+pub const ADMIN: Item<Addr> = Item::new("admin");
+// Account the admin offered the role to, until it accepts
+pub const PENDING_ADMIN: Item<Addr> = Item::new("pending_admin");
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, JsonSchema)]
+pub struct SubmissionPolicy {
+    /// Signers allowed to submit inputs, as addresses or hex encoded public keys. None allows anyone
+    pub allowed_signers: Option<Vec<String>>,
+    pub rate_limit: Option<RateLimit>,
+    /// Largest binary an input may carry, in bytes
+    pub max_payload_size: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct RateLimit {
+    /// Inputs each signer may submit per window
+    pub max_inputs: u32,
+    /// Length of a window in blocks, windows start at the multiples of it
+    pub window_blocks: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SubmissionWindow {
+    pub start: u64,
+    pub count: u32,
+}
+
+// Restrictions on Input, see submission.rs
+pub const SUBMISSION_POLICY: Item<SubmissionPolicy> = Item::new("submission_policy");
+// Inputs submitted in the current window, by signer address
+pub const SUBMISSIONS: Map<String, SubmissionWindow> = Map::new("submissions");
+
+// Identifier shared by the members of a deployment, part of the domain of inputs
+pub const DEPLOYMENT_ID: Item<String> = Item::new("deployment_id");
+// Next nonce each signer may use, by address
+pub const NONCES: Map<String, u64> = Map::new("nonces");
+
+// Prefix of account addresses on this chain and on the members that don't set their own, see utils::bech32_prefix
+pub const BECH32_PREFIX: Item<String> = Item::new("bech32_prefix");
+// Prefix of account addresses on the members that set their own
+pub const MEMBER_BECH32_PREFIXES: Map<PeerId, String> = Map::new("member_bech32_prefixes");
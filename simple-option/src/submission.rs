@@ -0,0 +1,137 @@
+// Who may start an instance through Input. The policy restricts the signers of the submitted value,
+// how many values each of them submits per window of blocks and how large the values may be.
+
+use cosmwasm_std::{Env, StdResult, Storage};
+
+use crate::state::{InputType, SubmissionPolicy, SubmissionWindow, SUBMISSIONS, SUBMISSION_POLICY};
+use crate::signature::Signer;
+use crate::utils::to_hex;
+use crate::ContractError;
+
+pub fn set_submission_policy(store: &mut dyn Storage, policy: &SubmissionPolicy) -> Result<(), ContractError> {
+    if let Some(rate_limit) = &policy.rate_limit {
+        if rate_limit.window_blocks == 0 {
+            return Err(ContractError::CustomError { val: "window_blocks must be positive".to_string() });
+        }
+    }
+    SUBMISSION_POLICY.save(store, policy)?;
+    Ok(())
+}
+
+pub fn query_submission_policy(store: &dyn Storage) -> StdResult<SubmissionPolicy> {
+    Ok(SUBMISSION_POLICY.may_load(store)?.unwrap_or_default())
+}
+
+// check_submission applies the policy to input and counts it against the rate limit of its signer
+pub fn check_submission(store: &mut dyn Storage, env: &Env, signer: &Signer, input: &InputType) -> Result<(), ContractError> {
+    let policy = query_submission_policy(store)?;
+
+    if let Some(max) = policy.max_payload_size {
+        let size = input.binary.len() as u64;
+        if size > max as u64 {
+            return Err(ContractError::PayloadTooLarge { size, max });
+        }
+    }
+
+    if let Some(allowed) = &policy.allowed_signers {
+        let public_key = to_hex(&signer.public_key);
+        if !allowed.iter().any(|entry| *entry == signer.address || entry.eq_ignore_ascii_case(&public_key)) {
+            return Err(ContractError::SignerNotAllowed { signer: signer.address.clone() });
+        }
+    }
+
+    if let Some(rate_limit) = policy.rate_limit {
+        let signer = signer.address.clone();
+        let start = env.block.height - env.block.height % rate_limit.window_blocks;
+        let mut window = match SUBMISSIONS.may_load(store, signer.clone())? {
+            Some(window) if window.start == start => window,
+            _ => SubmissionWindow { start, count: 0 },
+        };
+        if window.count >= rate_limit.max_inputs {
+            return Err(ContractError::RateLimited {
+                signer,
+                max_inputs: rate_limit.max_inputs,
+                retry_at: start + rate_limit.window_blocks,
+            });
+        }
+        window.count += 1;
+        SUBMISSIONS.save(store, signer, &window)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::testing::{mock_env, MockApi, MockStorage};
+
+    use crate::signature::signer;
+    use crate::state::RateLimit;
+    use crate::test_utils::{signed_input, unsigned_input, BINARY};
+
+    use super::*;
+
+    fn signer_of(seed: u8) -> Signer {
+        signer(&MockApi::default(), "wasm", &signed_input(seed, 0)).unwrap()
+    }
+
+    fn at_height(height: u64) -> Env {
+        let mut env = mock_env();
+        env.block.height = height;
+        env
+    }
+
+    fn with_policy(policy: SubmissionPolicy) -> MockStorage {
+        let mut store = MockStorage::new();
+        set_submission_policy(&mut store, &policy).unwrap();
+        store
+    }
+
+    #[test]
+    fn signers_outside_the_allowed_list_are_refused() {
+        let allowed = signer_of(1);
+        let by_key = signer_of(2);
+        let mut store = with_policy(SubmissionPolicy {
+            allowed_signers: Some(vec![allowed.address.clone(), to_hex(&by_key.public_key).to_uppercase()]),
+            ..SubmissionPolicy::default()
+        });
+        let env = mock_env();
+        check_submission(&mut store, &env, &allowed, &signed_input(1, 0)).unwrap();
+        check_submission(&mut store, &env, &by_key, &signed_input(2, 0)).unwrap();
+        let err = check_submission(&mut store, &env, &signer_of(3), &signed_input(3, 0)).unwrap_err();
+        assert!(matches!(err, ContractError::SignerNotAllowed { .. }), "{:?}", err);
+    }
+
+    #[test]
+    fn rate_limit_resets_with_the_window() {
+        let policy = SubmissionPolicy { rate_limit: Some(RateLimit { max_inputs: 2, window_blocks: 10 }), ..SubmissionPolicy::default() };
+        let mut store = with_policy(policy);
+        let (signer, input) = (signer_of(1), signed_input(1, 0));
+        check_submission(&mut store, &at_height(20), &signer, &input).unwrap();
+        check_submission(&mut store, &at_height(29), &signer, &input).unwrap();
+        let err = check_submission(&mut store, &at_height(29), &signer, &input).unwrap_err();
+        assert!(matches!(err, ContractError::RateLimited { max_inputs: 2, retry_at: 30, .. }), "{:?}", err);
+
+        // other signers have their own count, and the next window starts afresh
+        check_submission(&mut store, &at_height(29), &signer_of(2), &signed_input(2, 0)).unwrap();
+        check_submission(&mut store, &at_height(30), &signer, &input).unwrap();
+        check_submission(&mut store, &at_height(35), &signer, &input).unwrap();
+        check_submission(&mut store, &at_height(39), &signer, &input).unwrap_err();
+    }
+
+    #[test]
+    fn payloads_over_the_maximum_are_refused() {
+        let max = BINARY.len() as u32;
+        let mut store = with_policy(SubmissionPolicy { max_payload_size: Some(max), ..SubmissionPolicy::default() });
+        let signer = signer_of(1);
+        check_submission(&mut store, &mock_env(), &signer, &signed_input(1, 0)).unwrap();
+        let larger = unsigned_input(1, 0, &format!("{}AA==", BINARY));
+        let err = check_submission(&mut store, &mock_env(), &signer, &larger).unwrap_err();
+        assert!(matches!(err, ContractError::PayloadTooLarge { max: m, .. } if m == max), "{:?}", err);
+    }
+
+    #[test]
+    fn empty_windows_are_refused() {
+        let policy = SubmissionPolicy { rate_limit: Some(RateLimit { max_inputs: 1, window_blocks: 0 }), ..SubmissionPolicy::default() };
+        set_submission_policy(&mut MockStorage::new(), &policy).unwrap_err();
+    }
+}
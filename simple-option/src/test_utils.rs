@@ -0,0 +1,143 @@
+// Fixtures of the unit tests, a 4 chain deployment driven through the entry points as in tests/gas.rs.
+// Chain ids are 0 to 3 and the channel to chain i is channel-i.
+
+use cosmwasm_std::testing::{
+    mock_dependencies, mock_env, mock_ibc_channel_connect_ack, mock_ibc_packet_recv, mock_info, MockApi,
+    MockQuerier, MockStorage,
+};
+use cosmwasm_std::{from_slice, CosmosMsg, IbcMsg, IbcOrder, IbcReceiveResponse, OwnedDeps, Storage};
+use k256::ecdsa::signature::Signer;
+use k256::ecdsa::{Signature, SigningKey};
+
+use crate::contract::{execute, instantiate};
+use crate::ibc::{ibc_channel_connect, ibc_packet_receive};
+use crate::ibc_msg::{AcknowledgementMsg, Msg, MsgQueueResponse, PacketMsg, WireMsg};
+use crate::msg::{ExecuteMsg, InstantiateMsg};
+use crate::state::{InputDomain, InputTarget, SignatureScheme, TBInput, VALUES};
+
+pub type TestDeps = OwnedDeps<MockStorage, MockApi, MockQuerier>;
+
+pub const DEPLOYMENT_ID: &str = "test";
+pub const CHAINS: u32 = 4;
+pub const ADMIN: &str = "creator";
+/// {"register_tb":{"name":"test"}}
+pub const BINARY: &str = "eyJyZWdpc3Rlcl90YiI6eyJuYW1lIjoidGVzdCJ9fQ==";
+
+pub fn channel(chain_id: u32) -> String {
+    format!("channel-{}", chain_id)
+}
+
+pub fn signing_key(seed: u8) -> SigningKey {
+    SigningKey::from_bytes(&[seed; 32]).unwrap()
+}
+
+// an unsigned secp256k1 input of the key made of seed, for the deployment of setup
+pub fn unsigned_input(seed: u8, nonce: u64, binary: &str) -> TBInput {
+    TBInput {
+        binary: binary.to_string(),
+        public_key: signing_key(seed).verifying_key().to_bytes().to_vec(),
+        signature: vec![],
+        scheme: SignatureScheme::Secp256k1,
+        nonce,
+        domain: InputDomain {
+            deployment_id: DEPLOYMENT_ID.to_string(),
+            targets: (0..CHAINS)
+                .map(|chain_id| InputTarget { chain_id: chain_id.to_string(), contract: "nameservice".to_string() })
+                .collect(),
+        },
+        multisig: None,
+    }
+}
+
+// signs input the way Cosmos wallets do, with the key made of seed
+pub fn sign(seed: u8, mut input: TBInput) -> TBInput {
+    let signature: Signature = signing_key(seed).sign(&input.sign_bytes());
+    input.signature = signature.as_ref().to_vec();
+    input
+}
+
+pub fn signed_input(seed: u8, nonce: u64) -> TBInput {
+    sign(seed, unsigned_input(seed, nonce, BINARY))
+}
+
+pub fn instantiate_msg(chain_id: u32) -> InstantiateMsg {
+    InstantiateMsg {
+        chain_id: Some(chain_id.to_string()),
+        input: signed_input(7, 0),
+        contract_addr: "nameservice".to_string(),
+        members: vec![],
+        processing_budget: None,
+        value_chunk_size: None,
+        admin: None,
+        deployment_id: DEPLOYMENT_ID.to_string(),
+        submission_policy: None,
+        bech32_prefix: None,
+    }
+}
+
+pub fn instantiate_with(msg: InstantiateMsg) -> TestDeps {
+    let mut deps = mock_dependencies();
+    instantiate(deps.as_mut(), mock_env(), mock_info(ADMIN, &[]), msg).unwrap();
+    deps
+}
+
+// opens the channel to peer and returns the WhoAmI we send on it
+pub fn connect(deps: &mut TestDeps, peer: u32) -> PacketMsg {
+    let connect = mock_ibc_channel_connect_ack(&channel(peer), IbcOrder::Unordered, "trustboost-test");
+    let res = ibc_channel_connect(deps.as_mut(), mock_env(), connect).unwrap();
+    match &res.messages[0].msg {
+        CosmosMsg::Ibc(IbcMsg::SendPacket { data, .. }) => from_slice(data).unwrap(),
+        msg => panic!("unexpected {:?}", msg),
+    }
+}
+
+// the WhoAmI peer answers ours with, which only differs by chain_id
+pub fn answer(ours: PacketMsg, peer: u32) -> PacketMsg {
+    match ours {
+        PacketMsg::WhoAmI { protocol_version, fingerprint, contract_addr, network_id, .. } => {
+            PacketMsg::WhoAmI { chain_id: peer.to_string(), protocol_version, fingerprint, contract_addr, network_id }
+        }
+        msg => panic!("unexpected {:?}", msg),
+    }
+}
+
+// sets up chain_id of a 4 chain deployment with channels to every other chain
+pub fn setup(chain_id: u32) -> TestDeps {
+    setup_with(instantiate_msg(chain_id))
+}
+
+pub fn setup_with(msg: InstantiateMsg) -> TestDeps {
+    let chain_id: u32 = msg.chain_id.as_ref().unwrap().parse().unwrap();
+    let mut deps = instantiate_with(msg);
+    for peer in (0..CHAINS).filter(|peer| *peer != chain_id) {
+        let who_am_i = answer(connect(&mut deps, peer), peer);
+        receive(&mut deps, peer, &who_am_i);
+    }
+    deps
+}
+
+// submits input on behalf of user
+pub fn submit(deps: &mut TestDeps, input: TBInput) {
+    execute(deps.as_mut(), mock_env(), mock_info("user", &[]), ExecuteMsg::Input { value: input }).unwrap();
+}
+
+// receives packet on the channel to from, sent by the contract of the deployment
+pub fn receive(deps: &mut TestDeps, from: u32, packet: &PacketMsg) -> IbcReceiveResponse {
+    let mut recv = mock_ibc_packet_recv(&channel(from), packet).unwrap();
+    recv.packet.src.port_id = format!("wasm.{}", mock_env().contract.address);
+    ibc_packet_receive(deps.as_mut(), mock_env(), recv).unwrap()
+}
+
+pub fn deliver(deps: &mut TestDeps, from: u32, msgs: Vec<WireMsg>) -> IbcReceiveResponse {
+    receive(deps, from, &PacketMsg::MsgQueue(msgs))
+}
+
+// a message that fails to be handled: the value it asks for is stored but no longer parses
+pub fn failing_msg(deps: &mut TestDeps) -> WireMsg {
+    deps.storage.set(&VALUES.key("corrupt".to_string()), b"{");
+    Msg::FetchValue { digest: "corrupt".to_string() }
+}
+
+pub fn ack(res: &IbcReceiveResponse) -> AcknowledgementMsg<MsgQueueResponse> {
+    from_slice(&res.acknowledgement).unwrap()
+}
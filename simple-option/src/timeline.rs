@@ -0,0 +1,179 @@
+// Per-phase timeline. The block at which each milestone of a view was first reached is kept per
+// instance, so that the GetTimeline query can break the latency of an instance down by phase.
+// Only the last TIMELINE_INSTANCES instances are kept, older ones are pruned as a new one starts.
+
+use cosmwasm_std::{to_vec, Env, Order, StdResult, Storage};
+use cw_storage_plus::Bound;
+
+use crate::context::Context;
+use crate::events::{emit, EVENT_PHASE};
+use crate::msg::{TimelineResponse, ViewTimeline};
+use crate::state::{BlockRecord, Milestone, MilestoneRecord, STATE, TIMELINE};
+use crate::utils::TIMELINE_INSTANCES;
+
+// record_milestone records milestone for the current view and emits it as a phase event the first time
+pub fn record_milestone(ctx: &mut Context, milestone: Milestone) -> StdResult<()> {
+    if record_milestone_at(&mut ctx.store, ctx.env, ctx.state.instance, ctx.state.view, milestone)? {
+        let phase = to_value(&milestone)?;
+        emit(ctx, EVENT_PHASE, vec![("phase", phase)]);
+    }
+    Ok(())
+}
+
+// record_milestone_at records milestone for the view of instance unless it was reached before,
+// it returns whether it was recorded
+pub fn record_milestone_at(
+    store: &mut dyn Storage,
+    env: &Env,
+    instance: u32,
+    view: u32,
+    milestone: Milestone,
+) -> StdResult<bool> {
+    let mut records = TIMELINE.may_load(store, (instance, view))?.unwrap_or_default();
+    if records.iter().any(|record| record.milestone == milestone) {
+        return Ok(false);
+    }
+    records.push(MilestoneRecord {
+        milestone,
+        block: BlockRecord {
+            height: env.block.height,
+            time: env.block.time,
+        },
+    });
+    TIMELINE.save(store, (instance, view), &records)?;
+    Ok(true)
+}
+
+// to_value is the name of a unit variant as it is serialized
+fn to_value(milestone: &Milestone) -> StdResult<String> {
+    let name = to_vec(milestone)?;
+    Ok(String::from_utf8_lossy(&name).trim_matches('"').to_string())
+}
+
+// check_request_quorum records the RequestQuorum milestone once n - f chains asked to join the current view.
+// All chains are part of the first view of an instance from the start
+pub fn check_request_quorum(ctx: &mut Context) -> StdResult<()> {
+    let view = ctx.state.view;
+    let joined = ctx.highest_req.values().filter(|highest| **highest >= view).count();
+    if joined >= (ctx.state.n - ctx.state.F) as usize {
+        record_milestone(ctx, Milestone::RequestQuorum)?;
+    }
+    Ok(())
+}
+
+// prune_timeline drops the timelines of the instances before the last TIMELINE_INSTANCES up to instance
+pub fn prune_timeline(store: &mut dyn Storage, instance: u32) -> StdResult<()> {
+    let oldest = instance.saturating_sub(TIMELINE_INSTANCES - 1);
+    let expired: Vec<(u32, u32)> = TIMELINE
+        .keys(store, None, Some(Bound::exclusive((oldest, 0))), Order::Ascending)
+        .collect::<StdResult<_>>()?;
+    for key in expired {
+        TIMELINE.remove(store, key);
+    }
+    Ok(())
+}
+
+// query_timeline returns the milestones of instance, the current one when None
+pub fn query_timeline(store: &dyn Storage, instance: Option<u32>) -> StdResult<TimelineResponse> {
+    let instance = match instance {
+        Some(instance) => instance,
+        None => STATE.load(store)?.instance,
+    };
+    let views = TIMELINE
+        .prefix(instance)
+        .range(store, None, None, Order::Ascending)
+        .map(|entry| entry.map(|(view, milestones)| ViewTimeline { view, milestones }))
+        .collect::<StdResult<_>>()?;
+    Ok(TimelineResponse { instance, views })
+}
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::testing::{mock_env, MockStorage};
+
+    use crate::ibc_msg::{Msg, ValueRef};
+    use crate::test_utils::{deliver, setup, signed_input, submit, TestDeps};
+
+    use super::*;
+
+    fn at_height(height: u64) -> Env {
+        let mut env = mock_env();
+        env.block.height = height;
+        env
+    }
+
+    fn milestones(timeline: &TimelineResponse, view: u32) -> Vec<(Milestone, u64)> {
+        let view = timeline.views.iter().find(|timeline| timeline.view == view).unwrap();
+        view.milestones.iter().map(|record| (record.milestone, record.block.height)).collect()
+    }
+
+    #[test]
+    fn milestones_are_recorded_once_per_view() {
+        let mut store = MockStorage::new();
+        assert!(record_milestone_at(&mut store, &at_height(10), 1, 0, Milestone::EchoQuorum).unwrap());
+        assert!(!record_milestone_at(&mut store, &at_height(11), 1, 0, Milestone::EchoQuorum).unwrap());
+        assert!(record_milestone_at(&mut store, &at_height(12), 1, 0, Milestone::Key1Quorum).unwrap());
+        assert!(record_milestone_at(&mut store, &at_height(13), 1, 1, Milestone::EchoQuorum).unwrap());
+        assert!(record_milestone_at(&mut store, &at_height(14), 2, 0, Milestone::EchoQuorum).unwrap());
+
+        let timeline = query_timeline(&store, Some(1)).unwrap();
+        assert_eq!(timeline.views.iter().map(|view| view.view).collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(milestones(&timeline, 0), vec![(Milestone::EchoQuorum, 10), (Milestone::Key1Quorum, 12)]);
+        assert_eq!(milestones(&timeline, 1), vec![(Milestone::EchoQuorum, 13)]);
+        assert_eq!(milestones(&query_timeline(&store, Some(2)).unwrap(), 0), vec![(Milestone::EchoQuorum, 14)]);
+    }
+
+    #[test]
+    fn only_the_last_instances_are_kept() {
+        let mut store = MockStorage::new();
+        for instance in 1..=TIMELINE_INSTANCES + 2 {
+            record_milestone_at(&mut store, &at_height(instance as u64), instance, 0, Milestone::EchoQuorum).unwrap();
+            record_milestone_at(&mut store, &at_height(instance as u64), instance, 1, Milestone::EchoQuorum).unwrap();
+        }
+        prune_timeline(&mut store, TIMELINE_INSTANCES + 3).unwrap();
+        let kept: Vec<u32> = (1..=TIMELINE_INSTANCES + 2)
+            .filter(|instance| !query_timeline(&store, Some(*instance)).unwrap().views.is_empty())
+            .collect();
+        assert_eq!(kept, (4..=TIMELINE_INSTANCES + 2).collect::<Vec<_>>());
+        assert_eq!(query_timeline(&store, Some(4)).unwrap().views.len(), 2);
+    }
+
+    #[test]
+    fn current_instance_is_reported_by_default() {
+        let mut deps = setup(2);
+        let val = signed_input(7, 0);
+        submit(&mut deps, val.clone());
+        let res = deliver(&mut deps, 1, vec![Msg::Propose { chain_id: "1".to_string(), k: 0, v: ValueRef::Full(val), view: 0 }]);
+
+        let phases: Vec<&str> = res
+            .events
+            .iter()
+            .filter(|event| event.ty == EVENT_PHASE)
+            .flat_map(|event| event.attributes.iter().filter(|attr| attr.key == "phase").map(|attr| attr.value.as_str()))
+            .collect();
+        assert_eq!(phases, vec!["propose_received"]);
+
+        let timeline = query_timeline(&deps.storage, None).unwrap();
+        assert_eq!(timeline.instance, STATE.load(&deps.storage).unwrap().instance);
+        let reached: Vec<Milestone> = milestones(&timeline, 0).into_iter().map(|(milestone, _)| milestone).collect();
+        assert_eq!(reached, vec![Milestone::RequestQuorum, Milestone::ProposeReceived]);
+    }
+
+    #[test]
+    fn request_quorum_needs_n_minus_f_chains_in_the_view() {
+        let mut deps = setup(2);
+        submit(&mut deps, signed_input(7, 0));
+        let mut state = STATE.load(&deps.storage).unwrap();
+        state.view = 1;
+        STATE.save(&mut deps.storage, &state).unwrap();
+
+        // n - f = 3 chains have to ask for view 1, we didn't
+        let reached = |deps: &TestDeps| TIMELINE.has(&deps.storage, (state.instance, 1));
+        for from in [0, 1] {
+            deliver(&mut deps, from, vec![Msg::Request { view: 1, chain_id: from.to_string() }]);
+        }
+        assert!(!reached(&deps));
+        deliver(&mut deps, 3, vec![Msg::Request { view: 1, chain_id: "3".to_string() }]);
+        assert!(reached(&deps));
+    }
+}
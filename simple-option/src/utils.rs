@@ -1,4 +1,4 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 
 use cosmwasm_std::{
     StdResult, Order, IbcTimeout, Env, IbcOrder, StdError, IbcChannelOpenMsg, Storage, IbcMsg, to_binary, Addr, Binary, Deps, Api, Timestamp
@@ -14,21 +14,55 @@ use ripemd::{Digest as RipDigest, Ripemd160};
 
 use cw_storage_plus::{Map};
 use crate::state::{
-    CHANNELS, SEND_ALL_UPON, STATE, HIGHEST_REQ, HIGHEST_ABORT, RECEIVED, RECEIVED_ECHO, 
-    RECEIVED_KEY1, RECEIVED_KEY2, RECEIVED_KEY3, RECEIVED_LOCK, TEST_QUEUE,RECEIVED_DONE, 
-    DEBUG, IBC_MSG_SEND_DEBUG, DEBUG_RECEIVE_MSG, InputType, DEBUG_CTR
+    CHANNELS, CHANNEL_CODEC_VERSION, CHANNEL_ENCODING, MEMBERS, SEND_ALL_UPON, STATE, fault_threshold, HIGHEST_REQ, HIGHEST_ABORT, RECEIVED, RECEIVED_ECHO, 
+    RECEIVED_KEY1, RECEIVED_KEY2, RECEIVED_KEY3, RECEIVED_LOCK, RECEIVED_DONE, 
+    InputType, PeerId, VOTE_VALUES, DEPLOYMENT_ID, BECH32_PREFIX, MEMBER_BECH32_PREFIXES
 };
 
 /// Setting the lifetime of packets to be one hour
 pub const PACKET_LIFETIME: u64 = 60 * 60;
+/// Messages handled per transaction when the instantiate message doesn't set a processing budget
+pub const DEFAULT_PROCESSING_BUDGET: u32 = 64;
+/// Most messages the backlog may hold, packets that would take it further are refused
+pub const MAX_BACKLOG: usize = 1024;
+/// Values with a larger Json encoding are sent in chunks of this size, unless the instantiate message sets one
+pub const DEFAULT_VALUE_CHUNK_SIZE: u32 = 16 * 1024;
+/// Most chunks a received value may consist of
+pub const MAX_VALUE_CHUNKS: u32 = 256;
+/// Most messages of a peer waiting for values at once, any further ones are dropped
+pub const MAX_PENDING_VALUES: usize = 64;
 /// Setting up constant
 pub const IBC_APP_VERSION: &str = "simple_storage";
-pub const DEBUG_ON: bool = true;
-
-
+/// Channel version proposing the compact packet encoding in its current layout, any version without the prefix means Json
+pub const IBC_APP_VERSION_COMPACT: &str = "trustboost-compact-6";
+pub const IBC_APP_VERSION_COMPACT_PREFIX: &str = "trustboost-compact-";
+/// Version of the packet protocol announced in the WhoAmI handshake
+pub const PROTOCOL_VERSION: u32 = 6;
+/// Prefix of account addresses unless the instantiate message sets one
+pub const DEFAULT_BECH32_PREFIX: &str = "wasm";
+/// Longest prefix bech32 allows
+pub const MAX_BECH32_PREFIX_LEN: usize = 83;
+/// Log entries kept unless the admin configures otherwise
+pub const DEFAULT_LOG_RETENTION: u32 = 512;
+/// Most log entries returned by one GetLogs query
+pub const MAX_LOGS_PAGE: u32 = 100;
+/// Most log entries one GetLogs query looks at, those below its level included
+pub const MAX_LOGS_SCAN: usize = 1000;
+/// Most log entries the admin may have kept
+pub const MAX_LOG_RETENTION: u32 = 10_000;
+/// Number of instances, the current one included, whose timeline is kept
+pub const TIMELINE_INSTANCES: u32 = 16;
+
+
+#[cfg(feature = "testing")]
+use crate::state::TEST_QUEUE;
 use crate::ContractError;
+use crate::values::retire_values;
+use crate::metrics::reset_instance_metrics;
+use crate::timeline::prune_timeline;
+use crate::codec::{encode_packet, PacketEncoding, COMPACT_CODEC_VERSION};
 
-pub fn get_chain_id(store: &mut dyn Storage, channel_id_to_get: String) -> u32 {
+pub fn get_chain_id(store: &mut dyn Storage, channel_id_to_get: String) -> PeerId {
     // Get the chain_id of the sender
     CHANNELS
     .range(store, None, None, Order::Ascending)
@@ -50,7 +84,7 @@ pub fn init_receive_map(store: &mut dyn Storage) -> StdResult<()> {
         .collect();
     let all_chain_ids = all_chain_ids?;
     // initialize the highest_request of oneself
-    HIGHEST_REQ.save(store, state.chain_id, &0)?;
+    HIGHEST_REQ.save(store, state.chain_id.clone(), &0)?;
     // initialize the highest_abort of oneself
     for chain_id in all_chain_ids {
         HIGHEST_REQ.save(store, chain_id, &0)?;
@@ -61,6 +95,9 @@ pub fn init_receive_map(store: &mut dyn Storage) -> StdResult<()> {
     
     reset_view_specific_maps(store)?;
     reset_aborts(store)?;
+    retire_values(store, None)?;
+    reset_instance_metrics(store);
+    prune_timeline(store, state.instance + 1)?;
     Ok(())
 }
 
@@ -81,16 +118,22 @@ pub fn reset_view_specific_maps(store: &mut dyn Storage) -> StdResult<()> {
     delete_map(store, RECEIVED_KEY3)?;
     delete_map(store, RECEIVED_LOCK)?;
     delete_map(store, RECEIVED_DONE)?;
+    clear_vote_values(store)?;
 
-    //// TESTING ////
+    #[cfg(feature = "testing")]
+    clear_test_queue(store)?;
+
+    Ok(())
+}
+
+#[cfg(feature = "testing")]
+pub fn clear_test_queue(store: &mut dyn Storage) -> StdResult<()> {
     let keys: StdResult<Vec<_>> = TEST_QUEUE
         .keys(store, None, None, Order::Ascending)
         .collect();
     for v in keys? {
         TEST_QUEUE.remove(store, v);
-    }       
-    //// TESTING ////
-
+    }
     Ok(())
 }
 
@@ -101,7 +144,7 @@ fn reset_aborts(store: &mut dyn Storage) -> StdResult<()> {
     .keys(store, None, None, Order::Ascending)
     .collect();
     let all_chain_ids = all_chain_ids?;
-    HIGHEST_ABORT.save(store, state.chain_id, &-1)?;
+    HIGHEST_ABORT.save(store, state.chain_id.clone(), &-1)?;
     for chain_id in all_chain_ids {
         // Resetting highest_abort
         HIGHEST_ABORT.save(store, chain_id, &-1)?;
@@ -109,7 +152,17 @@ fn reset_aborts(store: &mut dyn Storage) -> StdResult<()> {
     Ok(())
 }
 
-fn delete_map(store: &mut dyn Storage, map: Map<u64, HashSet<u32>>)  -> StdResult<()> {
+pub fn clear_vote_values(store: &mut dyn Storage) -> StdResult<()> {
+    let hashes: StdResult<Vec<_>> = VOTE_VALUES
+        .keys(store, None, None, Order::Ascending)
+        .collect();
+    for hash in hashes? {
+        VOTE_VALUES.remove(store, hash);
+    }
+    Ok(())
+}
+
+fn delete_map(store: &mut dyn Storage, map: Map<u64, HashSet<PeerId>>)  -> StdResult<()> {
     let vals: StdResult<Vec<_>> = map
         .keys(store, None, None, Order::Ascending)
         .collect();
@@ -120,48 +173,68 @@ fn delete_map(store: &mut dyn Storage, map: Map<u64, HashSet<u32>>)  -> StdResul
 }
 
 
+// Fingerprint of the protocol version, the deployment id, the member set and the fault threshold.
+// Two deployments only talk to each other when their fingerprints agree. The member set is every
+// member with its network, this chain on network_id included, and the threshold is the one of its size.
+// Without configured members there is no set to agree on and only the version and deployment id count.
+pub fn config_fingerprint(store: &dyn Storage, network_id: &str) -> StdResult<String> {
+    let mut members: BTreeMap<PeerId, String> = MEMBERS
+        .range(store, None, None, Order::Ascending)
+        .collect::<StdResult<_>>()?;
+    if !members.is_empty() {
+        members.insert(STATE.load(store)?.chain_id, network_id.to_string());
+    }
+    let n = members.len() as u32;
+
+    let mut hasher = Sha256::new();
+    hasher.update(PROTOCOL_VERSION.to_be_bytes());
+    let deployment_id = DEPLOYMENT_ID.may_load(store)?.unwrap_or_default();
+    hasher.update((deployment_id.len() as u32).to_be_bytes());
+    hasher.update(deployment_id.as_bytes());
+    hasher.update(n.to_be_bytes());
+    hasher.update(fault_threshold(n).to_be_bytes());
+    for (chain_id, network_id) in members {
+        hasher.update((chain_id.len() as u32).to_be_bytes());
+        hasher.update(chain_id.as_bytes());
+        hasher.update((network_id.len() as u32).to_be_bytes());
+        hasher.update(network_id.as_bytes());
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() & 1 == 1 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
 pub fn get_timeout(env: &Env) -> IbcTimeout {
     env.block.time.plus_seconds(PACKET_LIFETIME).into()
 }
 
 
-pub fn get_id_channel_pair(store: &mut dyn Storage) -> StdResult<Vec<(u32, String)>> {
+pub fn get_id_channel_pair(store: &mut dyn Storage) -> StdResult<Vec<(PeerId, String)>> {
     let channels: StdResult<Vec<_>> = CHANNELS
         .range(store, None, None, Order::Ascending)
         .collect();
     channels
 }
 
-pub fn get_id_channel_pair_from_storage(storage: &mut dyn Storage) -> StdResult<Vec<(u32, String)>> {
+pub fn get_id_channel_pair_from_storage(storage: &mut dyn Storage) -> StdResult<Vec<(PeerId, String)>> {
     let channels: StdResult<Vec<_>> = CHANNELS
         .range(storage, None, None, Order::Ascending)
         .collect();
     channels
 }
 
-pub fn send_all_upon_join_queue(storage: &mut dyn Storage, packet_to_broadcast: Msg, 
-                                queue: &mut Vec<Vec<Msg>>) -> Result<(), ContractError> {
-    let channel_ids = get_id_channel_pair_from_storage(storage)?;
-    let state = STATE.load(storage)?;
-    for (chain_id, _channel_id) in &channel_ids {
-        let highest_request = HIGHEST_REQ.load(storage, chain_id.clone())?;
-        if highest_request == state.view {
-            queue[*chain_id as usize].push(packet_to_broadcast.clone());
-        }
-        else{
-            let action = |packets: Option<Vec<Msg>>| -> StdResult<Vec<Msg>> {
-                match packets {
-                    Some(_) => todo!(),
-                    None => Ok(vec!(packet_to_broadcast.clone())),
-                }
-                
-            };
-            SEND_ALL_UPON.update(storage, *chain_id, action)?;
-        }
-    }
-    Ok(())
-}
-
 fn _verify_channel(msg: IbcChannelOpenMsg) -> StdResult<()> {
     let channel = msg.channel();
 
@@ -186,15 +259,22 @@ fn _verify_channel(msg: IbcChannelOpenMsg) -> StdResult<()> {
     Ok(())
 }
 
-pub fn convert_send_ibc_msg(channel_id: String, packet: PacketMsg, timeout: IbcTimeout) -> IbcMsg {
-    IbcMsg::SendPacket {
+// convert_send_ibc_msg encodes the packet with the encoding negotiated for the channel
+pub fn convert_send_ibc_msg(store: &dyn Storage, channel_id: String, packet: PacketMsg, timeout: IbcTimeout) -> StdResult<IbcMsg> {
+    let encoding = CHANNEL_ENCODING
+        .may_load(store, channel_id.clone())?
+        .unwrap_or(PacketEncoding::Json);
+    let layout = CHANNEL_CODEC_VERSION
+        .may_load(store, channel_id.clone())?
+        .unwrap_or(COMPACT_CODEC_VERSION);
+    Ok(IbcMsg::SendPacket {
         channel_id,
-        data: to_binary(&packet).unwrap(),
+        data: encode_packet(&packet, encoding, layout)?,
         timeout,
-    }
+    })
 }
 
-pub fn derive_addr_from_pubkey(pub_key_bytes: &[u8]) -> Result<Addr, ContractError> {
+pub fn derive_addr_from_pubkey(pub_key_bytes: &[u8], prefix: &str) -> Result<Addr, ContractError> {
 
    // derive external address for merkle proof check
     let sha_hash = Sha256::digest(pub_key_bytes);
@@ -204,15 +284,39 @@ pub fn derive_addr_from_pubkey(pub_key_bytes: &[u8]) -> Result<Addr, ContractErr
     }
     
     let rip_hash = Ripemd160::digest(sha_hash);
-    let rip_slice: &[u8] = rip_hash.as_slice();
+    bech32_address(rip_hash.as_slice(), prefix)
+}
 
-    let addr: String = bech32::encode("wasm", rip_slice.to_base32(), bech32::Variant::Bech32)
+// bech32_address encodes the bytes of an account address
+pub fn bech32_address(data: &[u8], prefix: &str) -> Result<Addr, ContractError> {
+    let addr: String = bech32::encode(prefix, data.to_base32(), bech32::Variant::Bech32)
         .map_err(|err| ContractError::CustomError { val: err.to_string() })?;
 
     let addr = cosmwasm_std::Addr::unchecked(addr);
     Ok(addr)
 }
 
+// local_bech32_prefix is the prefix of account addresses on this chain
+pub fn local_bech32_prefix(store: &dyn Storage) -> StdResult<String> {
+    Ok(BECH32_PREFIX.may_load(store)?.unwrap_or_else(|| DEFAULT_BECH32_PREFIX.to_string()))
+}
+
+// bech32_prefix is the prefix of account addresses on a member, this chain included
+pub fn bech32_prefix(store: &dyn Storage, chain_id: &PeerId) -> StdResult<String> {
+    match MEMBER_BECH32_PREFIXES.may_load(store, chain_id.clone())? {
+        Some(prefix) => Ok(prefix),
+        None => local_bech32_prefix(store),
+    }
+}
+
+// validate_bech32_prefix accepts lowercase prefixes only, addresses are always normalized to lowercase
+pub fn validate_bech32_prefix(prefix: &str) -> Result<(), ContractError> {
+    if prefix.is_empty() || prefix.len() > MAX_BECH32_PREFIX_LEN || !prefix.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit()) {
+        return Err(ContractError::CustomError { val: format!("invalid bech32 prefix {:?}", prefix) });
+    }
+    Ok(())
+}
+
 pub fn append_binary_string(binaryString: String, key: &String, value: &String) -> Binary {
     let binary = Binary::from_base64(&binaryString).unwrap();;
     
@@ -240,42 +344,8 @@ pub fn append_binary_string(binaryString: String, key: &String, value: &String)
     Binary(binaryVector)
 }
 
-pub fn check_signature(api: &dyn Api, val: InputType) -> bool {
-    let mut result: Vec<bool> = Vec::new();
-
-    // Hashing
-    let hash = Sha256::digest(val.binary);
-
-    // Verification
-    let verify_result = api
-        .secp256k1_verify(hash.as_ref(), &val.signature, &val.public_key);
-
-    verify_result.unwrap()
-}
-
-
 pub fn get_seconds_diff(start: &Timestamp, end: &Timestamp) -> u64 {
     return end.seconds()-start.seconds();
 } 
 
-pub fn get_and_increment_debug_ctr(store: &mut dyn Storage) -> u32 {
-    if true {
-        return match DEBUG_CTR.load(store) {
-            Ok(val) => {
-                DEBUG_CTR.save(store, &(val+1));
-                return val+1;
-            }
-            Err(_) => 0,
-        };
-    }
-    return 0
-}
-
-
-pub fn debug_log(store: &mut dyn Storage, text: &String) {
-    if DEBUG_ON {
-        let ctr = get_and_increment_debug_ctr(store);
-        IBC_MSG_SEND_DEBUG.save(store, ctr, text);    
-    }
-}
 
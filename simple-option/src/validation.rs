@@ -0,0 +1,102 @@
+// Validity of user inputs.
+//
+// A value is valid when it is well formed, signed as its scheme says and not replayed, see signature.rs
+// and replay.rs.
+// Values must also be small enough to be sent in chunks, see values.rs.
+// Values are checked as soon as they are suggested or proposed, so that an invalid value is never
+// decided, and once more before being executed.
+
+use std::fmt;
+
+use cosmwasm_std::{from_slice, Api, Binary, Storage};
+use serde::de::{self, Deserialize, Deserializer, IgnoredAny, MapAccess, Visitor};
+
+use crate::replay::{check_domain, check_nonce};
+use crate::signature::{check_lengths, verify_signature};
+use crate::state::{InputType, State};
+use crate::utils::local_bech32_prefix;
+use crate::values::check_value_size;
+use crate::ContractError;
+
+// An execute message, an object with a single field holding the arguments object. Only the shape is
+// checked, maps can't be deserialized here
+struct ExecuteMsgShape;
+struct Arguments;
+
+impl<'de> Deserialize<'de> for ExecuteMsgShape {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_struct("ExecuteMsg", &[], ExecuteMsgVisitor)
+    }
+}
+
+struct ExecuteMsgVisitor;
+
+impl<'de> Visitor<'de> for ExecuteMsgVisitor {
+    type Value = ExecuteMsgShape;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an object with a single method")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<ExecuteMsgShape, A::Error> {
+        let mut methods = 0;
+        while map.next_key::<IgnoredAny>()?.is_some() {
+            map.next_value::<Arguments>()?;
+            methods += 1;
+        }
+        if methods != 1 {
+            return Err(de::Error::invalid_length(methods, &self));
+        }
+        Ok(ExecuteMsgShape)
+    }
+}
+
+impl<'de> Deserialize<'de> for Arguments {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_struct("Arguments", &[], ArgumentsVisitor)
+    }
+}
+
+struct ArgumentsVisitor;
+
+impl<'de> Visitor<'de> for ArgumentsVisitor {
+    type Value = Arguments;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an object")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Arguments, A::Error> {
+        while map.next_entry::<IgnoredAny, IgnoredAny>()?.is_some() {}
+        Ok(Arguments)
+    }
+}
+
+// check_structure fails unless binary is the base64 of an execute message, {"<method>":{...}} without
+// trailing whitespace so that the signer can be appended to it, and the key and signature have the right lengths
+pub fn check_structure(input: &InputType) -> Result<(), ContractError> {
+    let malformed = |reason: &str| Err(ContractError::MalformedInput { reason: reason.to_string() });
+    check_lengths(input)?;
+    let msg = match Binary::from_base64(&input.binary) {
+        Ok(msg) => msg,
+        Err(_) => return malformed("binary is not base64"),
+    };
+    if from_slice::<ExecuteMsgShape>(&msg).is_err() || !msg.ends_with(b"}}") {
+        return malformed("binary is not an execute message");
+    }
+    Ok(())
+}
+
+// verify_input checks everything about input and returns its signer
+pub fn verify_input(store: &dyn Storage, api: &dyn Api, state: &State, input: &InputType) -> Result<String, ContractError> {
+    check_structure(input)?;
+    check_value_size(store, input)?;
+    let signer = verify_signature(api, &local_bech32_prefix(store)?, input)?;
+    // a misconfigured prefix would have the target contract act for an address no one controls
+    if api.addr_validate(&signer.address).is_err() {
+        return Err(ContractError::InvalidAddress { address: signer.address });
+    }
+    check_domain(store, state, input)?;
+    check_nonce(store, &signer.address, input.nonce)?;
+    Ok(signer.address)
+}
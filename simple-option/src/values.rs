@@ -0,0 +1,468 @@
+use cosmwasm_std::{from_slice, to_vec, Binary, Order, StdError, StdResult, Storage, Timestamp};
+
+use crate::ibc_msg::{Msg, ValueRef, WireMsg};
+use crate::state::{ChunkedTransfer, InputType, PendingValue, CHUNKED_TRANSFERS, PENDING_VALUES, VALUES, VALUE_CHUNK_SIZE};
+use crate::utils::{DEFAULT_VALUE_CHUNK_SIZE, MAX_PENDING_VALUES, MAX_VALUE_CHUNKS, PACKET_LIFETIME};
+use crate::ContractError;
+
+// check_value_size fails when val is too large to be sent in MAX_VALUE_CHUNKS chunks. Such values are turned
+// down when submitted or received, rather than failing every packet that would carry them
+pub fn check_value_size(store: &dyn Storage, val: &InputType) -> Result<(), ContractError> {
+    let chunk_size = VALUE_CHUNK_SIZE.may_load(store)?.unwrap_or(DEFAULT_VALUE_CHUNK_SIZE);
+    let max = MAX_VALUE_CHUNKS.saturating_mul(chunk_size);
+    let size = to_vec(val)?.len() as u64;
+    if size > max as u64 {
+        return Err(ContractError::PayloadTooLarge { size, max });
+    }
+    Ok(())
+}
+
+// to_wire records the values carried by msg and replaces them by their digest.
+// Propose is the only message shipping its value in full, peers fetch anything else they miss.
+// Full values larger than chunk_size are referred to as Chunked instead and added to oversized.
+fn to_wire(store: &mut dyn Storage, msg: Msg, chunk_size: u32, oversized: &mut Vec<InputType>) -> StdResult<WireMsg> {
+    let full = matches!(msg, Msg::Propose { .. });
+    msg.try_map_values(|val| {
+        let digest = val.digest();
+        if !VALUES.has(store, digest.clone()) {
+            VALUES.save(store, digest.clone(), &val)?;
+        }
+        if !full {
+            Ok(ValueRef::Digest(digest))
+        } else if to_vec(&val)?.len() > chunk_size as usize {
+            if !oversized.contains(&val) {
+                oversized.push(val);
+            }
+            Ok(ValueRef::Chunked(digest))
+        } else {
+            Ok(ValueRef::Full(val))
+        }
+    })
+}
+
+// queue_to_wire converts msgs for the wire. The chunks of oversized values come first,
+// outbox::into_packets sends each of them as a packet of its own. Value replies that are too large are
+// replaced by their chunks.
+pub fn queue_to_wire(store: &mut dyn Storage, msgs: &[Msg]) -> StdResult<Vec<WireMsg>> {
+    // only full values may need chunking, spare the read otherwise
+    let chunk_size = if msgs.iter().any(|msg| matches!(msg, Msg::Propose { .. } | Msg::Value { .. })) {
+        VALUE_CHUNK_SIZE.may_load(store)?.unwrap_or(DEFAULT_VALUE_CHUNK_SIZE)
+    } else {
+        u32::MAX
+    };
+    let mut oversized = Vec::new();
+    let mut wire = Vec::new();
+    for msg in msgs {
+        match msg {
+            Msg::Value { val } if to_vec(val)?.len() > chunk_size as usize => {
+                if !oversized.contains(val) {
+                    oversized.push(val.clone());
+                }
+            }
+            _ => wire.push(to_wire(store, msg.clone(), chunk_size, &mut oversized)?),
+        }
+    }
+    let mut queue = Vec::new();
+    for val in &oversized {
+        queue.extend(value_chunks(val, chunk_size)?);
+    }
+    queue.extend(wire);
+    Ok(queue)
+}
+
+// value_chunks splits the Json encoding of val into ValueChunk messages of at most chunk_size bytes.
+// check_value_size keeps values needing more than MAX_VALUE_CHUNKS out of the protocol
+pub fn value_chunks(val: &InputType, chunk_size: u32) -> StdResult<Vec<WireMsg>> {
+    let data = to_vec(val)?;
+    let parts: Vec<&[u8]> = data.chunks(chunk_size.max(1) as usize).collect();
+    let total = parts.len() as u32;
+    if total > MAX_VALUE_CHUNKS {
+        return Err(StdError::generic_err(format!(
+            "value of {} bytes needs {} chunks, at most {} are accepted",
+            data.len(), total, MAX_VALUE_CHUNKS
+        )));
+    }
+    let digest = val.digest();
+    Ok(parts
+        .into_iter()
+        .enumerate()
+        .map(|(index, part)| Msg::ValueChunk {
+            digest: digest.clone(),
+            index: index as u32,
+            total,
+            data: Binary(part.to_vec()),
+        })
+        .collect())
+}
+
+// resolve_queue turns received wire messages back into protocol messages.
+// Messages referring to unknown values are parked until the sender delivers them,
+// the digests to fetch from the sender are returned alongside. A sender has at most MAX_PENDING_VALUES
+// messages parked, further ones are dropped. Chunked values are not fetched,
+// their chunks are on their way and release the parked messages once complete.
+pub fn resolve_queue(
+    store: &mut dyn Storage,
+    now: Timestamp,
+    channel_id: &str,
+    queue: Vec<WireMsg>,
+) -> StdResult<(Vec<Msg>, Vec<String>)> {
+    let mut resolved = Vec::new();
+    let mut to_fetch: Vec<String> = Vec::new();
+    for msg in queue {
+        if let Msg::ValueChunk { digest, index, total, data } = msg {
+            if let Some(val) = receive_chunk(store, now, channel_id, digest, index, total, data)? {
+                resolved.extend(release_parked(store, now, channel_id, val.digest())?);
+            }
+            continue;
+        }
+        // digests of the values we don't have, with whether they have to be fetched
+        let missing: Vec<(String, bool)> = msg
+            .values()
+            .into_iter()
+            .filter_map(|val| match val {
+                ValueRef::Digest(digest) if !VALUES.has(store, digest.clone()) => Some((digest.clone(), true)),
+                ValueRef::Chunked(digest) if !VALUES.has(store, digest.clone()) => Some((digest.clone(), false)),
+                _ => None,
+            })
+            .collect();
+
+        if missing.is_empty() {
+            resolved.push(msg.try_map_values(|val| match val {
+                ValueRef::Full(val) => {
+                    VALUES.save(store, val.digest(), &val)?;
+                    Ok(val)
+                }
+                ValueRef::Digest(digest) | ValueRef::Chunked(digest) => VALUES.load(store, digest),
+            })?);
+        } else if parked_count(store, channel_id)? < MAX_PENDING_VALUES {
+            // park it under the first missing value, it gets resolved again once that one arrives
+            let key = (channel_id.to_string(), missing[0].0.clone());
+            let mut parked = PENDING_VALUES
+                .may_load(store, key.clone())?
+                .unwrap_or(PendingValue { msgs: Vec::new(), requested: now });
+            parked.msgs.push(msg);
+            PENDING_VALUES.save(store, key, &parked)?;
+            for (digest, fetch) in missing {
+                if fetch && !to_fetch.contains(&digest) {
+                    to_fetch.push(digest);
+                }
+            }
+        }
+    }
+    Ok((resolved, to_fetch))
+}
+
+fn parked_count(store: &dyn Storage, channel_id: &str) -> StdResult<usize> {
+    PENDING_VALUES
+        .prefix(channel_id.to_string())
+        .range(store, None, None, Order::Ascending)
+        .map(|entry| entry.map(|(_, parked)| parked.msgs.len()))
+        .sum()
+}
+
+// receive_value stores a fetched value and hands back the messages from channel_id that were waiting for it
+pub fn receive_value(store: &mut dyn Storage, now: Timestamp, channel_id: &str, val: InputType) -> StdResult<Vec<Msg>> {
+    let digest = val.digest();
+    // ignore values nobody asked for
+    if !PENDING_VALUES.has(store, (channel_id.to_string(), digest.clone())) {
+        return Ok(Vec::new());
+    }
+    VALUES.save(store, digest.clone(), &val)?;
+    release_parked(store, now, channel_id, digest)
+}
+
+// release_parked resolves again the messages from channel_id that were waiting for the value with digest
+fn release_parked(store: &mut dyn Storage, now: Timestamp, channel_id: &str, digest: String) -> StdResult<Vec<Msg>> {
+    let key = (channel_id.to_string(), digest);
+    let parked = match PENDING_VALUES.may_load(store, key.clone())? {
+        Some(parked) => parked,
+        None => return Ok(Vec::new()),
+    };
+    PENDING_VALUES.remove(store, key);
+
+    // the remaining missing values were already requested along with this one
+    let (resolved, _) = resolve_queue(store, now, channel_id, parked.msgs)?;
+    Ok(resolved)
+}
+
+// receive_chunk records a chunk of the value with digest arriving on channel_id. Once all of its chunks are
+// there the value is reassembled, checked against the digest, stored and returned.
+pub fn receive_chunk(
+    store: &mut dyn Storage,
+    now: Timestamp,
+    channel_id: &str,
+    digest: String,
+    index: u32,
+    total: u32,
+    data: Binary,
+) -> StdResult<Option<InputType>> {
+    if VALUES.has(store, digest.clone()) {
+        return Ok(None);
+    }
+    if total == 0 || total > MAX_VALUE_CHUNKS || index >= total {
+        return Err(StdError::generic_err(format!("invalid chunk {} of {} for value {}", index, total, digest)));
+    }
+    let key = (channel_id.to_string(), digest.clone());
+    let mut transfer = CHUNKED_TRANSFERS.may_load(store, key.clone())?.unwrap_or_else(|| ChunkedTransfer {
+        chunks: vec![None; total as usize],
+        started: now,
+    });
+    if transfer.chunks.len() != total as usize {
+        return Err(StdError::generic_err(format!(
+            "value {} was announced with {} chunks, not {}",
+            digest, transfer.chunks.len(), total
+        )));
+    }
+    transfer.chunks[index as usize] = Some(data);
+    if transfer.chunks.iter().any(Option::is_none) {
+        CHUNKED_TRANSFERS.save(store, key, &transfer)?;
+        return Ok(None);
+    }
+
+    CHUNKED_TRANSFERS.remove(store, key);
+    let data: Vec<u8> = transfer.chunks.into_iter().flatten().flat_map(|chunk| chunk.0).collect();
+    let val: InputType = from_slice(&data)?;
+    if val.digest() != digest {
+        return Err(StdError::generic_err(format!("reassembled value does not match digest {}", digest)));
+    }
+    VALUES.save(store, digest, &val)?;
+    Ok(Some(val))
+}
+
+// gc_values runs on every packet. It drops the chunked transfers that expired and returns the
+// <Channel_Id, digest> of the values that messages waited for during a packet lifetime, to be fetched again
+pub fn gc_values(store: &mut dyn Storage, now: Timestamp) -> StdResult<Vec<(String, String)>> {
+    gc_chunked_transfers(store, now)?;
+    let overdue: Vec<_> = PENDING_VALUES
+        .range(store, None, None, Order::Ascending)
+        .filter_map(|entry| match entry {
+            Ok((key, parked)) if parked.requested.plus_seconds(PACKET_LIFETIME) < now => Some(Ok((key, parked))),
+            Ok(_) => None,
+            Err(err) => Some(Err(err)),
+        })
+        .collect::<StdResult<_>>()?;
+    let mut to_fetch = Vec::new();
+    for (key, mut parked) in overdue {
+        // the request or the reply timed out, or the chunks of the value never completed
+        parked.requested = now;
+        PENDING_VALUES.save(store, key.clone(), &parked)?;
+        to_fetch.push(key);
+    }
+    Ok(to_fetch)
+}
+
+// gc_chunked_transfers drops the transfers that did not complete within a packet lifetime,
+// their remaining chunks cannot arrive anymore
+fn gc_chunked_transfers(store: &mut dyn Storage, now: Timestamp) -> StdResult<()> {
+    let expired: Vec<_> = CHUNKED_TRANSFERS
+        .range(store, None, None, Order::Ascending)
+        .filter_map(|entry| match entry {
+            Ok((key, transfer)) if transfer.started.plus_seconds(PACKET_LIFETIME) < now => Some(Ok(key)),
+            Ok(_) => None,
+            Err(err) => Some(Err(err)),
+        })
+        .collect::<StdResult<_>>()?;
+    for key in expired {
+        CHUNKED_TRANSFERS.remove(store, key);
+    }
+    Ok(())
+}
+
+// retire_values drops the values of a finished instance along with the messages waiting for values.
+// kept, the decided value, stays so that peers still deciding can fetch it
+pub fn retire_values(store: &mut dyn Storage, kept: Option<&InputType>) -> StdResult<()> {
+    let kept = kept.map(InputType::digest);
+    let digests: Vec<_> = VALUES.keys(store, None, None, Order::Ascending).collect::<StdResult<_>>()?;
+    for digest in digests.into_iter().filter(|digest| Some(digest) != kept.as_ref()) {
+        VALUES.remove(store, digest);
+    }
+    let keys: Vec<_> = PENDING_VALUES.keys(store, None, None, Order::Ascending).collect::<StdResult<_>>()?;
+    for key in keys {
+        PENDING_VALUES.remove(store, key);
+    }
+    Ok(())
+}
+
+// retire_pending_values drops the parked messages of the views before view
+pub fn retire_pending_values(store: &mut dyn Storage, view: u32) -> StdResult<()> {
+    let entries: Vec<_> = PENDING_VALUES.range(store, None, None, Order::Ascending).collect::<StdResult<_>>()?;
+    for (key, mut parked) in entries {
+        let count = parked.msgs.len();
+        parked.msgs.retain(|msg| !matches!(msg.view(), Some(msg_view) if msg_view < view));
+        if parked.msgs.is_empty() {
+            PENDING_VALUES.remove(store, key);
+        } else if parked.msgs.len() != count {
+            PENDING_VALUES.save(store, key, &parked)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use cosmwasm_std::testing::{mock_env, mock_info, MockStorage};
+
+    use crate::contract::execute;
+    use crate::msg::{ExecuteMsg, InstantiateMsg};
+    use crate::test_utils::{instantiate_msg, setup_with, signed_input};
+
+    use super::*;
+
+    const CHANNEL: &str = "channel-1";
+
+    fn at(seconds: u64) -> Timestamp {
+        Timestamp::from_seconds(seconds)
+    }
+
+    fn chunks(val: &InputType, chunk_size: u32) -> Vec<(String, u32, u32, Binary)> {
+        value_chunks(val, chunk_size)
+            .unwrap()
+            .into_iter()
+            .map(|msg| match msg {
+                Msg::ValueChunk { digest, index, total, data } => (digest, index, total, data),
+                msg => panic!("unexpected {:?}", msg),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn chunks_are_reassembled_in_any_order() {
+        let mut store = MockStorage::new();
+        let val = signed_input(1, 0);
+        let mut parts = chunks(&val, 64);
+        assert!(parts.len() > 2);
+        parts.reverse();
+        let last = parts.pop().unwrap();
+        for (digest, index, total, data) in parts {
+            assert_eq!(receive_chunk(&mut store, at(0), CHANNEL, digest, index, total, data).unwrap(), None);
+        }
+        let (digest, index, total, data) = last;
+        assert_eq!(receive_chunk(&mut store, at(0), CHANNEL, digest.clone(), index, total, data).unwrap(), Some(val.clone()));
+        assert_eq!(VALUES.load(&store, digest.clone()).unwrap(), val);
+        assert!(!CHUNKED_TRANSFERS.has(&store, (CHANNEL.to_string(), digest)));
+    }
+
+    #[test]
+    fn bad_chunks_are_refused() {
+        let mut store = MockStorage::new();
+        let val = signed_input(1, 0);
+        let parts = chunks(&val, 64);
+        let (digest, _, total, data) = parts[0].clone();
+        for (index, total) in [(total, total), (0, 0), (0, MAX_VALUE_CHUNKS + 1)] {
+            receive_chunk(&mut store, at(0), CHANNEL, digest.clone(), index, total, data.clone()).unwrap_err();
+        }
+
+        // the first chunk fixes the number of chunks
+        receive_chunk(&mut store, at(0), CHANNEL, digest.clone(), 0, total, data.clone()).unwrap();
+        receive_chunk(&mut store, at(0), CHANNEL, digest.clone(), 1, total + 1, data).unwrap_err();
+
+        // chunks that don't add up to the value with digest
+        let mut last = None;
+        for (digest, index, total, _) in parts.into_iter().skip(1) {
+            last = Some(receive_chunk(&mut store, at(0), CHANNEL, digest, index, total, Binary(vec![b' '; 64])));
+        }
+        last.unwrap().unwrap_err();
+        assert!(!VALUES.has(&store, digest));
+    }
+
+    #[test]
+    fn oversized_values_are_refused() {
+        let mut store = MockStorage::new();
+        let val = signed_input(1, 0);
+        let size = to_vec(&val).unwrap().len() as u32;
+        check_value_size(&store, &val).unwrap();
+        VALUE_CHUNK_SIZE.save(&mut store, &(size / MAX_VALUE_CHUNKS + 1)).unwrap();
+        check_value_size(&store, &val).unwrap();
+        VALUE_CHUNK_SIZE.save(&mut store, &(size / MAX_VALUE_CHUNKS)).unwrap();
+        let err = check_value_size(&store, &val).unwrap_err();
+        assert!(matches!(err, ContractError::PayloadTooLarge { .. }), "{:?}", err);
+
+        // and turned down when submitted, before they start an instance
+        let mut deps = setup_with(InstantiateMsg { value_chunk_size: Some(1), ..instantiate_msg(0) });
+        let err = execute(deps.as_mut(), mock_env(), mock_info("user", &[]), ExecuteMsg::Input { value: val }).unwrap_err();
+        assert!(matches!(err, ContractError::PayloadTooLarge { .. }), "{:?}", err);
+    }
+
+    #[test]
+    fn values_waited_for_too_long_are_fetched_again() {
+        let mut store = MockStorage::new();
+        let val = signed_input(1, 0);
+        let echo = Msg::Echo { val: ValueRef::Digest(val.digest()), view: 1 };
+        let (resolved, to_fetch) = resolve_queue(&mut store, at(0), CHANNEL, vec![echo]).unwrap();
+        assert!(resolved.is_empty());
+        assert_eq!(to_fetch, vec![val.digest()]);
+
+        assert!(gc_values(&mut store, at(PACKET_LIFETIME)).unwrap().is_empty());
+        let key = (CHANNEL.to_string(), val.digest());
+        assert_eq!(gc_values(&mut store, at(PACKET_LIFETIME + 1)).unwrap(), vec![key.clone()]);
+        // asked for once per packet lifetime
+        assert!(gc_values(&mut store, at(PACKET_LIFETIME + 2)).unwrap().is_empty());
+
+        let released = receive_value(&mut store, at(PACKET_LIFETIME + 2), CHANNEL, val.clone()).unwrap();
+        assert_eq!(released, vec![Msg::Echo { val, view: 1 }]);
+        assert!(!PENDING_VALUES.has(&store, key));
+    }
+
+    fn echo(val: &InputType, view: u32) -> WireMsg {
+        Msg::Echo { val: ValueRef::Digest(val.digest()), view }
+    }
+
+    #[test]
+    fn unknown_values_are_fetched_and_release_the_waiting_messages() {
+        let mut store = MockStorage::new();
+        let val = signed_input(1, 0);
+        let done = Msg::Done { val: ValueRef::Digest(val.digest()) };
+        let (resolved, to_fetch) = resolve_queue(&mut store, at(0), CHANNEL, vec![echo(&val, 0), done]).unwrap();
+        assert!(resolved.is_empty());
+        assert_eq!(to_fetch, vec![val.digest()]);
+        assert_eq!(PENDING_VALUES.load(&store, (CHANNEL.to_string(), val.digest())).unwrap().msgs.len(), 2);
+
+        // a value from another channel or with another digest releases nothing
+        assert!(receive_value(&mut store, at(0), "channel-2", val.clone()).unwrap().is_empty());
+        assert!(receive_value(&mut store, at(0), CHANNEL, signed_input(1, 1)).unwrap().is_empty());
+        assert!(!VALUES.has(&store, signed_input(1, 1).digest()));
+
+        let released = receive_value(&mut store, at(0), CHANNEL, val.clone()).unwrap();
+        assert_eq!(released, vec![Msg::Echo { val: val.clone(), view: 0 }, Msg::Done { val: val.clone() }]);
+        // known from now on
+        let (resolved, to_fetch) = resolve_queue(&mut store, at(0), CHANNEL, vec![echo(&val, 1)]).unwrap();
+        assert_eq!(resolved, vec![Msg::Echo { val, view: 1 }]);
+        assert!(to_fetch.is_empty());
+    }
+
+    #[test]
+    fn waiting_messages_are_capped_per_peer() {
+        let mut store = MockStorage::new();
+        let msgs: Vec<WireMsg> = (0..MAX_PENDING_VALUES as u64 + 1).map(|nonce| echo(&signed_input(1, nonce), 0)).collect();
+        let (_, to_fetch) = resolve_queue(&mut store, at(0), CHANNEL, msgs).unwrap();
+        assert_eq!(to_fetch.len(), MAX_PENDING_VALUES);
+        assert_eq!(parked_count(&store, CHANNEL).unwrap(), MAX_PENDING_VALUES);
+
+        // other peers have their own room
+        let (_, to_fetch) = resolve_queue(&mut store, at(0), "channel-2", vec![echo(&signed_input(1, 0), 0)]).unwrap();
+        assert_eq!(to_fetch.len(), 1);
+    }
+
+    #[test]
+    fn retired_views_and_instances_leave_no_values() {
+        let mut store = MockStorage::new();
+        let (old, current, decided) = (signed_input(1, 0), signed_input(1, 1), signed_input(1, 2));
+        let done = Msg::Done { val: ValueRef::Digest(old.digest()) };
+        resolve_queue(&mut store, at(0), CHANNEL, vec![echo(&old, 0), done, echo(&current, 1)]).unwrap();
+
+        // the view 0 Echo goes, the Done outlives views
+        retire_pending_values(&mut store, 1).unwrap();
+        assert_eq!(PENDING_VALUES.load(&store, (CHANNEL.to_string(), old.digest())).unwrap().msgs.len(), 1);
+        assert_eq!(parked_count(&store, CHANNEL).unwrap(), 2);
+
+        for val in [&old, &current, &decided] {
+            VALUES.save(&mut store, val.digest(), val).unwrap();
+        }
+        retire_values(&mut store, Some(&decided)).unwrap();
+        assert_eq!(parked_count(&store, CHANNEL).unwrap(), 0);
+        let values: Vec<String> = VALUES.keys(&store, None, None, Order::Ascending).collect::<StdResult<_>>().unwrap();
+        assert_eq!(values, vec![decided.digest()]);
+        retire_values(&mut store, None).unwrap();
+        assert!(!VALUES.has(&store, decided.digest()));
+    }
+}
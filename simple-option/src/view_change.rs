@@ -1,61 +1,63 @@
-use std::convert::TryInto;
-use std::vec;
-
 use cosmwasm_std::{
-    IbcTimeout, Response, IbcMsg, Storage, StdResult, Env, Api
+    Response, IbcMsg, Storage, StdResult
 };
 
-use crate::ibc_msg::{PacketMsg, Msg};
-use crate::queue_handler::{receive_queue, send_all_party, send_all_upon_join_queue};
+use crate::ibc_msg::Msg;
+use crate::outbox::into_packets;
+use crate::queue_handler::{run_inbox, send_all_party, send_all_upon_join_queue};
+use crate::context::Context;
+use crate::timeline::check_request_quorum;
+use crate::events::{emit, EVENT_VIEW_CHANGE};
 use crate::state::{
-    HIGHEST_REQ, STATE, TEST_QUEUE, CHANNELS, IBC_MSG_SEND_DEBUG
+    LogCategory, LogLevel
 };
+#[cfg(feature = "testing")]
+use crate::state::{PeerId, STATE, TEST_QUEUE};
 
 use crate::ContractError;
-use crate::utils::{convert_send_ibc_msg, get_and_increment_debug_ctr};
-
-pub fn view_change(storage: &mut dyn Storage, timeout: IbcTimeout, env: &Env, api: &dyn Api) -> Result<Response, ContractError> {
+use crate::log::log;
 
-    let state = STATE.load(storage)?;
-    let mut queue: Vec<Vec<Msg>> = vec!(Vec::new(); state.n.try_into().unwrap());
+pub fn view_change(ctx: &mut Context) -> Result<Response, ContractError> {
 
-    append_queue_view_change(storage, & mut queue, timeout.clone(), env, api)?;
-    let msgs = convert_queue_to_ibc_msgs(storage, &queue, timeout.clone())?;
+    append_queue_view_change(ctx)?;
+    let wasm_exec_messages = run_inbox(ctx)?;
+    let msgs = convert_queue_to_ibc_msgs(ctx)?;
 
 
     let response = Response::new()
         .add_messages(msgs)
+        .add_submessages(wasm_exec_messages)
+        .add_events(std::mem::take(&mut ctx.events))
         .add_attribute("action", "execute")
         .add_attribute("msg_type", "input");
 
-        let debug_ctr = get_and_increment_debug_ctr(storage);
-        IBC_MSG_SEND_DEBUG.save(storage, debug_ctr, &format!("Start View Change!! "));
+    log(ctx, LogLevel::Info, LogCategory::Protocol, format!("started view {}", ctx.state.view))?;
 
 
     Ok(response)
 }
 
 pub fn append_queue_view_change(
-    storage: &mut dyn Storage,
-    queue: &mut Vec<Vec<Msg>>,
-    timeout: IbcTimeout,
-    env: &Env,
-    api: &dyn Api,
+    ctx: &mut Context,
 ) -> Result<(), ContractError> {
-    // load the state
-    let state = STATE.load(storage)?;
+    // the state as of the new view, self-delivery below keeps updating ctx.state
+    let state = ctx.state.clone();
+    ctx.metrics.views += 1;
+    emit(ctx, EVENT_VIEW_CHANGE, vec![("primary", state.primary.clone())]);
     // Add Request message to packets_to_be_broadcasted
     let request_packet = Msg::Request {
         view: state.view,
-        chain_id: state.chain_id,
+        chain_id: state.chain_id.clone(),
     };
 
     // Send Request to all parties
-    send_all_party(storage, queue, request_packet, timeout.clone(), env, api)?;
+    send_all_party(ctx, request_packet)?;
+    // peers may have asked to join before we did
+    check_request_quorum(ctx)?;
 
     
     let suggest_packet = Msg::Suggest {
-        chain_id: state.chain_id,
+        chain_id: state.chain_id.clone(),
         view: state.view,
         key2: state.key2,
         key2_val: state.key2_val.clone(),
@@ -65,11 +67,11 @@ pub fn append_queue_view_change(
     };
     // Upon highest_request[primary] == view
     if state.chain_id != state.primary {
-        if state.view == HIGHEST_REQ.load(storage, state.primary)? {
-            queue[state.primary as usize].push(suggest_packet);
+        if state.view == ctx.highest_req(&state.primary) {
+            ctx.outbox.enqueue(state.primary.clone(), suggest_packet);
         }
     } else {
-        receive_queue(storage, timeout.clone(), None, vec![suggest_packet], queue, env, api)?;
+        ctx.send_to_self(suggest_packet);
     }
 
 
@@ -81,13 +83,14 @@ pub fn append_queue_view_change(
         view: state.view,
     };
     // send_all_upon_join(Proof)
-    send_all_upon_join_queue(storage, queue, proof_packet, timeout.clone(), env, api)?;
+    send_all_upon_join_queue(ctx, proof_packet)?;
     Ok(())
 }
 
+#[cfg(feature = "testing")]
 pub fn testing_add2queue(
     store: &mut dyn Storage,
-    chain_id: u32,
+    chain_id: PeerId,
     msg_queue: Vec<Msg>
 ) -> Result<(), ContractError> {
     //// TESTING /////
@@ -110,36 +113,8 @@ pub fn testing_add2queue(
         
 
 pub fn convert_queue_to_ibc_msgs(
-    storage: &mut dyn Storage,
-    queue: &Vec<Vec<Msg>>,
-    timeout: IbcTimeout,
+    ctx: &mut Context,
 ) -> Result<Vec<IbcMsg>, ContractError>{
-    let state = STATE.load(storage)?;
-    let mut msgs = Vec::new();
-    for (chain_id, msg_queue) in queue.iter().enumerate() {
-        //// TESTING ////
-        testing_add2queue(storage, chain_id.try_into().unwrap(), msg_queue.to_vec())?;
-        //// TESTING ////
-
-        if chain_id != state.chain_id as usize {
-            // When chain wishes to send some msgs to dest chain
-            if msg_queue.len() > 0 {
-                let channel_id = CHANNELS.load(storage, chain_id.try_into().unwrap())?;
-                let msg = convert_send_ibc_msg(channel_id, PacketMsg::MsgQueue ( msg_queue.to_vec() ), timeout.clone());
-                // let msg = IbcMsg::SendPacket {
-                //     channel_id,
-                //     data: to_binary(&PacketMsg::MsgQueue ( msg_queue.to_vec() ) )?,
-                //     timeout: timeout.clone(),
-                // };
-                msgs.push(msg);
-            }
-        }
-    }
-    //// TESTING /////
-    let mut state = STATE.load(storage)?;
-    state.current_tx_id += 1;
-    STATE.save(storage, &state)?;
-    //// TESTING /////
-
+    let (msgs, _) = into_packets(ctx, None)?;
     Ok(msgs)
 }
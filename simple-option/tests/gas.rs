@@ -0,0 +1,320 @@
+// Storage gas of packet processing, per message type.
+//
+// The contract is driven through its entry points over a storage that charges the Cosmos SDK
+// KVStore gas schedule (see GasMeteredStorage), replaying one happy-path view on a 4 chain setup
+// as seen by the primary, and a view change as seen by another chain.
+// Run with `cargo test --test gas -- --nocapture` to print the table, Benchmarks.md keeps the figures.
+
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+
+use cosmwasm_std::testing::{
+    mock_env, mock_ibc_channel_connect_ack, mock_ibc_packet_recv, mock_info, MockApi, MockQuerier,
+    MockStorage,
+};
+use cosmwasm_std::{from_slice, CosmosMsg, IbcMsg, IbcOrder, Order, OwnedDeps, Record, Storage};
+use k256::ecdsa::signature::Signer;
+use k256::ecdsa::{Signature, SigningKey};
+
+use simple_option::contract::{execute, instantiate};
+use simple_option::ibc::{ibc_channel_connect, ibc_packet_receive};
+use simple_option::ibc_msg::{Msg, PacketMsg, ValueRef, WireMsg};
+use simple_option::msg::{ExecuteMsg, InstantiateMsg};
+use simple_option::state::{InputDomain, InputTarget, SignatureScheme, TBInput, STATE};
+
+// sdk KVGasConfig
+const READ_FLAT: u64 = 1000;
+const READ_PER_BYTE: u64 = 3;
+const WRITE_FLAT: u64 = 2000;
+const WRITE_PER_BYTE: u64 = 30;
+const DELETE_FLAT: u64 = 1000;
+const ITER_NEXT_FLAT: u64 = 30;
+
+#[derive(Default)]
+struct GasMeteredStorage {
+    inner: MockStorage,
+    gas: Cell<u64>,
+    reads: Cell<u64>,
+    writes: Cell<u64>,
+    state_writes: Cell<u64>,
+}
+
+impl GasMeteredStorage {
+    fn charge(&self, gas: u64) {
+        self.gas.set(self.gas.get() + gas);
+    }
+
+    fn take(&self) -> Usage {
+        Usage {
+            gas: self.gas.replace(0),
+            reads: self.reads.replace(0),
+            writes: self.writes.replace(0),
+            state_writes: self.state_writes.replace(0),
+        }
+    }
+}
+
+impl Storage for GasMeteredStorage {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let value = self.inner.get(key);
+        self.reads.set(self.reads.get() + 1);
+        self.charge(READ_FLAT + READ_PER_BYTE * value.as_ref().map_or(0, |v| v.len()) as u64);
+        value
+    }
+
+    fn range<'a>(
+        &'a self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        order: Order,
+    ) -> Box<dyn Iterator<Item = Record> + 'a> {
+        self.reads.set(self.reads.get() + 1);
+        Box::new(self.inner.range(start, end, order).map(move |(k, v)| {
+            self.charge(ITER_NEXT_FLAT + READ_PER_BYTE * (k.len() + v.len()) as u64);
+            (k, v)
+        }))
+    }
+
+    fn set(&mut self, key: &[u8], value: &[u8]) {
+        self.writes.set(self.writes.get() + 1);
+        if key == b"state" {
+            self.state_writes.set(self.state_writes.get() + 1);
+        }
+        self.charge(WRITE_FLAT + WRITE_PER_BYTE * (key.len() + value.len()) as u64);
+        self.inner.set(key, value)
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        self.writes.set(self.writes.get() + 1);
+        self.charge(DELETE_FLAT);
+        self.inner.remove(key)
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct Usage {
+    gas: u64,
+    reads: u64,
+    writes: u64,
+    state_writes: u64,
+}
+
+type Deps = OwnedDeps<GasMeteredStorage, MockApi, MockQuerier>;
+
+#[derive(Default)]
+struct Bench {
+    // message type -> (packets, total usage, max state writes in one packet)
+    rows: BTreeMap<&'static str, (u64, Usage, u64)>,
+}
+
+impl Bench {
+    fn record(&mut self, name: &'static str, usage: Usage) {
+        let row = self.rows.entry(name).or_default();
+        row.0 += 1;
+        row.1.gas += usage.gas;
+        row.1.reads += usage.reads;
+        row.1.writes += usage.writes;
+        row.1.state_writes += usage.state_writes;
+        row.2 = row.2.max(usage.state_writes);
+    }
+
+    fn print(&self) {
+        println!("| message | packets | avg gas | avg reads | avg writes | avg STATE saves |");
+        println!("|---|---:|---:|---:|---:|---:|");
+        for (name, (packets, usage, _)) in &self.rows {
+            println!(
+                "| {} | {} | {} | {} | {} | {:.1} |",
+                name,
+                packets,
+                usage.gas / packets,
+                usage.reads / packets,
+                usage.writes / packets,
+                usage.state_writes as f64 / *packets as f64
+            );
+        }
+    }
+}
+
+// a value signed for the 4 chain deployment of setup
+fn value() -> TBInput {
+    let key = SigningKey::from_bytes(&[7; 32]).unwrap();
+    let mut val = TBInput {
+        binary: "eyJyZWdpc3Rlcl90YiI6eyJuYW1lIjoidGVzdF9mcm9tX3RydXN0Ym9vc3Rfc2VwdCJ9fQ==".to_string(),
+        public_key: key.verifying_key().to_bytes().to_vec(),
+        signature: vec![],
+        scheme: SignatureScheme::Secp256k1,
+        nonce: 0,
+        domain: InputDomain {
+            deployment_id: "bench".to_string(),
+            targets: (0..4)
+                .map(|chain_id| InputTarget { chain_id: chain_id.to_string(), contract: "nameservice".to_string() })
+                .collect(),
+        },
+        multisig: None,
+    };
+    let signature: Signature = key.sign(&val.sign_bytes());
+    val.signature = signature.as_ref().to_vec();
+    val
+}
+
+fn digest() -> ValueRef {
+    ValueRef::Digest(value().digest())
+}
+
+fn channel(chain_id: u32) -> String {
+    format!("channel-{}", chain_id)
+}
+
+// sets up `chain_id` with channels to every other chain of a 4 chain deployment
+fn setup(chain_id: u32) -> Deps {
+    let mut deps = OwnedDeps {
+        storage: GasMeteredStorage::default(),
+        api: MockApi::default(),
+        querier: MockQuerier::default(),
+        custom_query_type: PhantomData,
+    };
+    let msg = InstantiateMsg {
+        chain_id: Some(chain_id.to_string()),
+        input: value(),
+        contract_addr: "nameservice".to_string(),
+        members: vec![],
+        processing_budget: None,
+        value_chunk_size: None,
+        admin: None,
+        deployment_id: "bench".to_string(),
+        submission_policy: None,
+        bech32_prefix: None,
+    };
+    instantiate(deps.as_mut(), mock_env(), mock_info("creator", &[]), msg).unwrap();
+
+    for peer in (0..4).filter(|peer| *peer != chain_id) {
+        let connect = mock_ibc_channel_connect_ack(&channel(peer), IbcOrder::Unordered, "trustboost-test");
+        let res = ibc_channel_connect(deps.as_mut(), mock_env(), connect).unwrap();
+        // answer with the handshake the peer would send, which only differs by chain_id
+        let who_am_i = match &res.messages[0].msg {
+            CosmosMsg::Ibc(IbcMsg::SendPacket { data, .. }) => data.clone(),
+            msg => panic!("unexpected {:?}", msg),
+        };
+        let who_am_i = match from_slice(&who_am_i).unwrap() {
+            PacketMsg::WhoAmI { protocol_version, fingerprint, contract_addr, network_id, .. } => {
+                PacketMsg::WhoAmI { chain_id: peer.to_string(), protocol_version, fingerprint, contract_addr, network_id }
+            }
+            msg => panic!("unexpected {:?}", msg),
+        };
+        let mut recv = mock_ibc_packet_recv(&channel(peer), &who_am_i).unwrap();
+        recv.packet.src.port_id = format!("wasm.{}", mock_env().contract.address);
+        ibc_packet_receive(deps.as_mut(), mock_env(), recv).unwrap();
+    }
+
+    let input = ExecuteMsg::Input { value: value() };
+    execute(deps.as_mut(), mock_env(), mock_info("user", &[]), input).unwrap();
+    deps
+}
+
+fn deliver(deps: &mut Deps, bench: &mut Bench, from: u32, msg: WireMsg) {
+    let name = msg.name_for_bench();
+    deps.storage.take();
+    let recv = mock_ibc_packet_recv(&channel(from), &PacketMsg::MsgQueue(vec![msg])).unwrap();
+    ibc_packet_receive(deps.as_mut(), mock_env(), recv).unwrap();
+    bench.record(name, deps.storage.take());
+}
+
+trait BenchName {
+    fn name_for_bench(&self) -> &'static str;
+}
+
+impl BenchName for WireMsg {
+    fn name_for_bench(&self) -> &'static str {
+        match self {
+            Msg::Request { .. } => "Request",
+            Msg::Suggest { .. } => "Suggest",
+            Msg::Proof { .. } => "Proof",
+            Msg::Abort { .. } => "Abort",
+            Msg::Propose { .. } => "Propose",
+            Msg::Echo { .. } => "Echo",
+            Msg::Key1 { .. } => "Key1",
+            Msg::Key2 { .. } => "Key2",
+            Msg::Key3 { .. } => "Key3",
+            Msg::Lock { .. } => "Lock",
+            Msg::Done { .. } => "Done",
+            Msg::FetchValue { .. } => "FetchValue",
+            Msg::Value { .. } => "Value",
+            Msg::ValueChunk { .. } => "ValueChunk",
+        }
+    }
+}
+
+fn run() -> Bench {
+    let mut bench = Bench::default();
+
+    // chain 1 is the primary of view 0
+    let mut deps = setup(1);
+    let peers = [0, 2, 3];
+    for from in peers {
+        deliver(&mut deps, &mut bench, from, Msg::Request { view: 0, chain_id: from.to_string() });
+    }
+    for from in peers {
+        let suggest = Msg::Suggest {
+            chain_id: from.to_string(),
+            view: 0,
+            key2: 0,
+            key2_val: digest(),
+            prev_key2: -1,
+            key3: 0,
+            key3_val: digest(),
+        };
+        deliver(&mut deps, &mut bench, from, suggest);
+    }
+    for from in peers {
+        let proof = Msg::Proof { key1: 0, key1_val: digest(), prev_key1: -1, view: 0 };
+        deliver(&mut deps, &mut bench, from, proof);
+    }
+    for from in peers {
+        deliver(&mut deps, &mut bench, from, Msg::Echo { val: digest(), view: 0 });
+    }
+    for from in peers {
+        deliver(&mut deps, &mut bench, from, Msg::Key1 { val: digest(), view: 0 });
+    }
+    for from in peers {
+        deliver(&mut deps, &mut bench, from, Msg::Key2 { val: digest(), view: 0 });
+    }
+    for from in peers {
+        deliver(&mut deps, &mut bench, from, Msg::Key3 { val: digest(), view: 0 });
+    }
+    for from in peers {
+        deliver(&mut deps, &mut bench, from, Msg::Lock { val: digest(), view: 0 });
+    }
+    assert!(STATE.load(&deps.storage).unwrap().sent.contains("Done"));
+
+    // chain 2 receives the proposal from the primary and decides through Done, or moves on after aborts
+    let mut deps = setup(2);
+    deliver(&mut deps, &mut bench, 1, Msg::Request { view: 0, chain_id: 1.to_string() });
+    let propose = Msg::Propose { chain_id: 1.to_string(), k: 0, v: ValueRef::Full(value()), view: 0 };
+    deliver(&mut deps, &mut bench, 1, propose);
+    for from in [0, 1, 3] {
+        deliver(&mut deps, &mut bench, from, Msg::Done { val: digest() });
+    }
+    let state = STATE.load(&deps.storage).unwrap();
+    assert_eq!(state.done, Some(value()));
+    assert!(state.done_executed);
+
+    let mut deps = setup(2);
+    for from in [0, 1, 3] {
+        deliver(&mut deps, &mut bench, from, Msg::Abort { view: 0, chain_id: from.to_string() });
+    }
+    assert_eq!(STATE.load(&deps.storage).unwrap().view, 1);
+
+    bench
+}
+
+#[test]
+fn storage_gas_per_message_type() {
+    let bench = run();
+    bench.print();
+    assert_eq!(bench.rows.len(), 11);
+    // the execution context commits State at most once per packet
+    for (name, (_, _, max_state_writes)) in &bench.rows {
+        assert!(*max_state_writes <= 1, "{} saved State {} times", name, max_state_writes);
+    }
+}